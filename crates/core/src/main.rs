@@ -3,6 +3,7 @@ use handler_core::Handler;
 use handler_core::HandlerContext;
 use log::error;
 
+use telegram_api::CallbackQuery;
 use telegram_api::Message;
 use telegram_api::SendMessage;
 
@@ -123,8 +124,16 @@ fn main() {
                 for update in r.clone().result {
                     match &update {
                         Update {
-                            update_id: _,
-                            message: Some(m),
+                            message: Some(m), ..
+                        } if white_list.contains(&m.chat.id) => tx_async
+                            .send(update)
+                            .expect("channel for async handlers is broken"),
+                        Update {
+                            callback_query:
+                                Some(CallbackQuery {
+                                    message: Some(m), ..
+                                }),
+                            ..
                         } if white_list.contains(&m.chat.id) => tx_async
                             .send(update)
                             .expect("channel for async handlers is broken"),
@@ -135,8 +144,7 @@ fn main() {
                 for update in r.clone().result {
                     match &update {
                         Update {
-                            update_id: _,
-                            message: Some(m),
+                            message: Some(m), ..
                         } if white_list.contains(&m.chat.id) => tx
                             .send(update)
                             .expect("Channel for async handlers is broken"),
@@ -186,6 +194,7 @@ pub fn init_sync_handlers_loop() -> Sender<Update> {
                     ref upd @ Update {
                         update_id: u_id,
                         message: ref m,
+                        ..
                     },
                 ) => {
                     for handler in SYNC_HANDLERS.iter() {
@@ -219,6 +228,18 @@ pub fn init_async_handlers_loop() -> UnboundedSender<Update> {
     let (tx, mut rx) = unbounded_channel::<Update>();
 
     let f = async move {
+        for handler in ASYNC_HANDLERS.iter() {
+            crate::RUNTIME.spawn(async move {
+                if let Err(e) = handler.run_background().await {
+                    error!(
+                        "Background work of handler {} failed with error: {:?}",
+                        handler.name(),
+                        e
+                    );
+                }
+            });
+        }
+
         loop {
             match rx.recv().await {
                 Some(update) => {
@@ -242,6 +263,21 @@ pub fn init_async_handlers_loop() -> UnboundedSender<Update> {
 
                             }
 
+                            if let Some(q) = &u.callback_query
+                                && let Err(e) = handler.process_callback(q).await
+                            {
+                                error!(
+                                    "Problem while processing callback query {:?} by handler {} with error: {:?}",
+                                    q,
+                                    handler.name(),
+                                    e
+                                );
+                                if let Some(m) = &q.message {
+                                    async_send_error_message(&u.update_id, m, &handler.name())
+                                        .await;
+                                }
+                            }
+
                             ack_update(&handler.name(), &u.update_id);
                         });
                     }
@@ -269,6 +305,7 @@ fn send_error_message(
             handler_name
         ),
         reply_to_message_id: Some(&message.message_id),
+        reply_markup: None,
     };
     let result = telegram_client.send_message(message);
     match result {
@@ -288,6 +325,7 @@ async fn async_send_error_message(update_id: &i32, message: &Message, handler_na
             handler_name
         ),
         reply_to_message_id: Some(&message.message_id),
+        reply_markup: None,
     };
     let result = crate::TELEGRAM_CLIENT.async_send_message(message).await;
     match result {
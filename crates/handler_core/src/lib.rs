@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use reqwest::Client;
-use telegram_api::{Message, TelegramClient};
+use telegram_api::{CallbackQuery, Message, TelegramClient};
 
 pub trait Handler {
     fn name(&self) -> String;
@@ -14,6 +14,18 @@ pub trait AsyncHandler {
     fn name(&self) -> String;
 
     async fn process(&self, m: &Message) -> Result<()>;
+
+    /// Long running work of the handler like servers or schedulers,
+    /// started once together with the bot
+    async fn run_background(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for presses of inline keyboard buttons, handlers should
+    /// recognize their own buttons by the prefix of the callback data
+    async fn process_callback(&self, _q: &CallbackQuery) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct HandlerContext<'a> {
@@ -8,7 +8,7 @@ use std::{
 use async_trait::async_trait;
 use handler_core::{AsyncHandler, HandlerContext};
 use shlex::Shlex;
-use telegram_api::{Message, TelegramClient};
+use telegram_api::{FileKind, Message, TelegramClient};
 use tokio::{fs::create_dir, process::Command};
 
 use anyhow::anyhow;
@@ -93,7 +93,7 @@ impl<'a> DownloaderHandler<'a> {
         .into();
 
         self.telegram_client
-            .async_send_file(chat_id, downloaded_file_path)
+            .async_send_file(chat_id, downloaded_file_path, FileKind::Video)
             .await?;
 
         remove_dir_all(message_download_tmp_dir)?;
@@ -23,6 +23,7 @@ impl<'a> AsyncHandler for HealthCheckHandler<'a> {
                     chat_id: m.chat.id.to_string(),
                     text: String::from("pong"),
                     reply_to_message_id: Some(&m.message_id),
+                    reply_markup: None,
                 })
                 .await?),
             _ => Ok(()),
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum TorrentAdded {
+    Added { name: String },
+    Duplicate { name: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TorrentState {
+    Stopped,
+    Checking,
+    Queued,
+    Downloading,
+    Seeding,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct TorrentStatus {
+    /// Info hash, both Transmission and qBittorrent accept it as an id
+    pub id: String,
+    pub name: String,
+    /// From 0.0 to 1.0
+    pub progress: f64,
+    pub state: TorrentState,
+    pub size: u64,
+}
+
+impl TorrentStatus {
+    pub fn is_complete(&self) -> bool {
+        self.progress >= 1.0
+    }
+}
+
+#[derive(Debug)]
+pub struct TorrentFile {
+    /// Absolute path on the host of the torrent client, the bot
+    /// has to see the same download directory to send files
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// Torrent client answered 401/403 to the provided credentials
+    Unauthorized,
+    /// Torrent client processed the request, but reported a failure,
+    /// e.g. "invalid or corrupt torrent file"
+    Failed { method: String, result: String },
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Unauthorized => write!(f, "Torrent client rejected credentials"),
+            BackendError::Failed { method, result } => {
+                write!(f, "Torrent client method {} failed: {}", method, result)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+#[async_trait]
+pub trait TorrentBackend {
+    async fn add_torrent(&self, file_name: &str, content: &[u8]) -> Result<TorrentAdded>;
+
+    async fn add_magnet(&self, link: &str) -> Result<TorrentAdded>;
+
+    async fn list(&self) -> Result<Vec<TorrentStatus>>;
+
+    async fn start(&self, id: &str) -> Result<()>;
+
+    async fn stop(&self, id: &str) -> Result<()>;
+
+    /// Removes torrent from the client, downloaded data is kept
+    async fn remove(&self, id: &str) -> Result<()>;
+
+    async fn files(&self, id: &str) -> Result<Vec<TorrentFile>>;
+}
@@ -1,195 +1,688 @@
-use anyhow::{Context, Result};
+mod backend;
+#[cfg(test)]
+mod mock_server;
+mod qbittorrent;
+mod torznab;
+mod transmission;
+
+use anyhow::{Result, anyhow};
 use async_trait::async_trait;
-use base64::prelude::*;
+use backend::{
+    BackendError, TorrentAdded, TorrentBackend, TorrentFile, TorrentState, TorrentStatus,
+};
 use handler_core::{AsyncHandler, HandlerContext};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use qbittorrent::QBittorrentClient;
+use std::collections::VecDeque;
 use std::env;
-use telegram_api::{Message, SendMessage, TelegramClient};
+use std::path::Path;
+use std::sync::Mutex;
+use telegram_api::{
+    AnswerCallbackQuery, CallbackQuery, FileKind, InlineKeyboardButton, InlineKeyboardMarkup,
+    Message, SendMessage, TelegramClient,
+};
+use torznab::{SearchResult, TorznabClient};
+use transmission::TransmissionClient;
+
+const SHORT_ID_LENGTH: usize = 8;
+
+const SEARCH_CALLBACK_PREFIX: &str = "torrent_search:";
+const SEARCH_RESULTS_LIMIT: usize = 10;
+/// How many recent searches keep their results available for the buttons
+const SEARCH_HISTORY_SIZE: usize = 20;
+const BUTTON_TITLE_LENGTH: usize = 48;
+
+const FILE_CALLBACK_PREFIX: &str = "torrent_file:";
+/// Inline keyboard is limited to 100 buttons
+const FILE_BUTTONS_LIMIT: usize = 50;
+/// Files bigger than upload limit are sent as parts, but not more than that
+const FILE_PARTS_LIMIT: u64 = 20;
 
 pub struct TorrentHandler<'a> {
     telegram_client: &'a TelegramClient<'a>,
-    transmission_client: TransmissionClient<'a>,
+    backend: Box<dyn TorrentBackend + Send + Sync + 'a>,
+    torznab_client: Option<TorznabClient<'a>>,
+    /// Results of recent searches keyed by `chat_id:message_id` of the query
+    searches: Mutex<VecDeque<(String, Vec<SearchResult>)>>,
+}
+
+enum Control {
+    Start,
+    Stop,
+    Remove,
 }
 
 #[async_trait]
 impl<'a> AsyncHandler for TorrentHandler<'a> {
     fn name(&self) -> String {
-        String::from("TransmissionClient")
+        String::from("Torrent")
     }
 
     async fn process(&self, message: &Message) -> Result<()> {
-        let process_success = |r: Response| async move {
-            match r {
-                Response {
-                    arguments: ResponseArguments::TorerntAdded { name: n, .. },
-                    ..
-                } => {
-                    self.telegram_client
-                        .async_send_message(SendMessage {
-                            chat_id: message.chat.id.to_string(),
-                            text: format!("{} успешно добавлен", n),
-                            reply_to_message_id: Some(&message.message_id),
-                        })
-                        .await
-                }
-                Response {
-                    arguments: ResponseArguments::TorerntDuplicate { name: n, .. },
-                    ..
-                } => {
-                    self.telegram_client
-                        .async_send_message(SendMessage {
-                            chat_id: message.chat.id.to_string(),
-                            text: format!("{} уже был добавлен ранее", n),
-                            reply_to_message_id: Some(&message.message_id),
-                        })
-                        .await
-                }
+        let result = self.dispatch(message).await;
+        self.report_backend_error(message, result).await
+    }
+
+    async fn process_callback(&self, q: &CallbackQuery) -> Result<()> {
+        match (&q.message, &q.data) {
+            (Some(m), Some(data)) if data.starts_with(SEARCH_CALLBACK_PREFIX) => {
+                let result = self
+                    .add_search_result(q, m, &data[SEARCH_CALLBACK_PREFIX.len()..])
+                    .await;
+                self.report_backend_error(m, result).await
+            }
+            (Some(m), Some(data)) if data.starts_with(FILE_CALLBACK_PREFIX) => {
+                let result = self
+                    .send_torrent_file(q, m, &data[FILE_CALLBACK_PREFIX.len()..])
+                    .await;
+                self.report_backend_error(m, result).await
             }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a> TorrentHandler<'a> {
+    pub fn new(handler_context: &'a HandlerContext) -> Self {
+        let http_client = handler_context.async_http_client;
+        let backend: Box<dyn TorrentBackend + Send + Sync + 'a> = match env::var("TORRENT_BACKEND")
+            .as_deref()
+        {
+            Ok("qbittorrent") => Box::new(QBittorrentClient::new(http_client)),
+            Ok("transmission") | Ok("") | Err(_) => Box::new(TransmissionClient::new(http_client)),
+            Ok(other) => panic!(
+                "Unknown TORRENT_BACKEND {}, use transmission or qbittorrent please",
+                other
+            ),
         };
+        Self {
+            telegram_client: handler_context.telegram_client,
+            backend,
+            torznab_client: TorznabClient::new(http_client),
+            searches: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    async fn report_backend_error(&self, message: &Message, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e) => match e.downcast_ref::<BackendError>() {
+                Some(be) => self.send_failure_message(message, be).await,
+                None => Err(e),
+            },
+            ok => ok,
+        }
+    }
 
+    async fn dispatch(&self, message: &Message) -> Result<()> {
         match message {
             Message {
                 document: Some(doc),
                 ..
             } if doc.file_name.ends_with(".torrent") => {
-                process_success(self.process_torrent(&doc.file_id).await?).await
+                let added = self.process_torrent(&doc.file_name, &doc.file_id).await?;
+                self.send_added_message(message, added).await
+            }
+            Message { text: Some(t), .. } if t.starts_with("magnet:?") => {
+                let added = self.backend.add_magnet(t.trim()).await?;
+                self.send_added_message(message, added).await
+            }
+            Message { text: Some(t), .. } => {
+                let mut args = t.split_whitespace();
+                match (args.next(), args.next()) {
+                    (Some("/search"), _) => {
+                        let query = t.trim_start()["/search".len()..].trim();
+                        self.search(message, query).await
+                    }
+                    (Some("/torrents"), _) => self.list_torrents(message).await,
+                    (Some("/resume"), Some(id)) => {
+                        self.control_torrent(message, id, Control::Start).await
+                    }
+                    (Some("/pause"), Some(id)) => {
+                        self.control_torrent(message, id, Control::Stop).await
+                    }
+                    (Some("/remove"), Some(id)) => {
+                        self.control_torrent(message, id, Control::Remove).await
+                    }
+                    (Some("/files"), Some(id)) => self.list_files(message, id).await,
+                    _ => Ok(()),
+                }
             }
             _ => Ok(()),
         }
     }
-}
 
-impl<'a> TorrentHandler<'a> {
-    pub fn new(handler_context: &'a HandlerContext) -> Self {
-        Self {
-            telegram_client: handler_context.telegram_client,
-            transmission_client: TransmissionClient::new(handler_context.async_http_client),
+    async fn send_added_message(&self, message: &Message, added: TorrentAdded) -> Result<()> {
+        match added {
+            TorrentAdded::Added { name: n } => {
+                self.reply(message, format!("{} успешно добавлен", n)).await
+            }
+            TorrentAdded::Duplicate { name: n } => {
+                self.reply(message, format!("{} уже был добавлен ранее", n))
+                    .await
+            }
         }
     }
 
-    async fn process_torrent(&self, file_id: &str) -> Result<Response> {
+    async fn process_torrent(&self, file_name: &str, file_id: &str) -> Result<TorrentAdded> {
         let response = self.telegram_client.async_get_file(file_id).await?;
         let content = self
             .telegram_client
             .async_donwload_file(&response.result.file_path)
             .await?;
-        self.transmission_client
-            .torrent_add(&content.to_vec())
+        self.backend.add_torrent(file_name, &content).await
+    }
+
+    async fn search(&self, message: &Message, query: &str) -> Result<()> {
+        let torznab_client = match &self.torznab_client {
+            Some(c) => c,
+            None => {
+                return self
+                    .reply(
+                        message,
+                        String::from("Поиск не настроен, укажите TORZNAB_URL индексатора"),
+                    )
+                    .await;
+            }
+        };
+        if query.is_empty() {
+            return self
+                .reply(message, String::from("Укажите запрос: /search <запрос>"))
+                .await;
+        }
+
+        let results: Vec<SearchResult> = torznab_client
+            .search(query)
+            .await?
+            .into_iter()
+            .take(SEARCH_RESULTS_LIMIT)
+            .collect();
+        if results.is_empty() {
+            return self
+                .reply(message, format!("По запросу «{}» ничего не найдено", query))
+                .await;
+        }
+
+        let text = results
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                format!(
+                    "{}. {}\n{} · {} сидов",
+                    i + 1,
+                    r.title,
+                    format_size(r.size),
+                    r.seeders.map_or(String::from("?"), |s| s.to_string())
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        let inline_keyboard = results
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                vec![InlineKeyboardButton {
+                    text: format!(
+                        "{}. {}",
+                        i + 1,
+                        r.title
+                            .chars()
+                            .take(BUTTON_TITLE_LENGTH)
+                            .collect::<String>()
+                    ),
+                    callback_data: format!(
+                        "{}{}:{}",
+                        SEARCH_CALLBACK_PREFIX, message.message_id, i
+                    ),
+                }]
+            })
+            .collect();
+
+        {
+            let mut searches = self
+                .searches
+                .lock()
+                .expect("Search history lock is poisoned");
+            searches.push_back((
+                format!("{}:{}", message.chat.id, message.message_id),
+                results,
+            ));
+            if searches.len() > SEARCH_HISTORY_SIZE {
+                searches.pop_front();
+            }
+        }
+
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: message.chat.id.to_string(),
+                text,
+                reply_to_message_id: Some(&message.message_id),
+                reply_markup: Some(InlineKeyboardMarkup { inline_keyboard }),
+            })
             .await
     }
-}
 
-struct TransmissionClient<'a> {
-    transmission_address: String,
-    http_client: &'a Client,
-}
+    /// Adds the search result chosen by the inline keyboard button,
+    /// `data` is `query_message_id:result_index`
+    async fn add_search_result(
+        &self,
+        q: &CallbackQuery,
+        message: &Message,
+        data: &str,
+    ) -> Result<()> {
+        let (query_message_id, index) = data
+            .split_once(':')
+            .ok_or(anyhow!("Malformed search callback data {}", data))?;
+        let key = format!("{}:{}", message.chat.id, query_message_id);
+        let index: usize = index.parse()?;
+        let result = self
+            .searches
+            .lock()
+            .expect("Search history lock is poisoned")
+            .iter()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, results)| results.get(index).cloned());
 
-#[derive(Serialize, Debug)]
-#[serde(untagged)]
-enum RequestArguments {
-    TorrentAdd {
-        #[serde(skip_serializing_if = "Option::is_none")]
-        filename: Option<String>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        metainfo: Option<String>,
-    },
-}
-#[derive(Serialize, Debug)]
-struct Request {
-    method: String,
-    arguments: RequestArguments,
-}
+        let (result, torznab_client) = match (result, &self.torznab_client) {
+            (Some(r), Some(c)) => (r, c),
+            _ => {
+                return self
+                    .telegram_client
+                    .async_answer_callback_query(AnswerCallbackQuery {
+                        callback_query_id: &q.id,
+                        text: Some(String::from("Результаты поиска устарели, повторите поиск")),
+                    })
+                    .await;
+            }
+        };
+        self.telegram_client
+            .async_answer_callback_query(AnswerCallbackQuery {
+                callback_query_id: &q.id,
+                text: Some(format!("Добавляю {}", result.title)),
+            })
+            .await?;
 
-#[derive(Deserialize, Debug)]
-struct Response {
-    #[expect(unused)]
-    result: String,
-    arguments: ResponseArguments,
-}
+        let added = match (&result.magnet, &result.link) {
+            (Some(magnet), _) => self.backend.add_magnet(magnet).await?,
+            (None, Some(link)) => {
+                let content = torznab_client.download(link).await?;
+                self.backend
+                    .add_torrent(&format!("{}.torrent", result.title), &content)
+                    .await?
+            }
+            (None, None) => return Err(anyhow!("Search result {} has no link", result.title)),
+        };
+        self.send_added_message(message, added).await
+    }
 
-#[derive(Deserialize, Debug)]
-enum ResponseArguments {
-    #[serde(rename = "torrent-duplicate")]
-    TorerntDuplicate {
-        #[expect(unused)]
-        id: i32,
-        name: String,
-    },
-
-    #[serde(rename = "torrent-added")]
-    TorerntAdded {
-        #[expect(unused)]
-        id: i32,
-        name: String,
-    },
-}
+    async fn list_torrents(&self, message: &Message) -> Result<()> {
+        let torrents = self.backend.list().await?;
+        let text = if torrents.is_empty() {
+            String::from("Список торрентов пуст")
+        } else {
+            torrents
+                .iter()
+                .map(format_status)
+                .collect::<Vec<String>>()
+                .join("\n\n")
+        };
+        self.reply(message, text).await
+    }
 
-impl<'a> TransmissionClient<'a> {
-    fn new(http_client: &'a Client) -> Self {
-        let transmission_address = {
-            env::var("TRANSMISSION_ADDRESS")
-                .expect("Provide TRANSMISSION_ADDRESS environment variable please")
+    async fn control_torrent(&self, message: &Message, id: &str, control: Control) -> Result<()> {
+        let torrent = match self.find_torrent(id).await? {
+            Some(t) => t,
+            None => {
+                return self
+                    .reply(message, format!("Торрент {} не найден", id))
+                    .await;
+            }
         };
-        Self {
-            transmission_address: transmission_address,
-            http_client: http_client,
+        let text = match control {
+            Control::Start => {
+                self.backend.start(&torrent.id).await?;
+                format!("{} возобновлен", torrent.name)
+            }
+            Control::Stop => {
+                self.backend.stop(&torrent.id).await?;
+                format!("{} остановлен", torrent.name)
+            }
+            Control::Remove => {
+                self.backend.remove(&torrent.id).await?;
+                format!("{} удален, скачанные файлы сохранены", torrent.name)
+            }
+        };
+        self.reply(message, text).await
+    }
+
+    async fn list_files(&self, message: &Message, id: &str) -> Result<()> {
+        let torrent = match self.find_torrent(id).await? {
+            Some(t) => t,
+            None => {
+                return self
+                    .reply(message, format!("Торрент {} не найден", id))
+                    .await;
+            }
+        };
+        if !torrent.is_complete() {
+            return self
+                .reply(
+                    message,
+                    format!(
+                        "{} еще загружается ({:.0}%)",
+                        torrent.name,
+                        torrent.progress * 100.0
+                    ),
+                )
+                .await;
         }
+
+        let files = self.backend.files(&torrent.id).await?;
+        let mut text = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let note = match part_count(f.size) {
+                    1 => String::new(),
+                    p if p <= FILE_PARTS_LIMIT => format!(" · {} частей", p),
+                    _ => String::from(" · слишком большой для отправки"),
+                };
+                format!(
+                    "{}. {} · {}{}",
+                    i + 1,
+                    file_name(&f.path),
+                    format_size(f.size),
+                    note
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        if files.len() > FILE_BUTTONS_LIMIT {
+            text.push_str(&format!(
+                "\n\nДля отправки доступны первые {} файлов",
+                FILE_BUTTONS_LIMIT
+            ));
+        }
+        let short_id = short_id(&torrent.id);
+        let inline_keyboard = files
+            .iter()
+            .enumerate()
+            .take(FILE_BUTTONS_LIMIT)
+            .map(|(i, f)| {
+                vec![InlineKeyboardButton {
+                    text: format!(
+                        "{}. {}",
+                        i + 1,
+                        file_name(&f.path)
+                            .chars()
+                            .take(BUTTON_TITLE_LENGTH)
+                            .collect::<String>()
+                    ),
+                    callback_data: format!("{}{}:{}", FILE_CALLBACK_PREFIX, short_id, i),
+                }]
+            })
+            .collect();
+
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: message.chat.id.to_string(),
+                text: format!("{}\n\n{}", torrent.name, text),
+                reply_to_message_id: Some(&message.message_id),
+                reply_markup: Some(InlineKeyboardMarkup { inline_keyboard }),
+            })
+            .await
     }
 
-    async fn req_with_sessions_id_loop<T: serde::de::DeserializeOwned>(
+    /// Sends the file chosen by the inline keyboard button,
+    /// `data` is `short_torrent_id:file_index`
+    async fn send_torrent_file(
         &self,
-        request: Request,
-    ) -> Result<T> {
-        // TODO: success session id should be persisted
-        let first_try_resp: Result<reqwest::Response> = self
-            .http_client
-            .post(&self.transmission_address)
-            .body(
-                serde_json::to_string(&request)
-                    .with_context(|| format!("Failed to serialize request {:?}", request))?,
-            )
-            .send()
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to send http post request to transmission api {:?}",
-                    request
+        q: &CallbackQuery,
+        message: &Message,
+        data: &str,
+    ) -> Result<()> {
+        let (id, index) = data
+            .split_once(':')
+            .ok_or(anyhow!("Malformed file callback data {}", data))?;
+        let index: usize = index.parse()?;
+        let file = match self.find_torrent(id).await? {
+            Some(t) => self.backend.files(&t.id).await?.into_iter().nth(index),
+            None => None,
+        };
+        let file = match file {
+            Some(f) => f,
+            None => {
+                return self
+                    .telegram_client
+                    .async_answer_callback_query(AnswerCallbackQuery {
+                        callback_query_id: &q.id,
+                        text: Some(String::from("Файл не найден, возможно торрент был удален")),
+                    })
+                    .await;
+            }
+        };
+        self.telegram_client
+            .async_answer_callback_query(AnswerCallbackQuery {
+                callback_query_id: &q.id,
+                text: Some(format!("Отправляю {}", file_name(&file.path))),
+            })
+            .await?;
+        self.send_file(message, &file).await
+    }
+
+    /// Sends the file as is if it fits upload limit, otherwise splits it
+    /// into `name.001`, `name.002`... documents which can be joined by `cat`
+    async fn send_file(&self, message: &Message, file: &TorrentFile) -> Result<()> {
+        let chat_id = message.chat.id.to_string();
+        let name = file_name(&file.path);
+        let parts = part_count(file.size);
+        if parts == 1 {
+            self.telegram_client
+                .async_send_file_range(
+                    &chat_id,
+                    &file.path,
+                    name.to_string(),
+                    0,
+                    file.size,
+                    file_kind(&file.path),
                 )
-            });
-        let result = match first_try_resp {
-            Ok(r) if r.status() == reqwest::StatusCode::CONFLICT => {
-                if let Some(session_id) = r.headers().get("X-Transmission-Session-Id") {
-                    Ok(self
-                        .http_client
-                        .post(&self.transmission_address)
-                        .body(serde_json::to_string(&request)?)
-                        .header("X-Transmission-Session-Id", session_id.to_str()?)
-                        .send()
-                        .await
-                        .with_context(|| format!("Failed to send http post request to transmission api with correct session-id {:?}", request))?)
-                } else {
-                    Ok(r)
-                }
+                .await?;
+            return Ok(());
+        }
+        if parts > FILE_PARTS_LIMIT {
+            return self
+                .reply(
+                    message,
+                    format!(
+                        "Файл {} слишком большой для отправки ({})",
+                        name,
+                        format_size(file.size)
+                    ),
+                )
+                .await;
+        }
+
+        self.reply(
+            message,
+            format!(
+                "Файл {} больше лимита Telegram, отправляю {} частями. Собрать: cat {}.* > {}",
+                name, parts, name, name
+            ),
+        )
+        .await?;
+        for part in 1..=parts {
+            let (offset, length) = part_range(file.size, part);
+            self.telegram_client
+                .async_send_file_range(
+                    &chat_id,
+                    &file.path,
+                    format!("{}.{:03}", name, part),
+                    offset,
+                    length,
+                    FileKind::Document,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Looks up the torrent by the id prefix shown in /torrents list
+    async fn find_torrent(&self, id: &str) -> Result<Option<TorrentStatus>> {
+        let id = id.to_lowercase();
+        let mut found = self
+            .backend
+            .list()
+            .await?
+            .into_iter()
+            .filter(|t| t.id.to_lowercase().starts_with(&id));
+        match (found.next(), found.next()) {
+            (Some(t), None) => Ok(Some(t)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn send_failure_message(&self, message: &Message, e: &BackendError) -> Result<()> {
+        let text = match e {
+            BackendError::Unauthorized => {
+                String::from("Торрент-клиент отклонил логин и пароль для доступа к API")
+            }
+            BackendError::Failed { result, .. } => {
+                format!("Торрент-клиент вернул ошибку: {}", result)
             }
-            r => r,
         };
-        Ok(result?
-            .json()
+        self.reply(message, text).await
+    }
+
+    async fn reply(&self, message: &Message, text: String) -> Result<()> {
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: message.chat.id.to_string(),
+                text,
+                reply_to_message_id: Some(&message.message_id),
+                reply_markup: None,
+            })
             .await
-            .with_context(|| format!("Failed to parse result for request {:?}", request))?)
     }
+}
 
-    pub async fn torrent_add(&self, file_content: &[u8]) -> Result<Response> {
-        let base64_encoded = BASE64_STANDARD.encode(file_content);
-        let request = Request {
-            method: "torrent-add".to_string(),
-            arguments: RequestArguments::TorrentAdd {
-                filename: None,
-                metainfo: Some(base64_encoded),
-            },
-        };
+fn format_status(t: &TorrentStatus) -> String {
+    let state = match t.state {
+        _ if t.is_complete() && t.state != TorrentState::Error => "завершен",
+        TorrentState::Stopped => "остановлен",
+        TorrentState::Checking => "проверка",
+        TorrentState::Queued => "в очереди",
+        TorrentState::Downloading => "загрузка",
+        TorrentState::Seeding => "раздача",
+        TorrentState::Error => "ошибка",
+    };
+    format!(
+        "{}\n{:.0}% · {} · {} · {}",
+        t.name,
+        t.progress * 100.0,
+        format_size(t.size),
+        state,
+        short_id(&t.id)
+    )
+}
+
+/// Number of documents the file is sent as, an empty file is one document
+fn part_count(size: u64) -> u64 {
+    size.div_ceil(TelegramClient::MAX_UPLOAD_SIZE).max(1)
+}
+
+/// Offset and length of the part of the file, parts are numbered from 1
+fn part_range(size: u64, part: u64) -> (u64, u64) {
+    let offset = (part - 1) * TelegramClient::MAX_UPLOAD_SIZE;
+    (offset, TelegramClient::MAX_UPLOAD_SIZE.min(size - offset))
+}
+
+fn short_id(id: &str) -> String {
+    id.chars().take(SHORT_ID_LENGTH).collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Telegram plays mp3/m4a as audio and mp4 as video, the rest is a document
+fn file_kind(path: &Path) -> FileKind {
+    match path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        Some("mp3") | Some("m4a") => FileKind::Audio,
+        Some("mp4") => FileKind::Video,
+        _ => FileKind::Document,
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u64 = TelegramClient::MAX_UPLOAD_SIZE;
+
+    #[test]
+    fn splits_files_over_the_upload_limit() {
+        assert_eq!(part_count(0), 1);
+        assert_eq!(part_count(1), 1);
+        assert_eq!(part_count(LIMIT), 1);
+        assert_eq!(part_count(LIMIT + 1), 2);
+        assert_eq!(part_count(2 * LIMIT), 2);
+        assert_eq!(part_count(2 * LIMIT + 1), 3);
+        assert_eq!(part_count(FILE_PARTS_LIMIT * LIMIT), FILE_PARTS_LIMIT);
+    }
+
+    #[test]
+    fn covers_the_file_with_parts() {
+        assert_eq!(part_range(LIMIT, 1), (0, LIMIT));
+        assert_eq!(part_range(LIMIT + 1, 1), (0, LIMIT));
+        assert_eq!(part_range(LIMIT + 1, 2), (LIMIT, 1));
+        assert_eq!(part_range(2 * LIMIT, 2), (LIMIT, LIMIT));
+
+        let size = 5 * LIMIT + 12345;
+        let parts: Vec<(u64, u64)> = (1..=part_count(size))
+            .map(|p| part_range(size, p))
+            .collect();
+        let mut end = 0;
+        for (offset, length) in &parts {
+            assert_eq!(*offset, end);
+            assert!(*length > 0 && *length <= LIMIT);
+            end = offset + length;
+        }
+        assert_eq!(end, size);
+    }
+
+    #[test]
+    fn shortens_ids() {
+        assert_eq!(
+            short_id("0123456789abcdef0123456789abcdef01234567"),
+            "01234567"
+        );
+        assert_eq!(short_id("abc"), "abc");
+        assert_eq!(short_id(""), "");
+    }
 
-        self.req_with_sessions_id_loop(request).await
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0.0 B");
+        assert_eq!(format_size(1023), "1023.0 B");
+        assert_eq!(format_size(1024), "1.0 KB");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(LIMIT), "50.0 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+        assert_eq!(format_size(2048 * 1024u64.pow(4)), "2048.0 TB");
     }
 }
@@ -0,0 +1,511 @@
+use super::backend::{
+    BackendError, TorrentAdded, TorrentBackend, TorrentFile, TorrentState, TorrentStatus,
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use reqwest::header::{COOKIE, SET_COOKIE};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+
+/// Client for qBittorrent Web API v2, both 4.x and 5.x versions are supported
+pub struct QBittorrentClient<'a> {
+    qbittorrent_address: String,
+    credentials: Option<(String, String)>,
+    /// Session cookie in the `name=value` form, the name differs between versions
+    session_cookie: RwLock<Option<String>>,
+    http_client: &'a Client,
+}
+
+#[derive(Deserialize, Debug)]
+struct Torrent {
+    hash: String,
+    name: String,
+    progress: f64,
+    state: String,
+    size: u64,
+    save_path: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct File {
+    /// Relative to the save path of the torrent
+    name: String,
+    size: u64,
+}
+
+impl From<Torrent> for TorrentStatus {
+    fn from(t: Torrent) -> Self {
+        let state = match t.state.as_str() {
+            "error" | "missingFiles" => TorrentState::Error,
+            "pausedUP" | "pausedDL" | "stoppedUP" | "stoppedDL" => TorrentState::Stopped,
+            "checkingUP" | "checkingDL" | "checkingResumeData" | "allocating" | "moving" => {
+                TorrentState::Checking
+            }
+            "queuedUP" | "queuedDL" => TorrentState::Queued,
+            "uploading" | "stalledUP" | "forcedUP" => TorrentState::Seeding,
+            _ => TorrentState::Downloading,
+        };
+        TorrentStatus {
+            id: t.hash,
+            name: t.name,
+            progress: t.progress,
+            state,
+            size: t.size,
+        }
+    }
+}
+
+impl<'a> QBittorrentClient<'a> {
+    pub fn new(http_client: &'a Client) -> Self {
+        let qbittorrent_address = {
+            env::var("QBITTORRENT_ADDRESS")
+                .expect("Provide QBITTORRENT_ADDRESS environment variable please")
+        };
+        let credentials = match env::var("QBITTORRENT_USERNAME") {
+            Ok(username) if !username.is_empty() => Some((
+                username,
+                env::var("QBITTORRENT_PASSWORD").expect(
+                    "Provide QBITTORRENT_PASSWORD environment variable please, \
+                     it is required when QBITTORRENT_USERNAME is set",
+                ),
+            )),
+            _ => None,
+        };
+        Self {
+            qbittorrent_address: qbittorrent_address.trim_end_matches('/').to_string(),
+            credentials,
+            session_cookie: RwLock::new(None),
+            http_client,
+        }
+    }
+
+    fn api_url(&self, method: &str) -> String {
+        format!("{}/api/v2/{}", self.qbittorrent_address, method)
+    }
+
+    async fn login(&self) -> Result<()> {
+        let (username, password) = self
+            .credentials
+            .as_ref()
+            .ok_or(BackendError::Unauthorized)?;
+        let response = self
+            .http_client
+            .post(self.api_url("auth/login"))
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await
+            .with_context(|| "Failed to send login request to qBittorrent api")?;
+
+        let session_cookie = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|c| c.to_str().ok())
+            .filter_map(|c| c.split(';').next())
+            .find(|c| is_session_cookie(c))
+            .map(|c| c.to_string());
+
+        match session_cookie {
+            Some(c) => {
+                *self.session_cookie.write().await = Some(c);
+                Ok(())
+            }
+            None => Err(BackendError::Unauthorized.into()),
+        }
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        request: &impl Fn() -> RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let mut request = request();
+        if let Some(cookie) = self.session_cookie.read().await.as_ref() {
+            request = request.header(COOKIE, cookie);
+        }
+        request
+            .send()
+            .await
+            .with_context(|| format!("Failed to send request {} to qBittorrent api", method))
+    }
+
+    /// Sends the request with the cached session cookie and logs in again
+    /// only when qBittorrent answers with 403 Forbidden.
+    async fn call(
+        &self,
+        method: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let mut response = self.send(method, &request).await?;
+        if response.status() == StatusCode::FORBIDDEN {
+            self.login().await?;
+            response = self.send(method, &request).await?;
+        }
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err(BackendError::Unauthorized.into());
+        }
+        Ok(response)
+    }
+
+    async fn torrents_add(
+        &self,
+        name: String,
+        form: impl Fn() -> reqwest::multipart::Form,
+    ) -> Result<TorrentAdded> {
+        let response = self
+            .call("torrents/add", || {
+                self.http_client
+                    .post(self.api_url("torrents/add"))
+                    .multipart(form())
+            })
+            .await?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .with_context(|| "Failed to read qBittorrent response for torrents/add")?;
+        // qBittorrent doesn't distinguish duplicates from broken torrents
+        // and answers "Fails." for both, unparsable files get 415 status
+        if !status.is_success() || text.trim() == "Fails." {
+            Err(BackendError::Failed {
+                method: "torrents/add".to_string(),
+                result: format!(
+                    "invalid or already added torrent: {} {}",
+                    status,
+                    text.trim()
+                ),
+            }
+            .into())
+        } else {
+            Ok(TorrentAdded::Added { name })
+        }
+    }
+
+    async fn torrents_info(&self, id: Option<&str>) -> Result<Vec<Torrent>> {
+        self.call("torrents/info", || {
+            let request = self.http_client.get(self.api_url("torrents/info"));
+            match id {
+                Some(id) => request.query(&[("hashes", id)]),
+                None => request,
+            }
+        })
+        .await?
+        .error_for_status()
+        .with_context(|| "qBittorrent rejected request torrents/info")?
+        .json()
+        .await
+        .with_context(|| "Failed to parse qBittorrent response for torrents/info")
+    }
+
+    async fn torrents_action(&self, methods: &[&str], id: &str) -> Result<()> {
+        for method in methods {
+            let response = self
+                .call(method, || {
+                    self.http_client
+                        .post(self.api_url(method))
+                        .form(&[("hashes", id)])
+                })
+                .await?;
+            // 5.x renamed pause/resume to stop/start, try the next alias
+            if response.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+            response
+                .error_for_status()
+                .with_context(|| format!("qBittorrent rejected request {}", method))?;
+            return Ok(());
+        }
+        Err(BackendError::Failed {
+            method: methods.join("/"),
+            result: "method is not supported by qBittorrent".to_string(),
+        }
+        .into())
+    }
+}
+
+/// 4.x names the cookie SID, 5.x adds the port: QBT_SID_8080
+fn is_session_cookie(cookie: &str) -> bool {
+    cookie
+        .split_once('=')
+        .is_some_and(|(name, _)| name == "SID" || name.starts_with("QBT_SID_"))
+}
+
+#[async_trait]
+impl<'a> TorrentBackend for QBittorrentClient<'a> {
+    async fn add_torrent(&self, file_name: &str, content: &[u8]) -> Result<TorrentAdded> {
+        let name = file_name.trim_end_matches(".torrent").to_string();
+        self.torrents_add(name, || {
+            reqwest::multipart::Form::new().part(
+                "torrents",
+                reqwest::multipart::Part::bytes(content.to_vec()).file_name(file_name.to_string()),
+            )
+        })
+        .await
+    }
+
+    async fn add_magnet(&self, link: &str) -> Result<TorrentAdded> {
+        let name = Url::parse(link)
+            .ok()
+            .and_then(|u| {
+                u.query_pairs()
+                    .find(|(k, _)| k == "dn")
+                    .map(|(_, v)| v.to_string())
+            })
+            .unwrap_or(link.to_string());
+        self.torrents_add(name, || {
+            reqwest::multipart::Form::new().text("urls", link.to_string())
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<TorrentStatus>> {
+        Ok(self
+            .torrents_info(None)
+            .await?
+            .into_iter()
+            .map(|t| t.into())
+            .collect())
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        self.torrents_action(&["torrents/start", "torrents/resume"], id)
+            .await
+    }
+
+    async fn stop(&self, id: &str) -> Result<()> {
+        self.torrents_action(&["torrents/stop", "torrents/pause"], id)
+            .await
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        let response = self
+            .call("torrents/delete", || {
+                self.http_client
+                    .post(self.api_url("torrents/delete"))
+                    .form(&[("hashes", id), ("deleteFiles", "false")])
+            })
+            .await?;
+        response
+            .error_for_status()
+            .with_context(|| "qBittorrent rejected request torrents/delete")?;
+        Ok(())
+    }
+
+    async fn files(&self, id: &str) -> Result<Vec<TorrentFile>> {
+        let torrent = self
+            .torrents_info(Some(id))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("qBittorrent has no torrent with hash {}", id))?;
+        let files: Vec<File> = self
+            .call("torrents/files", || {
+                self.http_client
+                    .get(self.api_url("torrents/files"))
+                    .query(&[("hash", id)])
+            })
+            .await?
+            .error_for_status()
+            .with_context(|| "qBittorrent rejected request torrents/files")?
+            .json()
+            .await
+            .with_context(|| "Failed to parse qBittorrent response for torrents/files")?;
+        Ok(files
+            .into_iter()
+            .map(|f| TorrentFile {
+                path: PathBuf::from(&torrent.save_path).join(f.name),
+                size: f.size,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer, header, http_client, path};
+
+    const TORRENTS: &str = r#"[{"hash":"abc","name":"Ubuntu","progress":0.5,"state":"stalledDL","size":1024,"save_path":"/downloads"}]"#;
+
+    fn client<'a>(http_client: &'a Client, mock: &MockServer) -> QBittorrentClient<'a> {
+        QBittorrentClient {
+            qbittorrent_address: mock.url(""),
+            credentials: Some(("admin".to_string(), "secret".to_string())),
+            session_cookie: RwLock::new(None),
+            http_client,
+        }
+    }
+
+    fn torrent(state: &str) -> Torrent {
+        Torrent {
+            hash: "abc".to_string(),
+            name: "Ubuntu".to_string(),
+            progress: 1.0,
+            state: state.to_string(),
+            size: 1024,
+            save_path: "/downloads".to_string(),
+        }
+    }
+
+    #[test]
+    fn maps_torrent_states() {
+        let cases = [
+            ("error", TorrentState::Error),
+            ("missingFiles", TorrentState::Error),
+            ("pausedUP", TorrentState::Stopped),
+            ("stoppedDL", TorrentState::Stopped),
+            ("checkingResumeData", TorrentState::Checking),
+            ("moving", TorrentState::Checking),
+            ("queuedDL", TorrentState::Queued),
+            ("stalledUP", TorrentState::Seeding),
+            ("forcedUP", TorrentState::Seeding),
+            ("downloading", TorrentState::Downloading),
+            ("metaDL", TorrentState::Downloading),
+        ];
+        for (state, expected) in cases {
+            assert_eq!(
+                TorrentStatus::from(torrent(state)).state,
+                expected,
+                "{}",
+                state
+            );
+        }
+
+        let status = TorrentStatus::from(torrent("uploading"));
+        assert_eq!(status.id, "abc");
+        assert_eq!(status.name, "Ubuntu");
+        assert_eq!(status.size, 1024);
+        assert!(status.is_complete());
+    }
+
+    #[test]
+    fn picks_session_cookie_of_both_versions() {
+        assert!(is_session_cookie("SID=abc"));
+        assert!(is_session_cookie("QBT_SID_8080=abc"));
+        assert!(!is_session_cookie("NOSID=abc"));
+        assert!(!is_session_cookie("SID"));
+    }
+
+    #[tokio::test]
+    async fn logs_in_again_on_forbidden() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(403, "Forbidden"),
+            MockResponse::new(200, "Ok.")
+                .header("Set-Cookie", "theme=dark; path=/")
+                .header("Set-Cookie", "QBT_SID_8080=session-1; HttpOnly; path=/"),
+            MockResponse::new(200, TORRENTS),
+            MockResponse::new(200, TORRENTS),
+        ])
+        .await;
+        let http_client = http_client();
+        let qbittorrent = client(&http_client, &mock);
+
+        let torrents = qbittorrent.list().await.unwrap();
+        assert_eq!(torrents.len(), 1);
+        assert_eq!(torrents[0].state, TorrentState::Downloading);
+        // Session cookie is cached for the next requests
+        qbittorrent.list().await.unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(path(&requests[0]), Some("/api/v2/torrents/info"));
+        assert_eq!(header(&requests[0], "cookie"), None);
+        assert_eq!(path(&requests[1]), Some("/api/v2/auth/login"));
+        assert!(requests[1].ends_with("username=admin&password=secret"));
+        assert_eq!(
+            header(&requests[2], "cookie"),
+            Some("QBT_SID_8080=session-1")
+        );
+        assert_eq!(
+            header(&requests[3], "cookie"),
+            Some("QBT_SID_8080=session-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unauthorized_without_session_cookie() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(403, "Forbidden"),
+            MockResponse::new(200, "Fails."),
+        ])
+        .await;
+        let http_client = http_client();
+        let qbittorrent = client(&http_client, &mock);
+
+        let error = qbittorrent.list().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BackendError>(),
+            Some(BackendError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_methods_of_4x() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(404, "Not Found"),
+            MockResponse::new(200, ""),
+            MockResponse::new(200, ""),
+        ])
+        .await;
+        let http_client = http_client();
+        let qbittorrent = client(&http_client, &mock);
+
+        qbittorrent.start("abc").await.unwrap();
+        qbittorrent.stop("abc").await.unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(path(&requests[0]), Some("/api/v2/torrents/start"));
+        assert_eq!(path(&requests[1]), Some("/api/v2/torrents/resume"));
+        assert!(requests[1].ends_with("hashes=abc"));
+        // 5.x methods are tried first
+        assert_eq!(path(&requests[2]), Some("/api/v2/torrents/stop"));
+    }
+
+    #[tokio::test]
+    async fn reports_unsupported_action() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(404, "Not Found"),
+            MockResponse::new(404, "Not Found"),
+        ])
+        .await;
+        let http_client = http_client();
+        let qbittorrent = client(&http_client, &mock);
+
+        let error = qbittorrent.stop("abc").await.unwrap_err();
+        match error.downcast_ref::<BackendError>() {
+            Some(BackendError::Failed { method, .. }) => {
+                assert_eq!(method, "torrents/stop/torrents/pause")
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_failed_add() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(200, "Fails."),
+            MockResponse::new(200, "Ok."),
+        ])
+        .await;
+        let http_client = http_client();
+        let qbittorrent = client(&http_client, &mock);
+
+        let error = qbittorrent
+            .add_magnet("magnet:?xt=urn:btih:abc&dn=Ubuntu")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BackendError>(),
+            Some(BackendError::Failed { .. })
+        ));
+
+        let added = qbittorrent
+            .add_magnet("magnet:?xt=urn:btih:abc&dn=Ubuntu")
+            .await
+            .unwrap();
+        assert!(matches!(added, TorrentAdded::Added { name } if name == "Ubuntu"));
+        assert_eq!(path(&mock.requests()[1]), Some("/api/v2/torrents/add"));
+    }
+}
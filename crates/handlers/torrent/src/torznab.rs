@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use reqwest::Client;
+use rss::Channel;
+use std::cmp::Reverse;
+use std::env;
+
+const TORZNAB_NAMESPACE: &str = "torznab";
+
+/// Client for Torznab compatible indexers like Jackett or Prowlarr
+pub struct TorznabClient<'a> {
+    torznab_url: String,
+    api_key: String,
+    http_client: &'a Client,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub title: String,
+    pub size: u64,
+    pub seeders: Option<u32>,
+    /// Link to the .torrent file on the indexer
+    pub link: Option<String>,
+    pub magnet: Option<String>,
+}
+
+impl<'a> TorznabClient<'a> {
+    /// Returns None if indexer is not configured
+    pub fn new(http_client: &'a Client) -> Option<Self> {
+        let torznab_url = env::var("TORZNAB_URL").ok().filter(|u| !u.is_empty())?;
+        let api_key = env::var("TORZNAB_API_KEY")
+            .expect("Provide TORZNAB_API_KEY environment variable please");
+        Some(Self {
+            torznab_url,
+            api_key,
+            http_client,
+        })
+    }
+
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        let content = self
+            .http_client
+            .get(&self.torznab_url)
+            .query(&[("t", "search"), ("q", query), ("apikey", &self.api_key)])
+            .send()
+            .await
+            .with_context(|| format!("Failed to send search request for query {}", query))?
+            .error_for_status()
+            .with_context(|| format!("Indexer rejected search request for query {}", query))?
+            .text()
+            .await
+            .with_context(|| format!("Failed to read search response for query {}", query))?;
+
+        parse_results(&content)
+            .with_context(|| format!("Failed to parse search response for query {}", query))
+    }
+
+    pub async fn download(&self, link: &str) -> Result<Vec<u8>> {
+        Ok(self
+            .http_client
+            .get(link)
+            .send()
+            .await
+            .with_context(|| format!("Failed to download torrent file {}", link))?
+            .error_for_status()
+            .with_context(|| format!("Indexer rejected download of torrent file {}", link))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read torrent file {}", link))?
+            .to_vec())
+    }
+}
+
+/// Results of the indexer feed, the most seeded first
+fn parse_results(content: &str) -> Result<Vec<SearchResult>> {
+    let channel = Channel::read_from(content.as_bytes())?;
+    let mut results: Vec<SearchResult> = channel
+        .items()
+        .iter()
+        .map(|item| {
+            let enclosure = item.enclosure();
+            let size = torznab_attr(item, "size")
+                .or(enclosure.map(|e| e.length()))
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0);
+            let link = enclosure
+                .map(|e| e.url())
+                .or(item.link())
+                .map(|l| l.to_string());
+            SearchResult {
+                title: item.title().unwrap_or_default().to_string(),
+                size,
+                seeders: torznab_attr(item, "seeders").and_then(|s| s.parse().ok()),
+                magnet: torznab_attr(item, "magneturl")
+                    .or(link.as_deref().filter(|l| l.starts_with("magnet:")))
+                    .map(|m| m.to_string()),
+                link,
+            }
+        })
+        .collect();
+    results.sort_by_key(|r| Reverse(r.seeders));
+    Ok(results)
+}
+
+/// Reads `<torznab:attr name="..." value="..."/>` item extension
+fn torznab_attr<'i>(item: &'i rss::Item, name: &str) -> Option<&'i str> {
+    item.extensions()
+        .get(TORZNAB_NAMESPACE)?
+        .get("attr")?
+        .iter()
+        .find(|e| e.attrs().get("name").map(|n| n.as_str()) == Some(name))?
+        .attrs()
+        .get("value")
+        .map(|v| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed response of Jackett to `t=search`
+    const RESPONSE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:torznab="http://torznab.com/schemas/2015/feed">
+  <channel>
+    <title>AggregateSearch</title>
+    <description>Search results</description>
+    <link>http://127.0.0.1:9117/</link>
+    <item>
+      <title>Ubuntu 24.04 Desktop</title>
+      <guid>http://tracker.example.com/t/1</guid>
+      <link>http://127.0.0.1:9117/dl/tracker/?file=Ubuntu</link>
+      <size>6114656256</size>
+      <enclosure url="http://127.0.0.1:9117/dl/tracker/?file=Ubuntu" length="6114656256" type="application/x-bittorrent" />
+      <torznab:attr name="seeders" value="12" />
+      <torznab:attr name="peers" value="15" />
+      <torznab:attr name="magneturl" value="magnet:?xt=urn:btih:aaaa" />
+    </item>
+    <item>
+      <title>Ubuntu 24.04 Server</title>
+      <guid>http://tracker.example.com/t/2</guid>
+      <link>magnet:?xt=urn:btih:bbbb</link>
+      <torznab:attr name="size" value="2754981888" />
+      <torznab:attr name="seeders" value="40" />
+    </item>
+    <item>
+      <title>Ubuntu 24.04 Sources</title>
+      <guid>http://tracker.example.com/t/3</guid>
+      <enclosure url="http://127.0.0.1:9117/dl/tracker/?file=Sources" length="1024" type="application/x-bittorrent" />
+    </item>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn parses_indexer_results() {
+        let results = parse_results(RESPONSE).unwrap();
+        let titles: Vec<&str> = results.iter().map(|r| r.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            [
+                "Ubuntu 24.04 Server",
+                "Ubuntu 24.04 Desktop",
+                "Ubuntu 24.04 Sources"
+            ]
+        );
+
+        // Magnet link of the item is used when there is no magneturl
+        let server = &results[0];
+        assert_eq!(server.size, 2754981888);
+        assert_eq!(server.seeders, Some(40));
+        assert_eq!(server.link.as_deref(), Some("magnet:?xt=urn:btih:bbbb"));
+        assert_eq!(server.magnet.as_deref(), Some("magnet:?xt=urn:btih:bbbb"));
+
+        // Size comes from the enclosure without the size attribute
+        let desktop = &results[1];
+        assert_eq!(desktop.size, 6114656256);
+        assert_eq!(desktop.seeders, Some(12));
+        assert_eq!(
+            desktop.link.as_deref(),
+            Some("http://127.0.0.1:9117/dl/tracker/?file=Ubuntu")
+        );
+        assert_eq!(desktop.magnet.as_deref(), Some("magnet:?xt=urn:btih:aaaa"));
+
+        let sources = &results[2];
+        assert_eq!(sources.size, 1024);
+        assert_eq!(sources.seeders, None);
+        assert_eq!(sources.magnet, None);
+    }
+
+    #[test]
+    fn rejects_invalid_response() {
+        assert!(parse_results("<error code=\"100\" description=\"Invalid API Key\" />").is_err());
+    }
+}
@@ -0,0 +1,478 @@
+use super::backend::{
+    BackendError, TorrentAdded, TorrentBackend, TorrentFile, TorrentState, TorrentStatus,
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use base64::prelude::*;
+use reqwest::{Client, StatusCode};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+const TORRENT_GET_FIELDS: &[&str] = &[
+    "hashString",
+    "name",
+    "percentDone",
+    "status",
+    "totalSize",
+    "error",
+];
+
+pub struct TransmissionClient<'a> {
+    transmission_address: String,
+    credentials: Option<(String, String)>,
+    session_id: RwLock<Option<String>>,
+    http_client: &'a Client,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum RequestArguments {
+    Add {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        metainfo: Option<String>,
+    },
+    Get {
+        fields: &'static [&'static str],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ids: Option<Vec<String>>,
+    },
+    Action {
+        ids: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct Request {
+    method: String,
+    arguments: RequestArguments,
+}
+
+/// Envelope of every Transmission RPC response. `result` is "success" or
+/// a human readable error, `arguments` is method specific and can be empty
+/// for failed calls, so it is parsed only after `result` was checked.
+#[derive(Deserialize, Debug)]
+struct Response {
+    result: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+    #[serde(default)]
+    #[expect(unused)]
+    tag: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TorrentAddArguments {
+    #[serde(rename = "torrent-added")]
+    torrent_added: Option<AddedTorrent>,
+    #[serde(rename = "torrent-duplicate")]
+    torrent_duplicate: Option<AddedTorrent>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AddedTorrent {
+    #[expect(unused)]
+    id: i64,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TorrentGetArguments<T> {
+    torrents: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Torrent {
+    hash_string: String,
+    name: String,
+    percent_done: f64,
+    status: i32,
+    total_size: u64,
+    error: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TorrentFiles {
+    download_dir: String,
+    files: Vec<File>,
+}
+
+#[derive(Deserialize, Debug)]
+struct File {
+    /// Relative to the download dir
+    name: String,
+    length: u64,
+}
+
+impl From<Torrent> for TorrentStatus {
+    fn from(t: Torrent) -> Self {
+        // https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md#33-torrent-accessor-torrent-get
+        let state = match t.status {
+            _ if t.error != 0 => TorrentState::Error,
+            0 => TorrentState::Stopped,
+            1 | 2 => TorrentState::Checking,
+            3 | 5 => TorrentState::Queued,
+            4 => TorrentState::Downloading,
+            _ => TorrentState::Seeding,
+        };
+        TorrentStatus {
+            id: t.hash_string,
+            name: t.name,
+            progress: t.percent_done,
+            state,
+            size: t.total_size,
+        }
+    }
+}
+
+/// Basic auth is used only with a non-empty username
+fn credentials(username: Option<String>, password: Option<String>) -> Option<(String, String)> {
+    match username {
+        Some(username) if !username.is_empty() => Some((
+            username,
+            password.expect(
+                "Provide TRANSMISSION_PASSWORD environment variable please, \
+                 it is required when TRANSMISSION_USERNAME is set",
+            ),
+        )),
+        _ => None,
+    }
+}
+
+impl<'a> TransmissionClient<'a> {
+    pub fn new(http_client: &'a Client) -> Self {
+        let transmission_address = {
+            env::var("TRANSMISSION_ADDRESS")
+                .expect("Provide TRANSMISSION_ADDRESS environment variable please")
+        };
+        let credentials = credentials(
+            env::var("TRANSMISSION_USERNAME").ok(),
+            env::var("TRANSMISSION_PASSWORD").ok(),
+        );
+        Self {
+            transmission_address,
+            credentials,
+            session_id: RwLock::new(None),
+            http_client,
+        }
+    }
+
+    async fn send(&self, body: &str) -> Result<reqwest::Response> {
+        let mut request = self
+            .http_client
+            .post(&self.transmission_address)
+            .body(body.to_string());
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        if let Some(session_id) = self.session_id.read().await.as_ref() {
+            request = request.header(SESSION_ID_HEADER, session_id);
+        }
+        request
+            .send()
+            .await
+            .with_context(|| "Failed to send http post request to transmission api")
+    }
+
+    /// Sends the request with the cached session id and refreshes it
+    /// only when Transmission answers with 409 Conflict.
+    async fn call<T: DeserializeOwned>(&self, request: Request) -> Result<T> {
+        let body = serde_json::to_string(&request)
+            .with_context(|| format!("Failed to serialize request {}", request.method))?;
+
+        let mut response = self
+            .send(&body)
+            .await
+            .with_context(|| format!("Transmission request {} failed", request.method))?;
+
+        if response.status() == StatusCode::CONFLICT {
+            let session_id = response
+                .headers()
+                .get(SESSION_ID_HEADER)
+                .ok_or(anyhow!(
+                    "Transmission responded with 409 without {} header",
+                    SESSION_ID_HEADER
+                ))?
+                .to_str()?
+                .to_string();
+            *self.session_id.write().await = Some(session_id);
+            response = self.send(&body).await.with_context(|| {
+                format!(
+                    "Transmission request {} failed with refreshed session id",
+                    request.method
+                )
+            })?;
+        }
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(BackendError::Unauthorized.into());
+        }
+
+        let response: Response = response
+            .error_for_status()
+            .with_context(|| format!("Transmission rejected request {}", request.method))?
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse result for request {}", request.method))?;
+
+        if response.result != "success" {
+            return Err(BackendError::Failed {
+                method: request.method,
+                result: response.result,
+            }
+            .into());
+        }
+
+        serde_json::from_value(response.arguments).with_context(|| {
+            format!(
+                "Failed to parse arguments of the response for request {}",
+                request.method
+            )
+        })
+    }
+
+    async fn torrent_add(&self, arguments: RequestArguments) -> Result<TorrentAdded> {
+        let request = Request {
+            method: "torrent-add".to_string(),
+            arguments,
+        };
+
+        let arguments: TorrentAddArguments = self.call(request).await?;
+        match arguments {
+            TorrentAddArguments {
+                torrent_added: Some(t),
+                ..
+            } => Ok(TorrentAdded::Added { name: t.name }),
+            TorrentAddArguments {
+                torrent_duplicate: Some(t),
+                ..
+            } => Ok(TorrentAdded::Duplicate { name: t.name }),
+            _ => Err(anyhow!(
+                "Transmission response for torrent-add contains neither added nor duplicate torrent"
+            )),
+        }
+    }
+
+    async fn torrent_action(&self, method: &str, id: &str) -> Result<()> {
+        let request = Request {
+            method: method.to_string(),
+            arguments: RequestArguments::Action {
+                ids: vec![id.to_string()],
+            },
+        };
+        self.call::<IgnoredAny>(request).await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl<'a> TorrentBackend for TransmissionClient<'a> {
+    async fn add_torrent(&self, _file_name: &str, content: &[u8]) -> Result<TorrentAdded> {
+        self.torrent_add(RequestArguments::Add {
+            filename: None,
+            metainfo: Some(BASE64_STANDARD.encode(content)),
+        })
+        .await
+    }
+
+    async fn add_magnet(&self, link: &str) -> Result<TorrentAdded> {
+        self.torrent_add(RequestArguments::Add {
+            filename: Some(link.to_string()),
+            metainfo: None,
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<TorrentStatus>> {
+        let request = Request {
+            method: "torrent-get".to_string(),
+            arguments: RequestArguments::Get {
+                fields: TORRENT_GET_FIELDS,
+                ids: None,
+            },
+        };
+        let arguments: TorrentGetArguments<Torrent> = self.call(request).await?;
+        Ok(arguments.torrents.into_iter().map(|t| t.into()).collect())
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        self.torrent_action("torrent-start", id).await
+    }
+
+    async fn stop(&self, id: &str) -> Result<()> {
+        self.torrent_action("torrent-stop", id).await
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        self.torrent_action("torrent-remove", id).await
+    }
+
+    async fn files(&self, id: &str) -> Result<Vec<TorrentFile>> {
+        let request = Request {
+            method: "torrent-get".to_string(),
+            arguments: RequestArguments::Get {
+                fields: &["downloadDir", "files"],
+                ids: Some(vec![id.to_string()]),
+            },
+        };
+        let arguments: TorrentGetArguments<TorrentFiles> = self.call(request).await?;
+        let torrent = arguments
+            .torrents
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Transmission has no torrent with id {}", id))?;
+        Ok(torrent
+            .files
+            .into_iter()
+            .map(|f| TorrentFile {
+                path: PathBuf::from(&torrent.download_dir).join(f.name),
+                size: f.length,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer, header, http_client};
+
+    const SUCCESS: &str = r#"{"result":"success","arguments":{"torrents":[]}}"#;
+
+    fn client<'a>(
+        http_client: &'a Client,
+        mock: &MockServer,
+        credentials: Option<(String, String)>,
+    ) -> TransmissionClient<'a> {
+        TransmissionClient {
+            transmission_address: mock.url("/transmission/rpc"),
+            credentials,
+            session_id: RwLock::new(None),
+            http_client,
+        }
+    }
+
+    #[test]
+    fn maps_torrent_states() {
+        let torrent = |status: i32, error: i32| Torrent {
+            hash_string: "abc".to_string(),
+            name: "Ubuntu".to_string(),
+            percent_done: 0.5,
+            status,
+            total_size: 1024,
+            error,
+        };
+        let cases = [
+            (0, 0, TorrentState::Stopped),
+            (1, 0, TorrentState::Checking),
+            (2, 0, TorrentState::Checking),
+            (3, 0, TorrentState::Queued),
+            (4, 0, TorrentState::Downloading),
+            (5, 0, TorrentState::Queued),
+            (6, 0, TorrentState::Seeding),
+            // Errors win over the status
+            (4, 3, TorrentState::Error),
+        ];
+        for (status, error, expected) in cases {
+            assert_eq!(TorrentStatus::from(torrent(status, error)).state, expected);
+        }
+
+        let status = TorrentStatus::from(torrent(4, 0));
+        assert_eq!(status.id, "abc");
+        assert_eq!(status.size, 1024);
+        assert!(!status.is_complete());
+    }
+
+    #[tokio::test]
+    async fn retries_with_session_id_from_conflict() {
+        let mock = MockServer::start(vec![
+            MockResponse::new(409, "").header(SESSION_ID_HEADER, "session-1"),
+            MockResponse::new(200, SUCCESS),
+            MockResponse::new(200, SUCCESS),
+        ])
+        .await;
+        let http_client = http_client();
+        let transmission = client(&http_client, &mock, None);
+
+        assert!(transmission.list().await.unwrap().is_empty());
+        // Session id is cached for the next requests
+        assert!(transmission.list().await.unwrap().is_empty());
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(header(&requests[0], SESSION_ID_HEADER), None);
+        assert_eq!(header(&requests[1], SESSION_ID_HEADER), Some("session-1"));
+        assert_eq!(header(&requests[2], SESSION_ID_HEADER), Some("session-1"));
+    }
+
+    #[tokio::test]
+    async fn reports_unauthorized() {
+        let mock = MockServer::start(vec![MockResponse::new(401, "")]).await;
+        let http_client = http_client();
+        let transmission = client(&http_client, &mock, None);
+
+        let error = transmission.list().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<BackendError>(),
+            Some(BackendError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_failed_result() {
+        let mock = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"result":"duplicate torrent","arguments":{}}"#,
+        )])
+        .await;
+        let http_client = http_client();
+        let transmission = client(&http_client, &mock, None);
+
+        let error = transmission.stop("abc").await.unwrap_err();
+        match error.downcast_ref::<BackendError>() {
+            Some(BackendError::Failed { method, result }) => {
+                assert_eq!(method, "torrent-stop");
+                assert_eq!(result, "duplicate torrent");
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_basic_auth() {
+        let mock = MockServer::start(vec![MockResponse::new(200, SUCCESS)]).await;
+        let http_client = http_client();
+        let transmission = client(
+            &http_client,
+            &mock,
+            credentials(Some("user".to_string()), Some("secret".to_string())),
+        );
+
+        transmission.list().await.unwrap();
+        let requests = mock.requests();
+        assert_eq!(
+            header(&requests[0], "authorization"),
+            Some(format!("Basic {}", BASE64_STANDARD.encode("user:secret")).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_basic_auth_for_empty_username() {
+        let mock = MockServer::start(vec![MockResponse::new(200, SUCCESS)]).await;
+        let http_client = http_client();
+        let credentials = credentials(Some(String::new()), None);
+        assert_eq!(credentials, None);
+        let transmission = client(&http_client, &mock, credentials);
+
+        transmission.list().await.unwrap();
+        assert_eq!(header(&mock.requests()[0], "authorization"), None);
+    }
+}
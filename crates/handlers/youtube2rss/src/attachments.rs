@@ -0,0 +1,246 @@
+use super::audio::{AudioSettings, Tags};
+use super::feeds::Feed;
+use super::metadata::VideoMetadata;
+use super::yt_dlp::VideoInfo;
+use super::{LinkKind, PodcastHandler};
+use anyhow::{Context, Result, anyhow};
+use chrono::DateTime;
+use chrono::offset::Utc;
+use log::warn;
+use std::fs;
+use std::time::SystemTime;
+use telegram_api::Message;
+
+/// Bot API doesn't let bots download bigger files
+const MAX_DOWNLOAD_SIZE: i64 = 20 * 1024 * 1024;
+
+/// Audio, voice note or video sent to the bot
+pub struct Attachment {
+    pub file_id: String,
+    pub unique_id: String,
+    pub ext: String,
+    pub file_size: Option<i64>,
+    pub duration: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+}
+
+impl Attachment {
+    pub fn from_message(m: &Message) -> Option<Self> {
+        if let Some(audio) = &m.audio {
+            let title = audio.title.clone().or(audio.file_name.clone());
+            Some(Self {
+                file_id: audio.file_id.to_string(),
+                unique_id: audio.file_unique_id.to_string(),
+                ext: extension(audio.file_name.as_deref(), audio.mime_type.as_deref()),
+                file_size: audio.file_size,
+                duration: audio.duration,
+                title,
+                performer: audio.performer.clone(),
+            })
+        } else if let Some(voice) = &m.voice {
+            Some(Self {
+                file_id: voice.file_id.to_string(),
+                unique_id: voice.file_unique_id.to_string(),
+                ext: extension(None, voice.mime_type.as_deref().or(Some("audio/ogg"))),
+                file_size: voice.file_size,
+                duration: voice.duration,
+                title: None,
+                performer: None,
+            })
+        } else {
+            m.video.as_ref().map(|video| Self {
+                file_id: video.file_id.to_string(),
+                unique_id: video.file_unique_id.to_string(),
+                ext: extension(video.file_name.as_deref(), video.mime_type.as_deref()),
+                file_size: video.file_size,
+                duration: video.duration,
+                title: video.file_name.clone(),
+                performer: None,
+            })
+        }
+    }
+
+    /// Podcast apps play only mp3 and m4a reliably, voice notes are ogg and
+    /// videos are converted to audio
+    pub fn needs_conversion(&self) -> bool {
+        !matches!(self.ext.as_str(), "mp3" | "m4a")
+    }
+}
+
+/// Caption can start with #name of the target feed, the rest is the title of the episode
+pub fn parse_caption(m: &Message) -> (Option<&str>, Option<&str>) {
+    let caption = m.caption.as_deref().unwrap_or_default().trim();
+    let (feed, title) = match caption.strip_prefix('#') {
+        Some(rest) => {
+            let (feed, title) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            (Some(feed), title.trim())
+        }
+        None => (None, caption),
+    };
+    (feed, Some(title).filter(|t| !t.is_empty()))
+}
+
+fn extension(file_name: Option<&str>, mime_type: Option<&str>) -> String {
+    if let Some((_, ext)) = file_name.and_then(|n| n.rsplit_once('.'))
+        && !ext.is_empty()
+    {
+        return ext.to_lowercase();
+    }
+    let mime_type = mime_type.unwrap_or_default();
+    match mime_type {
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => "m4a",
+        "video/quicktime" => "mov",
+        _ => {
+            return match mime_type.split_once('/') {
+                Some((_, subtype)) => subtype.trim_start_matches("x-").to_string(),
+                None => "mp4".to_string(),
+            };
+        }
+    }
+    .to_string()
+}
+
+impl PodcastHandler<'_> {
+    /// Downloads audio, voice note or video of the message from Telegram,
+    /// converts it to m4a if needed and adds it to the feed
+    pub(crate) async fn process_attachment(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        force: bool,
+    ) -> Result<(String, Vec<VideoMetadata>)> {
+        let attachment = Attachment::from_message(m)
+            .ok_or(anyhow!("Message {} has no attachment", m.message_id))?;
+        let file = self
+            .telegram_client
+            .async_get_file(&attachment.file_id)
+            .await?
+            .result;
+        let content = self
+            .telegram_client
+            .async_donwload_file(&file.file_path)
+            .await?;
+        let path = self.tmp_dir.join(format!(
+            "{}{}.{}",
+            m.message_id, attachment.unique_id, attachment.ext
+        ));
+        fs::write(&path, &content)
+            .with_context(|| format!("Failed to write attachment to {}", path.display()))?;
+
+        let (_, caption) = parse_caption(m);
+        let title = caption
+            .map(|c| c.to_string())
+            .or(attachment.title.clone())
+            .unwrap_or_else(|| {
+                let now: DateTime<Utc> = SystemTime::now().into();
+                format!("Запись от {}", now.format("%d.%m.%Y %H:%M"))
+            });
+        let converted = match &self.audio_processor {
+            Some(processor) if attachment.needs_conversion() => {
+                let settings = AudioSettings {
+                    codec: Some("aac".to_string()),
+                    ..Default::default()
+                };
+                let converted = processor
+                    .process(
+                        &path,
+                        &attachment.ext,
+                        &settings,
+                        &[],
+                        &[],
+                        &Tags {
+                            title: &title,
+                            artist: None,
+                            album: "",
+                            artwork_url: None,
+                        },
+                    )
+                    .await;
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to remove temporary file {}: {}", path.display(), e);
+                }
+                Some(converted?)
+            }
+            _ => None,
+        };
+        let (filepath, ext) = match converted {
+            Some(c) => (c.path, c.ext),
+            None => (path, attachment.ext.to_string()),
+        };
+
+        let info = VideoInfo {
+            id: attachment.unique_id.to_string(),
+            title,
+            extractor: Some("telegram".to_string()),
+            description: None,
+            duration: Some(attachment.duration as f64),
+            thumbnail: None,
+            uploader: attachment.performer.clone(),
+            upload_date: None,
+            chapters: None,
+            requested_subtitles: None,
+            filepath,
+            ext,
+        };
+        self.add_video("", feed, &info, force).await
+    }
+
+    pub(crate) async fn add_attachment(&self, m: &Message) -> Result<()> {
+        let Some(attachment) = Attachment::from_message(m) else {
+            return Ok(());
+        };
+        if attachment.file_size.is_some_and(|s| s > MAX_DOWNLOAD_SIZE) {
+            return self
+                .reply(
+                    m,
+                    "Файл больше 20 МБ, Telegram не дает ботам скачивать такие файлы".to_string(),
+                )
+                .await;
+        }
+        let (feed_name, _) = parse_caption(m);
+        self.add_link(m, "", LinkKind::Attachment, feed_name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(caption: Option<&str>) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "chat": {"id": 1},
+            "caption": caption,
+            "voice": {"file_id": "file", "file_unique_id": "unique", "duration": 10},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_feed_and_title_of_caption() {
+        let m = message(Some("#talks Episode title"));
+        assert_eq!(parse_caption(&m), (Some("talks"), Some("Episode title")));
+        let m = message(Some("  Episode title "));
+        assert_eq!(parse_caption(&m), (None, Some("Episode title")));
+        let m = message(Some("#talks"));
+        assert_eq!(parse_caption(&m), (Some("talks"), None));
+        assert_eq!(parse_caption(&message(None)), (None, None));
+    }
+
+    #[test]
+    fn converts_voice_notes() {
+        let attachment = Attachment::from_message(&message(None)).unwrap();
+        assert_eq!(attachment.ext, "ogg");
+        assert!(attachment.needs_conversion());
+    }
+
+    #[test]
+    fn takes_extension_from_file_name_or_mime_type() {
+        assert_eq!(extension(Some("Track.MP3"), Some("audio/ogg")), "mp3");
+        assert_eq!(extension(Some("track"), Some("audio/x-m4a")), "m4a");
+        assert_eq!(extension(None, Some("audio/x-flac")), "flac");
+        assert_eq!(extension(None, None), "mp4");
+    }
+}
@@ -0,0 +1,562 @@
+use super::PodcastHandler;
+use super::chapters::{self, Chapter};
+use super::feeds::Feed;
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use telegram_api::Message;
+use tokio::fs;
+use tokio::process::Command;
+
+/// atempo filter accepts factors from 0.5 to 2, higher speeds are chained
+const MAX_ATEMPO: f32 = 2.0;
+const MIN_SPEED: f32 = 0.5;
+const MAX_SPEED: f32 = 4.0;
+const CODECS: [&str; 3] = ["mp3", "opus", "aac"];
+const MAX_BITRATE_KBPS: u32 = 320;
+const ARTWORK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Per-feed options of the ffmpeg processing of downloaded audio
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AudioSettings {
+    /// mp3, opus or aac, the codec of the source is kept if empty
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub bitrate_kbps: Option<u32>,
+    #[serde(default)]
+    pub mono: bool,
+    /// EBU R128 loudness normalization to the -16 LUFS of podcasts
+    #[serde(default)]
+    pub normalize: bool,
+    /// Removes silence at the start and long pauses. Chapters and subtitles
+    /// are dropped then, their timestamps don't match the trimmed audio.
+    #[serde(default)]
+    pub trim_silence: bool,
+    #[serde(default)]
+    pub speed: Option<f32>,
+    /// Title, author and artwork tags for players, which ignore the feed
+    #[serde(default)]
+    pub tags: bool,
+}
+
+impl AudioSettings {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Imported settings get the same checks as /audio
+    pub fn validate(&self) -> Result<()> {
+        if let Some(codec) = &self.codec
+            && !CODECS.contains(&codec.as_str())
+        {
+            return Err(anyhow!("Unknown codec {}", codec));
+        }
+        if let Some(bitrate) = self.bitrate_kbps
+            && !(1..=MAX_BITRATE_KBPS).contains(&bitrate)
+        {
+            return Err(anyhow!("Bitrate {} kbps is out of range", bitrate));
+        }
+        if let Some(speed) = self.speed
+            && !(MIN_SPEED..=MAX_SPEED).contains(&speed)
+        {
+            return Err(anyhow!("Speed {} is out of range", speed));
+        }
+        Ok(())
+    }
+
+    /// Cuts are applied first, their timestamps refer to the source audio
+    fn filters(&self, cuts: &[(f64, f64)]) -> Vec<String> {
+        let mut filters = vec![];
+        if !cuts.is_empty() {
+            let removed = cuts
+                .iter()
+                .map(|(start, end)| format!("between(t,{:.3},{:.3})", start, end))
+                .collect::<Vec<String>>()
+                .join("+");
+            filters.push(format!("aselect='not({})'", removed));
+            filters.push("asetpts=N/SR/TB".to_string());
+        }
+        if self.trim_silence {
+            filters.push(
+                "silenceremove=start_periods=1:start_threshold=-50dB:\
+                 stop_periods=-1:stop_duration=1:stop_threshold=-50dB"
+                    .to_string(),
+            );
+        }
+        if let Some(mut speed) = self.speed.filter(|s| *s != 1.0) {
+            while speed > MAX_ATEMPO {
+                filters.push(format!("atempo={}", MAX_ATEMPO));
+                speed /= MAX_ATEMPO;
+            }
+            filters.push(format!("atempo={}", speed));
+        }
+        // Normalization goes last, so it measures the resulting audio
+        if self.normalize {
+            filters.push("loudnorm=I=-16:TP=-1.5:LRA=11".to_string());
+        }
+        filters
+    }
+
+    fn needs_encoding(&self, cuts: &[(f64, f64)]) -> bool {
+        self.codec.is_some()
+            || self.bitrate_kbps.is_some()
+            || self.mono
+            || !self.filters(cuts).is_empty()
+    }
+}
+
+/// Tags embedded into the processed file
+pub struct Tags<'a> {
+    pub title: &'a str,
+    pub artist: Option<&'a str>,
+    pub album: &'a str,
+    pub artwork_url: Option<&'a str>,
+}
+
+pub struct ProcessedAudio {
+    pub path: PathBuf,
+    pub ext: String,
+}
+
+/// Runs ffmpeg over downloaded audio, processing is disabled without FFMPEG_PATH
+pub struct AudioProcessor<'a> {
+    ffmpeg: String,
+    http_client: &'a Client,
+}
+
+impl<'a> AudioProcessor<'a> {
+    pub fn new(http_client: &'a Client) -> Option<Self> {
+        let ffmpeg = env::var("FFMPEG_PATH").ok().filter(|p| !p.is_empty())?;
+        Some(Self {
+            ffmpeg,
+            http_client,
+        })
+    }
+
+    /// Artwork is fetched by the client with the proxy and the timeout,
+    /// ffmpeg would hang or fail the whole processing on a bad url
+    async fn download_artwork(&self, url: &str, output: &Path) -> Result<()> {
+        let artwork = self
+            .http_client
+            .get(url)
+            .timeout(ARTWORK_TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("Failed to request artwork {}", url))?
+            .error_for_status()
+            .with_context(|| format!("Failed to download artwork {}", url))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read artwork {}", url))?;
+        fs::write(output, artwork)
+            .await
+            .with_context(|| format!("Failed to write artwork to {}", output.display()))
+    }
+
+    /// Writes the result next to the source file, the source is left as is.
+    /// Cuts are segments in seconds removed from the audio, chapters are
+    /// embedded as is, so they must already be in the timeline of the result.
+    pub async fn process(
+        &self,
+        input: &Path,
+        input_ext: &str,
+        settings: &AudioSettings,
+        cuts: &[(f64, f64)],
+        chapters: &[Chapter],
+        tags: &Tags<'_>,
+    ) -> Result<ProcessedAudio> {
+        let (encoder, ext) = match settings.codec.as_deref() {
+            Some("mp3") => ("libmp3lame", "mp3"),
+            Some("opus") => ("libopus", "opus"),
+            Some("aac") => ("aac", "m4a"),
+            Some(codec) => return Err(anyhow!("Unsupported audio codec {}", codec)),
+            None if !settings.needs_encoding(cuts) => ("copy", input_ext),
+            None if input_ext == "mp3" => ("libmp3lame", "mp3"),
+            None => ("aac", "m4a"),
+        };
+        let output = input.with_extension(format!("processed.{}", ext));
+        let chapters_path = input.with_extension("chapters.txt");
+        if !chapters.is_empty() {
+            fs::write(&chapters_path, chapters::to_ffmetadata(chapters))
+                .await
+                .with_context(|| {
+                    format!("Failed to write chapters to {}", chapters_path.display())
+                })?;
+        }
+
+        // Ogg container of ffmpeg can't hold the cover picture
+        let artwork_path = match tags.artwork_url.filter(|_| settings.tags && ext != "opus") {
+            Some(url) => {
+                let path = input.with_extension("artwork");
+                match self.download_artwork(url, &path).await {
+                    Ok(()) => Some(path),
+                    Err(e) => {
+                        warn!("Processing audio without artwork: {:?}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(["-y", "-loglevel", "error", "-i"]).arg(input);
+        if let Some(path) = &artwork_path {
+            command.arg("-i").arg(path);
+        }
+        if chapters.is_empty() {
+            // Chapters of the source don't match the processed audio
+            command.args(["-map_chapters", "-1"]);
+        } else {
+            let index = if artwork_path.is_some() { "2" } else { "1" };
+            command.args(["-f", "ffmetadata", "-i"]).arg(&chapters_path);
+            command.args(["-map_chapters", index]);
+        }
+        if artwork_path.is_some() {
+            command.args(["-map", "0:a", "-map", "1:v"]);
+            command.args(["-c:v", "mjpeg", "-disposition:v", "attached_pic"]);
+        } else {
+            command.args(["-map", "0:a"]);
+        }
+        command.args(["-c:a", encoder]);
+        if let Some(bitrate) = settings.bitrate_kbps {
+            command.args(["-b:a", &format!("{}k", bitrate)]);
+        }
+        if settings.mono {
+            command.args(["-ac", "1"]);
+        }
+        let filters = settings.filters(cuts);
+        if !filters.is_empty() {
+            command.args(["-af", &filters.join(",")]);
+        }
+        if settings.tags {
+            command.args(["-metadata", &format!("title={}", tags.title)]);
+            command.args(["-metadata", &format!("album={}", tags.album)]);
+            if let Some(artist) = tags.artist {
+                command.args(["-metadata", &format!("artist={}", artist)]);
+            }
+            if ext == "mp3" {
+                command.args(["-id3v2_version", "3"]);
+            }
+        }
+        let res = command.arg(&output).output().await;
+        let temporary = artwork_path
+            .into_iter()
+            .chain(Some(chapters_path).filter(|_| !chapters.is_empty()));
+        for path in temporary {
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("Failed to remove temporary file {}: {}", path.display(), e);
+            }
+        }
+        let res =
+            res.with_context(|| format!("Failed to execute ffmpeg for {}", input.display()))?;
+        if res.status.success() {
+            Ok(ProcessedAudio {
+                path: output,
+                ext: ext.to_string(),
+            })
+        } else {
+            Err(anyhow!(
+                "Exit code of ffmpeg command was not 0, output: {:?}",
+                res
+            ))
+        }
+    }
+
+    /// Copies the part of the audio between start and end seconds to a new file
+    /// next to the source. Chapters and tags of the source are kept.
+    pub async fn cut(&self, input: &Path, part: usize, start: f64, end: f64) -> Result<PathBuf> {
+        let ext = input
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let output = input.with_extension(format!("part{}.{}", part, ext));
+        let res = Command::new(&self.ffmpeg)
+            .args(["-y", "-loglevel", "error"])
+            .args([
+                "-ss",
+                &format!("{:.3}", start),
+                "-to",
+                &format!("{:.3}", end),
+            ])
+            .arg("-i")
+            .arg(input)
+            .args(["-map", "0", "-c", "copy"])
+            .arg(&output)
+            .output()
+            .await
+            .with_context(|| format!("Failed to execute ffmpeg for {}", input.display()))?;
+        if res.status.success() {
+            Ok(output)
+        } else {
+            Err(anyhow!(
+                "Exit code of ffmpeg command was not 0, output: {:?}",
+                res
+            ))
+        }
+    }
+
+    /// Duration of the file from its header, processing like silence trimming
+    /// changes the length, so it can't be derived from the source duration
+    pub async fn duration(&self, input: &Path) -> Result<f64> {
+        // Without an output ffmpeg only prints the info of the input and fails
+        let res = Command::new(&self.ffmpeg)
+            .args(["-hide_banner", "-i"])
+            .arg(input)
+            .output()
+            .await
+            .with_context(|| format!("Failed to execute ffmpeg for {}", input.display()))?;
+        String::from_utf8_lossy(&res.stderr)
+            .lines()
+            .find_map(|l| l.trim().strip_prefix("Duration: "))
+            .and_then(|d| parse_duration(d.split(',').next().unwrap_or_default()))
+            .ok_or(anyhow!(
+                "ffmpeg didn't report duration of {}",
+                input.display()
+            ))
+    }
+
+    /// Converts the audio to 16 kHz mono wav expected by speech recognition
+    pub async fn to_wav(&self, input: &Path) -> Result<PathBuf> {
+        let output = input.with_extension("16k.wav");
+        let res = Command::new(&self.ffmpeg)
+            .args(["-y", "-loglevel", "error", "-i"])
+            .arg(input)
+            .args(["-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"])
+            .arg(&output)
+            .output()
+            .await
+            .with_context(|| format!("Failed to execute ffmpeg for {}", input.display()))?;
+        if res.status.success() {
+            Ok(output)
+        } else {
+            Err(anyhow!(
+                "Exit code of ffmpeg command was not 0, output: {:?}",
+                res
+            ))
+        }
+    }
+}
+
+/// hh:mm:ss.xx of the ffmpeg output
+fn parse_duration(duration: &str) -> Option<f64> {
+    let mut secs = 0.0;
+    for part in duration.trim().split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(secs)
+}
+
+pub fn mime_type(ext: &str) -> String {
+    match ext {
+        "mp3" => "audio/mpeg",
+        "opus" | "ogg" | "oga" => "audio/ogg",
+        "webm" | "weba" => "audio/webm",
+        "mp4" => "video/mp4",
+        "m4a" => "audio/m4a",
+        _ => return format!("audio/{}", ext),
+    }
+    .to_string()
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn set_audio(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        option: Option<&str>,
+        value: Option<&str>,
+    ) -> Result<()> {
+        let Some(option) = option else {
+            let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+            return self.reply(m, self.format_audio(&settings.audio)).await;
+        };
+        let switch = match value {
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            _ => None,
+        };
+        let change: Box<dyn Fn(&mut AudioSettings) + Send + Sync> = match (option, value, switch) {
+            ("off", _, _) => Box::new(|audio| *audio = AudioSettings::default()),
+            ("codec", Some("original"), _) => Box::new(|audio| audio.codec = None),
+            ("codec", Some(codec), _) if CODECS.contains(&codec) => {
+                Box::new(move |audio| audio.codec = Some(codec.to_string()))
+            }
+            ("bitrate", Some(v), _) => match v.parse::<u32>() {
+                Ok(0) => Box::new(|audio| audio.bitrate_kbps = None),
+                Ok(bitrate) if bitrate <= MAX_BITRATE_KBPS => {
+                    Box::new(move |audio| audio.bitrate_kbps = Some(bitrate))
+                }
+                _ => return self.reply_audio_usage(m).await,
+            },
+            ("speed", Some(v), _) => match v.parse::<f32>() {
+                Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => {
+                    Box::new(move |audio| audio.speed = Some(speed).filter(|s| *s != 1.0))
+                }
+                _ => return self.reply_audio_usage(m).await,
+            },
+            ("mono", _, Some(on)) => Box::new(move |audio| audio.mono = on),
+            ("normalize", _, Some(on)) => Box::new(move |audio| audio.normalize = on),
+            ("trim", _, Some(on)) => Box::new(move |audio| audio.trim_silence = on),
+            ("tags", _, Some(on)) => Box::new(move |audio| audio.tags = on),
+            _ => return self.reply_audio_usage(m).await,
+        };
+        let (settings, _) = self
+            .metadata
+            .update_settings(&feed.settings_path(), |settings| {
+                change(&mut settings.audio)
+            })
+            .await?;
+        self.reply(m, self.format_audio(&settings.audio)).await
+    }
+
+    async fn reply_audio_usage(&self, m: &Message) -> Result<()> {
+        self.reply(
+            m,
+            "Используйте /audio codec mp3|opus|aac|original, /audio bitrate <kbps>, \
+             /audio speed <0.5-4>, /audio mono|normalize|trim|tags on|off или /audio off"
+                .to_string(),
+        )
+        .await
+    }
+
+    fn format_audio(&self, audio: &AudioSettings) -> String {
+        if !audio.is_enabled() {
+            return "Аудио загружается без обработки".to_string();
+        }
+        let switch = |on: bool| if on { "вкл" } else { "выкл" };
+        let mut text = format!(
+            "Кодек: {}\nБитрейт: {}\nСкорость: {}\nМоно: {}\nНормализация громкости: {}\n\
+             Удаление тишины: {}\nТеги: {}",
+            audio.codec.as_deref().unwrap_or("исходный"),
+            audio
+                .bitrate_kbps
+                .map(|b| format!("{} kbps", b))
+                .unwrap_or("по умолчанию".to_string()),
+            audio.speed.unwrap_or(1.0),
+            switch(audio.mono),
+            switch(audio.normalize),
+            switch(audio.trim_silence),
+            switch(audio.tags),
+        );
+        if audio.trim_silence {
+            text.push_str("\nГлавы и субтитры не добавляются при удалении тишины");
+        }
+        if self.audio_processor.is_none() {
+            text.push_str("\nОбработка не выполняется: не настроен FFMPEG_PATH");
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOUDNORM: &str = "loudnorm=I=-16:TP=-1.5:LRA=11";
+
+    #[test]
+    fn skips_filters_by_default() {
+        let settings = AudioSettings::default();
+        assert!(settings.filters(&[]).is_empty());
+        assert!(!settings.needs_encoding(&[]));
+        // Tags are written without encoding
+        let tags = AudioSettings {
+            tags: true,
+            ..Default::default()
+        };
+        assert!(!tags.needs_encoding(&[]));
+    }
+
+    #[test]
+    fn combines_filters_in_order() {
+        let settings = AudioSettings {
+            trim_silence: true,
+            speed: Some(3.0),
+            normalize: true,
+            ..Default::default()
+        };
+        let filters = settings.filters(&[(1.0, 2.5), (10.0, 12.0)]);
+        assert_eq!(
+            filters,
+            [
+                "aselect='not(between(t,1.000,2.500)+between(t,10.000,12.000))'",
+                "asetpts=N/SR/TB",
+                "silenceremove=start_periods=1:start_threshold=-50dB:\
+                 stop_periods=-1:stop_duration=1:stop_threshold=-50dB",
+                "atempo=2",
+                "atempo=1.5",
+                LOUDNORM,
+            ]
+        );
+    }
+
+    #[test]
+    fn chains_atempo_over_its_limit() {
+        let speed = |speed: f32| {
+            AudioSettings {
+                speed: Some(speed),
+                ..Default::default()
+            }
+            .filters(&[])
+        };
+        assert!(speed(1.0).is_empty());
+        assert_eq!(speed(0.5), ["atempo=0.5"]);
+        assert_eq!(speed(2.0), ["atempo=2"]);
+        assert_eq!(speed(4.0), ["atempo=2", "atempo=2"]);
+    }
+
+    #[test]
+    fn encodes_when_audio_changes() {
+        let cuts = [(0.0, 5.0)];
+        assert!(AudioSettings::default().needs_encoding(&cuts));
+        for settings in [
+            AudioSettings {
+                codec: Some("mp3".to_string()),
+                ..Default::default()
+            },
+            AudioSettings {
+                bitrate_kbps: Some(64),
+                ..Default::default()
+            },
+            AudioSettings {
+                mono: true,
+                ..Default::default()
+            },
+            AudioSettings {
+                normalize: true,
+                ..Default::default()
+            },
+        ] {
+            assert!(settings.needs_encoding(&[]), "{:?}", settings);
+        }
+        let normalize = AudioSettings {
+            normalize: true,
+            ..Default::default()
+        };
+        assert_eq!(normalize.filters(&[]), [LOUDNORM]);
+    }
+
+    #[test]
+    fn parses_ffmpeg_durations() {
+        assert_eq!(parse_duration("01:02:03.50"), Some(3723.5));
+        assert_eq!(parse_duration(" 00:00:07.04"), Some(7.04));
+        assert_eq!(parse_duration("N/A"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn maps_extensions_to_mime_types() {
+        assert_eq!(mime_type("mp3"), "audio/mpeg");
+        assert_eq!(mime_type("opus"), "audio/ogg");
+        assert_eq!(mime_type("weba"), "audio/webm");
+        assert_eq!(mime_type("m4a"), "audio/m4a");
+        assert_eq!(mime_type("mp4"), "video/mp4");
+        assert_eq!(mime_type("flac"), "audio/flac");
+    }
+}
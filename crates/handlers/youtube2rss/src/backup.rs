@@ -0,0 +1,823 @@
+use super::feeds::{DEFAULT_FEED, Feed, feeds_index_path, is_valid_feed_name};
+use super::metadata::{FeedSettings, VideoMetadata};
+use super::subscriptions::{
+    SUBSCRIPTIONS_DISABLED, Subscription, Subscriptions, is_youtube_collection,
+    subscription_listing_url,
+};
+use super::{PodcastHandler, message_user};
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use telegram_api::{
+    AnswerCallbackQuery, CallbackQuery, FileKind, InlineKeyboardButton, InlineKeyboardMarkup,
+    Message, SendMessage,
+};
+
+/// Version of the JSON export format, files of newer versions are rejected
+pub const EXPORT_VERSION: u32 = 1;
+
+/// The summary of /import replies to the file, so the button finds it
+pub const IMPORT_CALLBACK: &str = "podcast_import";
+pub const IMPORT_USAGE: &str =
+    "Отправьте файл из /export с подписью /import или ответьте /import на сообщение с файлом";
+
+/// Feeds of the user with settings and episodes, sent by /export
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedsExport {
+    pub version: u32,
+    pub feeds: Vec<FeedExport>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedExport {
+    pub name: String,
+    #[serde(default)]
+    pub settings: FeedSettings,
+    #[serde(default)]
+    pub episodes: Vec<VideoMetadata>,
+}
+
+/// Playlist or channel from the OPML file and the feed it goes to
+#[derive(Debug)]
+pub struct OpmlSubscription {
+    pub feed: String,
+    pub title: String,
+    pub url: String,
+}
+
+/// Parsed file of /import
+enum ImportFile {
+    Json(FeedsExport),
+    Opml(Vec<OpmlSubscription>),
+}
+
+/// Storage paths are internal, only urls are exported
+pub fn export_episode(mut episode: VideoMetadata) -> VideoMetadata {
+    episode.file_path.clear();
+    episode.chapters_path = None;
+    episode.transcript_path = None;
+    episode
+}
+
+pub fn to_json(export: &FeedsExport) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(export)?)
+}
+
+/// Parses the export and checks it can be imported as is
+pub fn parse_json(data: &[u8]) -> Result<FeedsExport> {
+    let export: FeedsExport =
+        serde_json::from_slice(data).context("File doesn't match the export format")?;
+    if export.version > EXPORT_VERSION {
+        return Err(anyhow!(
+            "Export version {} is not supported, the latest is {}",
+            export.version,
+            EXPORT_VERSION
+        ));
+    }
+    let mut names = HashSet::new();
+    for feed in &export.feeds {
+        if feed.name != DEFAULT_FEED && !is_valid_feed_name(&feed.name) {
+            return Err(anyhow!("Invalid feed name {}", feed.name));
+        }
+        if !names.insert(feed.name.as_str()) {
+            return Err(anyhow!("Feed {} is listed twice", feed.name));
+        }
+        feed.settings
+            .validate()
+            .with_context(|| format!("Invalid settings of the feed {}", feed.name))?;
+        for (i, episode) in feed.episodes.iter().enumerate() {
+            if episode.name.is_empty() || !episode.file_url.starts_with("http") {
+                return Err(anyhow!(
+                    "Episode {} of the feed {} has no name or audio url",
+                    i + 1,
+                    feed.name
+                ));
+            }
+            // Storage paths are derived from the urls on import
+            if !episode.file_path.is_empty()
+                || episode.chapters_path.is_some()
+                || episode.transcript_path.is_some()
+            {
+                return Err(anyhow!(
+                    "Episode {} of the feed {} has storage paths",
+                    i + 1,
+                    feed.name
+                ));
+            }
+        }
+    }
+    Ok(export)
+}
+
+/// Feeds with their rss urls for podcast apps, subscriptions of a feed
+/// are nested into its outline
+pub fn to_opml(title: &str, feeds: &[(String, String)], subscriptions: &[Subscription]) -> String {
+    let mut opml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n\
+         <head><title>{}</title></head>\n<body>\n",
+        escape(title)
+    );
+    for (name, rss_url) in feeds {
+        let outline = format!(
+            "<outline text=\"{0}\" title=\"{0}\" type=\"rss\" xmlUrl=\"{1}\"",
+            escape(name.as_str()),
+            escape(rss_url.as_str())
+        );
+        let children: Vec<String> = subscriptions
+            .iter()
+            .filter(|s| s.feed == *name)
+            .map(|s| {
+                format!(
+                    "  <outline text=\"{0}\" title=\"{0}\" type=\"link\" url=\"{1}\"/>\n",
+                    escape(s.title.as_str()),
+                    escape(s.url.as_str())
+                )
+            })
+            .collect();
+        if children.is_empty() {
+            opml.push_str(&format!("{}/>\n", outline));
+        } else {
+            opml.push_str(&format!("{}>\n{}</outline>\n", outline, children.concat()));
+        }
+    }
+    opml.push_str("</body>\n</opml>\n");
+    opml
+}
+
+/// Subscriptions are leaf outlines with a url. They go to the feed named by
+/// the enclosing outline or to the default feed, so OPML of YouTube
+/// subscriptions exported by other apps can be imported too.
+pub fn parse_opml(data: &[u8]) -> Result<Vec<OpmlSubscription>> {
+    let text = std::str::from_utf8(data).context("OPML file is not UTF-8")?;
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+    let mut groups: Vec<Option<String>> = vec![];
+    let mut subscriptions = vec![];
+    let mut is_opml = false;
+    loop {
+        match reader.read_event().context("Failed to parse OPML")? {
+            Event::Start(e) if e.name().as_ref() == b"opml" => is_opml = true,
+            Event::Start(e) if e.name().as_ref() == b"outline" => {
+                groups.push(attribute(&e, "text")?);
+            }
+            Event::End(e) if e.name().as_ref() == b"outline" => {
+                groups.pop();
+            }
+            Event::Empty(e) if e.name().as_ref() == b"outline" => {
+                let url = match attribute(&e, "url")? {
+                    Some(url) => url,
+                    None => match attribute(&e, "xmlUrl")?.and_then(|u| youtube_feed_channel(&u)) {
+                        Some(url) => url,
+                        None => continue,
+                    },
+                };
+                let feed = groups
+                    .iter()
+                    .rev()
+                    .flatten()
+                    .find(|g| is_valid_feed_name(g))
+                    .map(|g| g.to_string())
+                    .unwrap_or(DEFAULT_FEED.to_string());
+                let title = attribute(&e, "title")?
+                    .or(attribute(&e, "text")?)
+                    .unwrap_or(url.to_string());
+                subscriptions.push(OpmlSubscription { feed, title, url });
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !is_opml {
+        return Err(anyhow!("File is not OPML"));
+    }
+    Ok(subscriptions)
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>> {
+    match e.try_get_attribute(name)? {
+        Some(a) => Ok(Some(
+            a.normalized_value(XmlVersion::Implicit1_0)?.to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Channel page of the YouTube rss feed, apps export subscriptions as these feeds
+fn youtube_feed_channel(url: &str) -> Option<String> {
+    let (_, channel_id) = url.split_once("youtube.com/feeds/videos.xml?channel_id=")?;
+    let channel_id = channel_id.split('&').next()?;
+    (!channel_id.is_empty()).then(|| format!("https://www.youtube.com/channel/{}", channel_id))
+}
+
+impl PodcastHandler<'_> {
+    /// Sends feeds with settings and episodes as JSON and subscriptions as OPML
+    pub(crate) async fn export_feeds(&self, m: &Message) -> Result<()> {
+        let user = message_user(m)?;
+        let names: Vec<String> = std::iter::once(DEFAULT_FEED.to_string())
+            .chain(
+                self.metadata
+                    .load_feeds(&feeds_index_path(&self.roots.root(user)))
+                    .await?,
+            )
+            .collect();
+        let mut export = FeedsExport {
+            version: EXPORT_VERSION,
+            feeds: vec![],
+        };
+        let mut rss_urls = vec![];
+        for name in names {
+            let feed = self.roots.feed(user, &name);
+            export.feeds.push(FeedExport {
+                settings: self.metadata.load_settings(&feed.settings_path()).await?,
+                episodes: self
+                    .metadata
+                    .load_metadata(&feed.metadata_path())
+                    .await?
+                    .into_iter()
+                    .map(export_episode)
+                    .collect(),
+                name: name.to_string(),
+            });
+            rss_urls.push((name, self.feed_url(&feed).await?));
+        }
+        let subscriptions: Vec<Subscription> = match &self.subscriptions {
+            Some(subscriptions) => subscriptions
+                .load()
+                .await?
+                .into_iter()
+                .filter(|s| s.user_id == user.id)
+                .collect(),
+            None => vec![],
+        };
+
+        let chat_id = m.chat.id.to_string();
+        self.telegram_client
+            .async_send_bytes(
+                &chat_id,
+                "podcasts.json".to_string(),
+                to_json(&export)?,
+                FileKind::Document,
+            )
+            .await?;
+        let title = format!("Подкасты {}", user.first_name);
+        self.telegram_client
+            .async_send_bytes(
+                &chat_id,
+                "podcasts.opml".to_string(),
+                to_opml(&title, &rss_urls, &subscriptions).into_bytes(),
+                FileKind::Document,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Imports the file from /export into the feeds of the sender. Without apply
+    /// nothing is changed: the summary of the import is sent with the button
+    /// to apply it.
+    pub(crate) async fn import_file(
+        &self,
+        m: &Message,
+        file_message: &Message,
+        apply: bool,
+    ) -> Result<()> {
+        let Some(document) = &file_message.document else {
+            return self.reply(m, IMPORT_USAGE.to_string()).await;
+        };
+        let user = message_user(m)?;
+        if message_user(file_message)?.id != user.id {
+            return self
+                .reply(m, "Импортировать можно только свои файлы".to_string())
+                .await;
+        }
+        let file = self
+            .telegram_client
+            .async_get_file(&document.file_id)
+            .await?
+            .result;
+        let content = self
+            .telegram_client
+            .async_donwload_file(&file.file_path)
+            .await?;
+
+        let is_opml = document.file_name.ends_with(".opml")
+            || document.file_name.ends_with(".xml")
+            || content.trim_ascii_start().starts_with(b"<");
+        let parsed = if is_opml {
+            parse_opml(&content).map(ImportFile::Opml)
+        } else {
+            parse_json(&content).map(ImportFile::Json)
+        };
+        let summary = match parsed {
+            Ok(ImportFile::Json(export)) => self.import_feeds(file_message, export, apply).await?,
+            Ok(ImportFile::Opml(items)) => {
+                let Some(subscriptions) = &self.subscriptions else {
+                    return self.reply(m, SUBSCRIPTIONS_DISABLED.to_string()).await;
+                };
+                self.import_subscriptions(subscriptions, file_message, items, apply)
+                    .await?
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to parse import file {}: {:?}",
+                    document.file_name, e
+                );
+                return self
+                    .reply(m, format!("Файл не подходит для импорта: {:#}", e))
+                    .await;
+            }
+        };
+        if summary.is_empty() {
+            return self
+                .reply(m, "В файле нечего импортировать".to_string())
+                .await;
+        }
+
+        let (header, reply_markup) = if apply {
+            ("Импорт завершен:", None)
+        } else {
+            (
+                "Проверка импорта, пока ничего не изменено:",
+                Some(InlineKeyboardMarkup {
+                    inline_keyboard: vec![vec![InlineKeyboardButton {
+                        text: "Импортировать".to_string(),
+                        callback_data: IMPORT_CALLBACK.to_string(),
+                    }]],
+                }),
+            )
+        };
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: file_message.chat.id.to_string(),
+                text: format!("{}\n{}", header, summary.join("\n")),
+                reply_to_message_id: Some(&file_message.message_id),
+                reply_markup,
+            })
+            .await
+    }
+
+    /// Applies the import checked by the summary message
+    pub(crate) async fn apply_import(&self, q: &CallbackQuery, summary: &Message) -> Result<()> {
+        let file_message = summary
+            .reply_to_message
+            .as_deref()
+            .filter(|f| f.from.as_ref().is_some_and(|u| u.id == q.from.id));
+        let Some(file_message) = file_message else {
+            return self
+                .telegram_client
+                .async_answer_callback_query(AnswerCallbackQuery {
+                    callback_query_id: &q.id,
+                    text: Some("Импортировать может только отправитель файла".to_string()),
+                })
+                .await;
+        };
+        self.telegram_client
+            .async_answer_callback_query(AnswerCallbackQuery {
+                callback_query_id: &q.id,
+                text: Some("Импортирую".to_string()),
+            })
+            .await?;
+        self.import_file(file_message, file_message, true).await
+    }
+
+    /// Episodes, which are already in the feed, are skipped, settings are replaced
+    async fn import_feeds(
+        &self,
+        m: &Message,
+        export: FeedsExport,
+        apply: bool,
+    ) -> Result<Vec<String>> {
+        let user = message_user(m)?;
+        let feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&self.roots.root(user)))
+            .await?;
+        let mut summary = vec![];
+        for imported in export.feeds {
+            let feed = self.roots.feed(user, &imported.name);
+            let exists = feed.is_default() || feeds.contains(&imported.name);
+            let mut episodes = vec![];
+            for episode in imported.episodes {
+                if !exists || self.find_duplicate(&feed, &episode.keys()).await?.is_none() {
+                    episodes.push(episode);
+                }
+            }
+            // Urls of the feed are kept, the secret is changed only by /rotate
+            let current = self.metadata.load_settings(&feed.settings_path()).await?;
+            let imported_settings = FeedSettings {
+                url_secret: current.url_secret.clone(),
+                ..imported.settings
+            };
+            let settings_changed = current != imported_settings;
+            summary.push(format!(
+                "{}: {}, новых эпизодов {}{}",
+                feed.name,
+                if exists {
+                    "фид есть"
+                } else {
+                    "новый фид"
+                },
+                episodes.len(),
+                if settings_changed {
+                    ", настройки заменяются"
+                } else {
+                    ""
+                }
+            ));
+            if !apply {
+                continue;
+            }
+
+            if !exists {
+                self.add_feed(user, &feed.name).await?;
+            }
+            if settings_changed {
+                self.metadata
+                    .update_settings(&feed.settings_path(), |settings| {
+                        *settings = FeedSettings {
+                            url_secret: settings.url_secret.take(),
+                            ..imported_settings.clone()
+                        }
+                    })
+                    .await?;
+            }
+            let mut items = vec![];
+            for episode in episodes {
+                items.push(self.import_episode(&feed, episode).await?);
+            }
+            self.update_feed(&feed, |metadata| {
+                let new: Vec<VideoMetadata> = items
+                    .iter()
+                    .filter(|item| {
+                        let keys = item.keys();
+                        !metadata.iter().any(|old| keys.matches(&old.keys()))
+                    })
+                    .cloned()
+                    .collect();
+                metadata.extend(new);
+            })
+            .await?;
+        }
+        Ok(summary)
+    }
+
+    /// Objects of the storage outside of the feed are copied into it, so the
+    /// retention of one feed doesn't remove audio of another. Audio from other
+    /// storages is kept by the url and is never removed.
+    async fn import_episode(
+        &self,
+        feed: &Feed,
+        mut episode: VideoMetadata,
+    ) -> Result<VideoMetadata> {
+        // Paths are set only for the objects copied to the feed
+        episode.chapters_path = None;
+        episode.transcript_path = None;
+        episode.file_path = self.import_object(feed, &mut episode.file_url).await?;
+        if let Some(chapters_url) = episode.chapters_url.as_mut() {
+            episode.chapters_path =
+                Some(self.import_object(feed, chapters_url).await?).filter(|p| !p.is_empty());
+        }
+        if let Some(transcript_url) = episode.transcript_url.as_mut() {
+            episode.transcript_path =
+                Some(self.import_object(feed, transcript_url).await?).filter(|p| !p.is_empty());
+        }
+        Ok(episode)
+    }
+
+    /// Returns the path of the object in the feed or an empty path for external urls.
+    /// Only objects of the importing user are copied, urls of other users' objects
+    /// are kept as external ones.
+    async fn import_object(&self, feed: &Feed, url: &mut String) -> Result<String> {
+        let Some(path) = url
+            .strip_prefix(&self.storage.get_public_url(""))
+            .filter(|p| p.starts_with(&format!("{}/", feed.root)))
+            .filter(|p| p.split('/').all(|s| !s.is_empty() && s != "." && s != ".."))
+        else {
+            return Ok(String::new());
+        };
+        let data_path = feed.data_path();
+        if path.starts_with(&format!("{}/", data_path)) {
+            return Ok(path.to_string());
+        }
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let new_path = format!("{}/{}", data_path, file_name);
+        self.storage
+            .copy_object(path, &new_path)
+            .await
+            .with_context(|| format!("Failed to copy imported object {}", path))?;
+        *url = self.storage.get_public_url(&new_path);
+        Ok(new_path)
+    }
+
+    /// Feeds of the subscriptions are created if needed, videos already
+    /// in the playlists are not added, like with a new subscription
+    async fn import_subscriptions(
+        &self,
+        subscriptions: &Subscriptions,
+        m: &Message,
+        items: Vec<OpmlSubscription>,
+        apply: bool,
+    ) -> Result<Vec<String>> {
+        let user = message_user(m)?;
+        let mut feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&self.roots.root(user)))
+            .await?;
+        let existing = subscriptions.load().await?;
+        let mut summary = vec![];
+        for item in items {
+            if !is_youtube_collection(&item.url) {
+                summary.push(format!("{}: не поддерживается", item.url));
+                continue;
+            }
+            let url = subscription_listing_url(&item.url);
+            if existing
+                .iter()
+                .any(|s| s.user_id == user.id && s.feed == item.feed && s.url == url)
+            {
+                summary.push(format!("{} -> {}: уже есть", item.title, item.feed));
+                continue;
+            }
+            let is_new_feed = item.feed != DEFAULT_FEED && !feeds.contains(&item.feed);
+            summary.push(format!(
+                "{} -> {}: новая подписка{}",
+                item.title,
+                item.feed,
+                if is_new_feed {
+                    ", новый фид"
+                } else {
+                    ""
+                }
+            ));
+            if !apply {
+                continue;
+            }
+
+            if is_new_feed {
+                self.add_feed(user, &item.feed).await?;
+                feeds.push(item.feed.to_string());
+            }
+            let feed = self.roots.feed(user, &item.feed);
+            if let Err(e) = self.add_subscription(subscriptions, m, &url, &feed).await {
+                warn!("Failed to import subscription {}: {:?}", url, e);
+                summary.pop();
+                summary.push(format!(
+                    "{} -> {}: не удалось подписаться",
+                    item.title, item.feed
+                ));
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioSettings;
+    use std::time::SystemTime;
+
+    fn episode(name: &str) -> VideoMetadata {
+        VideoMetadata {
+            file_size: 1024,
+            file_url: format!("https://storage.example.com/user/audio/{}.m4a", name),
+            video_id: name.to_string(),
+            created_at: SystemTime::now(),
+            name: name.to_string(),
+            original_link: String::new(),
+            mime_type: "audio/m4a".to_string(),
+            file_path: String::new(),
+            description: String::new(),
+            thumbnail_url: None,
+            duration_secs: None,
+            uploader: None,
+            published_at: None,
+            removed_secs: None,
+            chapters_url: None,
+            chapters_path: None,
+            content_hash: None,
+            transcript_url: None,
+            transcript_path: None,
+        }
+    }
+
+    fn export(feeds: Vec<FeedExport>) -> Vec<u8> {
+        to_json(&FeedsExport {
+            version: EXPORT_VERSION,
+            feeds,
+        })
+        .unwrap()
+    }
+
+    fn feed(name: &str, episodes: Vec<VideoMetadata>) -> FeedExport {
+        FeedExport {
+            name: name.to_string(),
+            settings: FeedSettings::default(),
+            episodes,
+        }
+    }
+
+    fn subscription(feed: &str, title: &str, url: &str) -> Subscription {
+        Subscription {
+            user_id: 1,
+            user_first_name: "User".to_string(),
+            chat_id: 1,
+            feed: feed.to_string(),
+            url: url.to_string(),
+            title: title.to_string(),
+            seen: HashSet::new(),
+            created_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn parses_exported_feeds() {
+        let data = export(vec![
+            feed(DEFAULT_FEED, vec![export_episode(episode("first"))]),
+            feed("talks", vec![]),
+        ]);
+        let parsed = parse_json(&data).unwrap();
+        assert_eq!(parsed.feeds.len(), 2);
+        assert_eq!(parsed.feeds[0].episodes[0].name, "first");
+    }
+
+    #[test]
+    fn exports_episodes_without_storage_paths() {
+        let mut item = episode("first");
+        item.file_path = "user/audio/first.m4a".to_string();
+        item.chapters_path = Some("user/audio/first.chapters.json".to_string());
+        item.transcript_path = Some("user/audio/first.vtt".to_string());
+        let item = export_episode(item);
+        assert!(item.file_path.is_empty());
+        assert_eq!(item.chapters_path, None);
+        assert_eq!(item.transcript_path, None);
+    }
+
+    #[test]
+    fn rejects_episodes_with_storage_paths() {
+        let mut item = episode("first");
+        item.transcript_path = Some("other/audio/secret.vtt".to_string());
+        let data = export(vec![feed(DEFAULT_FEED, vec![item])]);
+        assert!(parse_json(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_exports() {
+        let mut newer: serde_json::Value = serde_json::from_slice(&export(vec![])).unwrap();
+        newer["version"] = (EXPORT_VERSION + 1).into();
+        assert!(parse_json(&serde_json::to_vec(&newer).unwrap()).is_err());
+
+        let mut unknown = newer.clone();
+        unknown["version"] = EXPORT_VERSION.into();
+        unknown["owner"] = "someone".into();
+        assert!(parse_json(&serde_json::to_vec(&unknown).unwrap()).is_err());
+
+        assert!(parse_json(&export(vec![feed("../other", vec![])])).is_err());
+        assert!(parse_json(&export(vec![feed("talks", vec![]), feed("talks", vec![])])).is_err());
+
+        let mut item = episode("first");
+        item.file_url = "file:///etc/passwd".to_string();
+        assert!(parse_json(&export(vec![feed(DEFAULT_FEED, vec![item])])).is_err());
+    }
+
+    #[test]
+    fn rejects_settings_the_commands_reject() {
+        let invalid = [
+            FeedSettings {
+                max_episodes: Some(0),
+                ..Default::default()
+            },
+            FeedSettings {
+                max_age_days: Some(0),
+                ..Default::default()
+            },
+            FeedSettings {
+                max_episode_minutes: Some(0),
+                ..Default::default()
+            },
+            FeedSettings {
+                sponsorblock: vec!["sponsor".to_string(), "ads".to_string()],
+                ..Default::default()
+            },
+            FeedSettings {
+                audio: AudioSettings {
+                    codec: Some("flac".to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            FeedSettings {
+                audio: AudioSettings {
+                    bitrate_kbps: Some(321),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            FeedSettings {
+                audio: AudioSettings {
+                    speed: Some(0.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            FeedSettings {
+                audio: AudioSettings {
+                    speed: Some(4.5),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        for settings in invalid {
+            let mut talks = feed("talks", vec![]);
+            talks.settings = settings;
+            assert!(parse_json(&export(vec![talks])).is_err());
+        }
+
+        let mut talks = feed("talks", vec![]);
+        talks.settings = FeedSettings {
+            max_episodes: Some(10),
+            max_episode_minutes: Some(60),
+            sponsorblock: vec!["sponsor".to_string()],
+            audio: AudioSettings {
+                codec: Some("opus".to_string()),
+                bitrate_kbps: Some(64),
+                speed: Some(1.5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(parse_json(&export(vec![talks])).is_ok());
+    }
+
+    #[test]
+    fn parses_subscriptions_of_exported_opml() {
+        let feeds = vec![
+            (
+                DEFAULT_FEED.to_string(),
+                "https://storage.example.com/user/feed.xml".to_string(),
+            ),
+            (
+                "talks".to_string(),
+                "https://storage.example.com/user/feeds/talks/feed.xml?a=1&b=2".to_string(),
+            ),
+        ];
+        let subscriptions = vec![
+            subscription(
+                DEFAULT_FEED,
+                "Channel",
+                "https://www.youtube.com/@channel/videos",
+            ),
+            subscription(
+                "talks",
+                "Talks & \"Q&A\"",
+                "https://www.youtube.com/playlist?list=PL1&index=2",
+            ),
+        ];
+        let opml = to_opml("Подкасты", &feeds, &subscriptions);
+        let parsed = parse_opml(opml.as_bytes()).unwrap();
+        let parsed: Vec<(&str, &str, &str)> = parsed
+            .iter()
+            .map(|s| (s.feed.as_str(), s.title.as_str(), s.url.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                (
+                    DEFAULT_FEED,
+                    "Channel",
+                    "https://www.youtube.com/@channel/videos"
+                ),
+                (
+                    "talks",
+                    "Talks & \"Q&A\"",
+                    "https://www.youtube.com/playlist?list=PL1&index=2"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_youtube_feeds_of_other_apps() {
+        let opml = r#"<?xml version="1.0"?>
+            <opml version="1.1"><body><outline text="YouTube Subscriptions">
+                <outline text="Channel" xmlUrl="https://www.youtube.com/feeds/videos.xml?channel_id=UC123"/>
+                <outline text="Blog" xmlUrl="https://example.com/feed.xml"/>
+            </outline></body></opml>"#;
+        let parsed = parse_opml(opml.as_bytes()).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].feed, DEFAULT_FEED);
+        assert_eq!(parsed[0].url, "https://www.youtube.com/channel/UC123");
+    }
+
+    #[test]
+    fn rejects_files_other_than_opml() {
+        assert!(parse_opml(b"<rss><channel></channel></rss>").is_err());
+        assert!(parse_opml(b"<opml><body><outline").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use super::PodcastHandler;
+use super::feeds::Feed;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use telegram_api::Message;
+
+/// Version of the Podcasting 2.0 JSON chapters format
+const CHAPTERS_VERSION: &str = "1.2.0";
+/// Shorter tail of the audio is appended to the previous part
+const MIN_PART_LENGTH: f64 = 60.0;
+pub const CHAPTERS_MIME_TYPE: &str = "application/json+chapters";
+
+/// Chapter of the yt-dlp info dict, times are in seconds
+#[derive(Clone, Debug, Deserialize)]
+pub struct Chapter {
+    pub start_time: f64,
+    pub end_time: f64,
+    #[serde(default)]
+    pub title: String,
+}
+
+#[derive(Serialize)]
+struct ChaptersFile<'a> {
+    version: &'a str,
+    chapters: Vec<JsonChapter<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonChapter<'a> {
+    start_time: f64,
+    title: &'a str,
+}
+
+/// Moves chapters to the timeline of the processed audio: cut segments are
+/// removed and the speed is applied. Chapters, which were cut completely, are dropped.
+pub fn adjust(chapters: &[Chapter], cuts: &[(f64, f64)], speed: f32) -> Vec<Chapter> {
+    chapters
+        .iter()
+        .map(|c| Chapter {
+            start_time: position(c.start_time, cuts, speed),
+            end_time: position(c.end_time, cuts, speed),
+            title: c.title.to_string(),
+        })
+        .filter(|c| c.end_time > c.start_time)
+        .collect()
+}
+
+/// Time of the source audio in the timeline of the processed one
+pub fn position(t: f64, cuts: &[(f64, f64)], speed: f32) -> f64 {
+    let removed: f64 = cuts
+        .iter()
+        .map(|(start, end)| (t.min(*end) - start).max(0.0))
+        .sum();
+    (t - removed) / speed as f64
+}
+
+pub fn to_json(chapters: &[Chapter]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&ChaptersFile {
+        version: CHAPTERS_VERSION,
+        chapters: chapters
+            .iter()
+            .map(|c| JsonChapter {
+                start_time: (c.start_time * 1000.0).round() / 1000.0,
+                title: &c.title,
+            })
+            .collect(),
+    })?)
+}
+
+/// Chapters in the ffmetadata format of ffmpeg
+pub fn to_ffmetadata(chapters: &[Chapter]) -> String {
+    let mut metadata = ";FFMETADATA1\n".to_string();
+    for chapter in chapters {
+        metadata.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start_time * 1000.0).round() as u64,
+            (chapter.end_time * 1000.0).round() as u64,
+            escape_ffmetadata(&chapter.title)
+        ));
+    }
+    metadata
+}
+
+fn escape_ffmetadata(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Chapters of the part of the audio moved to the timeline of the part
+pub fn slice(chapters: &[Chapter], start: f64, end: f64) -> Vec<Chapter> {
+    chapters
+        .iter()
+        .map(|c| Chapter {
+            start_time: c.start_time.max(start) - start,
+            end_time: c.end_time.min(end) - start,
+            title: c.title.to_string(),
+        })
+        .filter(|c| c.end_time > c.start_time)
+        .collect()
+}
+
+/// Splits the audio into parts not longer than max_length. With chapters parts
+/// end on chapter boundaries, chapters longer than max_length are split evenly.
+/// A tail shorter than a minute isn't worth an episode, so the last part
+/// can be slightly longer than max_length.
+pub fn split_points(total: f64, max_length: f64, chapters: Option<&[Chapter]>) -> Vec<(f64, f64)> {
+    if total <= max_length {
+        return vec![(0.0, total)];
+    }
+    let Some(chapters) = chapters.filter(|c| !c.is_empty()) else {
+        return even_parts(0.0, total, max_length);
+    };
+    let mut bounds: Vec<f64> = chapters
+        .iter()
+        .map(|c| c.start_time)
+        .filter(|t| *t > 0.0 && *t < total)
+        .collect();
+    bounds.push(total);
+
+    let mut parts = vec![];
+    let (mut start, mut end) = (0.0, 0.0);
+    for bound in bounds {
+        if bound - start > max_length {
+            if end > start {
+                parts.push((start, end));
+                start = end;
+            }
+            if bound - start > max_length {
+                parts.extend(even_parts(start, bound, max_length));
+                start = bound;
+            }
+        }
+        end = bound;
+    }
+    if end > start {
+        parts.push((start, end));
+    }
+    if parts.len() > 1
+        && let Some((tail_start, tail_end)) = parts.last().copied()
+        && tail_end - tail_start < MIN_PART_LENGTH
+    {
+        parts.pop();
+        if let Some(last) = parts.last_mut() {
+            last.1 = tail_end;
+        }
+    }
+    parts
+}
+
+fn even_parts(start: f64, end: f64, max_length: f64) -> Vec<(f64, f64)> {
+    let count = ((end - start) / max_length).ceil().max(1.0) as usize;
+    let length = (end - start) / count as f64;
+    (0..count)
+        .map(|i| (start + i as f64 * length, start + (i + 1) as f64 * length))
+        .collect()
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn set_split(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        minutes: Option<&str>,
+        mode: Option<&str>,
+    ) -> Result<()> {
+        let change = match (minutes, mode) {
+            (None, _) => None,
+            (Some("off"), None) => Some((None, false)),
+            (Some(minutes), None | Some("chapters")) => match minutes.parse::<u64>() {
+                Ok(minutes) if minutes > 0 => Some((Some(minutes), mode.is_some())),
+                _ => return self.reply_split_usage(m).await,
+            },
+            _ => return self.reply_split_usage(m).await,
+        };
+        let settings = match change {
+            Some((max_episode_minutes, split_by_chapters)) => {
+                self.metadata
+                    .update_settings(&feed.settings_path(), |settings| {
+                        settings.max_episode_minutes = max_episode_minutes;
+                        settings.split_by_chapters = split_by_chapters;
+                    })
+                    .await?
+                    .0
+            }
+            None => self.metadata.load_settings(&feed.settings_path()).await?,
+        };
+        let mut text = match (settings.max_episode_minutes, settings.split_by_chapters) {
+            (None, _) => "Записи не разделяются на части".to_string(),
+            (Some(minutes), false) => format!("Записи делятся на части до {} минут", minutes),
+            (Some(minutes), true) => format!(
+                "Записи делятся на части до {} минут по границам глав",
+                minutes
+            ),
+        };
+        if self.audio_processor.is_none() {
+            text.push_str("\nЗаписи не разделяются: не настроен FFMPEG_PATH");
+        }
+        self.reply(m, text).await
+    }
+
+    async fn reply_split_usage(&self, m: &Message) -> Result<()> {
+        self.reply(
+            m,
+            "Используйте /split <минуты>, /split <минуты> chapters или /split off".to_string(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: f64 = 3600.0;
+
+    fn chapter(start_time: f64, end_time: f64, title: &str) -> Chapter {
+        Chapter {
+            start_time,
+            end_time,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn keeps_short_audio_whole() {
+        assert_eq!(split_points(HOUR, HOUR, None), [(0.0, HOUR)]);
+    }
+
+    #[test]
+    fn splits_evenly_without_chapters() {
+        assert_eq!(
+            split_points(2.5 * HOUR, HOUR, None),
+            [(0.0, 3000.0), (3000.0, 6000.0), (6000.0, 9000.0)]
+        );
+        // Empty chapters are the same as no chapters
+        assert_eq!(split_points(2.0 * HOUR, HOUR, Some(&[])).len(), 2);
+    }
+
+    #[test]
+    fn ends_parts_on_chapter_boundaries() {
+        let chapters = [
+            chapter(0.0, 1500.0, "a"),
+            chapter(1500.0, 3000.0, "b"),
+            chapter(3000.0, 4500.0, "c"),
+            chapter(4500.0, 6000.0, "d"),
+        ];
+        assert_eq!(
+            split_points(6000.0, HOUR, Some(&chapters)),
+            [(0.0, 3000.0), (3000.0, 6000.0)]
+        );
+    }
+
+    #[test]
+    fn splits_long_chapter_evenly() {
+        let chapters = [chapter(0.0, 600.0, "intro"), chapter(600.0, 7800.0, "long")];
+        assert_eq!(
+            split_points(7800.0, HOUR, Some(&chapters)),
+            [(0.0, 600.0), (600.0, 4200.0), (4200.0, 7800.0)]
+        );
+    }
+
+    #[test]
+    fn appends_short_tail_to_previous_part() {
+        let chapters = [chapter(0.0, 3500.0, "a"), chapter(3500.0, 3530.0, "outro")];
+        assert_eq!(
+            split_points(3530.0, 3510.0, Some(&chapters)),
+            [(0.0, 3530.0)]
+        );
+    }
+
+    #[test]
+    fn slices_chapter_crossing_split() {
+        let chapters = [
+            chapter(0.0, 1000.0, "a"),
+            chapter(1000.0, 3000.0, "crossing"),
+            chapter(3000.0, 4000.0, "c"),
+        ];
+        let first = slice(&chapters, 0.0, 2000.0);
+        let second = slice(&chapters, 2000.0, 4000.0);
+        let times = |c: &[Chapter]| -> Vec<(f64, f64, String)> {
+            c.iter()
+                .map(|c| (c.start_time, c.end_time, c.title.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            times(&first),
+            [
+                (0.0, 1000.0, "a".to_string()),
+                (1000.0, 2000.0, "crossing".to_string())
+            ]
+        );
+        assert_eq!(
+            times(&second),
+            [
+                (0.0, 1000.0, "crossing".to_string()),
+                (1000.0, 2000.0, "c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_chapters_after_cuts_and_speed() {
+        let chapters = [
+            chapter(0.0, 100.0, "a"),
+            chapter(100.0, 200.0, "sponsor"),
+            chapter(200.0, 400.0, "b"),
+        ];
+        let adjusted = adjust(&chapters, &[(100.0, 200.0)], 2.0);
+        let times: Vec<(f64, f64)> = adjusted
+            .iter()
+            .map(|c| (c.start_time, c.end_time))
+            .collect();
+        // Chapter cut out completely is dropped
+        assert_eq!(times, [(0.0, 50.0), (50.0, 150.0)]);
+    }
+}
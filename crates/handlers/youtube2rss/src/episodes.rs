@@ -0,0 +1,622 @@
+use super::audio::{self, Tags};
+use super::chapters::{self, Chapter};
+use super::feeds::Feed;
+use super::metadata::{EpisodeKeys, FeedSettings, VideoMetadata};
+use super::sponsorblock;
+use super::transcripts::{self, Cue, Transcript};
+use super::yt_dlp::VideoInfo;
+use super::{DuplicateEpisode, PodcastHandler, parse_index, storage_file_name};
+use anyhow::{Context, Result, anyhow};
+use chrono::DateTime;
+use chrono::offset::Utc;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use telegram_api::Message;
+
+const MAX_LISTED_EPISODES: usize = 50;
+
+/// Audio of the episode in the storage
+struct StoredAudio {
+    path: String,
+    ext: String,
+    size: u64,
+    duration_secs: Option<u64>,
+    removed_secs: Option<u64>,
+    chapters_path: Option<String>,
+    /// Number of the part and the count of parts of a split recording
+    part: Option<(usize, usize)>,
+    transcript_path: Option<String>,
+    transcript: Option<Vec<Cue>>,
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn list_episodes(&self, m: &Message, feed: &Feed) -> Result<()> {
+        let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+        if metadata.is_empty() {
+            return self.reply(m, "В фиде пока нет эпизодов".to_string()).await;
+        }
+        let mut lines: Vec<String> = metadata
+            .iter()
+            .take(MAX_LISTED_EPISODES)
+            .enumerate()
+            .map(|(i, item)| {
+                let created_at: DateTime<Utc> = item.created_at.into();
+                format!(
+                    "{}. {} ({})",
+                    i + 1,
+                    item.name,
+                    created_at.format("%d.%m.%Y")
+                )
+            })
+            .collect();
+        if metadata.len() > MAX_LISTED_EPISODES {
+            lines.push(format!(
+                "...и еще {} эпизодов",
+                metadata.len() - MAX_LISTED_EPISODES
+            ));
+        }
+        self.reply(m, lines.join("\n")).await
+    }
+
+    pub(crate) async fn delete_episode(&self, m: &Message, feed: &Feed, n: &str) -> Result<()> {
+        let deleted = self
+            .update_feed(feed, |metadata| {
+                parse_index(n, metadata.len())
+                    .and_then(|i| metadata.remove(i))
+                    .map(|item| item.name)
+            })
+            .await?;
+        match deleted {
+            Some(name) => {
+                self.reply(m, format!("Эпизод {} удален из фида", name))
+                    .await
+            }
+            None => self.reply(m, format!("Нет эпизода с номером {}", n)).await,
+        }
+    }
+
+    pub(crate) async fn move_episode(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        from: &str,
+        to: &str,
+    ) -> Result<()> {
+        let moved = self
+            .update_feed(feed, |metadata| {
+                let from_index = parse_index(from, metadata.len())?;
+                let to_index = parse_index(to, metadata.len())?;
+                let item = metadata.remove(from_index)?;
+                let name = item.name.to_string();
+                metadata.insert(to_index, item);
+                Some(name)
+            })
+            .await?;
+        match moved {
+            Some(name) => {
+                self.reply(m, format!("Эпизод {} перемещен на позицию {}", name, to))
+                    .await
+            }
+            None => {
+                self.reply(m, format!("Нет эпизодов с номерами {} и {}", from, to))
+                    .await
+            }
+        }
+    }
+
+    pub(crate) async fn process_mp3(
+        &self,
+        feed: &Feed,
+        url: String,
+        force: bool,
+    ) -> Result<String> {
+        let content = self.http_client.get(&url).send().await?.bytes().await?;
+        let content_hash = hex::encode(Sha256::digest(&content));
+        if !force {
+            let keys = EpisodeKeys {
+                hash: Some(content_hash.to_string()),
+                ..Default::default()
+            };
+            if let Some(name) = self.find_duplicate(feed, &keys).await? {
+                return Err(DuplicateEpisode { name }.into());
+            }
+        }
+        let file_name = url
+            .split("/")
+            .last()
+            .ok_or(anyhow!("Can't extract mp3 file name"))?;
+
+        let s3_result_file_path: String = format!("{}/{}.mp3", feed.data_path(), file_name);
+        self.storage
+            .upload_object(content.to_vec(), &s3_result_file_path)
+            .await?;
+
+        let file_size = content.len() as u64;
+        let mp3_metadata = VideoMetadata {
+            file_size,
+            file_url: self.storage.get_public_url(&s3_result_file_path),
+            video_id: String::new(),
+            created_at: SystemTime::now(),
+            name: file_name.to_string(),
+            original_link: url,
+            mime_type: "audio/mp3".to_string(),
+            file_path: s3_result_file_path,
+            description: String::new(),
+            thumbnail_url: None,
+            duration_secs: None,
+            uploader: None,
+            published_at: None,
+            removed_secs: None,
+            chapters_url: None,
+            chapters_path: None,
+            content_hash: Some(content_hash),
+            transcript_url: None,
+            transcript_path: None,
+        };
+        self.add_episodes(feed, vec![mp3_metadata]).await
+    }
+
+    /// Returns the feed url and the added episodes, long recordings can be split into several
+    pub(crate) async fn process_url(
+        &self,
+        url: &str,
+        feed: &Feed,
+        message_id: i64,
+        force: bool,
+    ) -> Result<(String, Vec<VideoMetadata>)> {
+        let download_path = self
+            .tmp_dir
+            .join(format!("{}{}", message_id, "%(id)s.%(ext)s"))
+            .to_str()
+            .expect("Failed to convert to string file path")
+            .to_string();
+        let info = self.yt_dlp.download(url, &download_path).await?;
+        self.add_video(url, feed, &info, force).await
+    }
+
+    /// Uploads the downloaded audio, adds it to the feed and removes the file.
+    /// Duplicates of the episodes in the feed are added only if forced.
+    pub(crate) async fn add_video(
+        &self,
+        link: &str,
+        feed: &Feed,
+        info: &VideoInfo,
+        force: bool,
+    ) -> Result<(String, Vec<VideoMetadata>)> {
+        let subtitles = self.read_subtitles(info);
+        let audio: Result<(String, Vec<StoredAudio>)> = async {
+            let content_hash = file_hash(&info.filepath)?;
+            if !force {
+                let keys = EpisodeKeys {
+                    id: Some(info.episode_id()),
+                    link: Some(link.to_string()).filter(|l| !l.is_empty()),
+                    hash: Some(content_hash.to_string()),
+                };
+                if let Some(name) = self.find_duplicate(feed, &keys).await? {
+                    return Err(DuplicateEpisode { name }.into());
+                }
+            }
+            let stored = self.upload_audio(feed, info, subtitles.as_deref()).await?;
+            Ok((content_hash, stored))
+        }
+        .await;
+
+        if let Err(e) = fs::remove_file(&info.filepath) {
+            warn!(
+                "Failed to remove temporary file {}: {}",
+                info.filepath.display(),
+                e
+            );
+        }
+
+        let (content_hash, parts) = audio?;
+        let mut episode = VideoMetadata {
+            file_size: 0,
+            file_url: String::new(),
+            video_id: info.episode_id(),
+            created_at: SystemTime::now(),
+            original_link: link.to_string(),
+            mime_type: String::new(),
+            file_path: String::new(),
+            thumbnail_url: info.thumbnail.clone(),
+            duration_secs: parts.iter().map(|p| p.duration_secs).sum(),
+            published_at: info.published_at(),
+            uploader: info.uploader.clone(),
+            description: info.description.clone().unwrap_or_default(),
+            name: info.title.to_string(),
+            removed_secs: None,
+            chapters_url: None,
+            chapters_path: None,
+            content_hash: Some(content_hash),
+            transcript_url: None,
+            transcript_path: None,
+        };
+        if info.is_youtube() {
+            self.complete_from_data_api(&mut episode).await;
+        }
+        let transcript = parts
+            .iter()
+            .filter_map(|p| p.transcript.as_deref())
+            .map(transcripts::to_text)
+            .collect::<Vec<String>>()
+            .join("\n");
+        if let Some(summary) = self.summarize(&episode.video_id, &transcript).await {
+            episode.description = format!("{}\n\n{}", summary, episode.description)
+                .trim()
+                .to_string();
+        }
+        let episodes: Vec<VideoMetadata> = parts
+            .into_iter()
+            .map(|audio| {
+                let mut item = episode.clone();
+                if let Some((n, count)) = audio.part {
+                    item.name = format!("{} (часть {}/{})", episode.name, n, count);
+                    item.video_id = format!("{}:part{}", episode.video_id, n);
+                    // Later parts are newer, so podcast apps list them in order
+                    item.created_at = episode.created_at - Duration::from_secs((count - n) as u64);
+                    item.duration_secs = audio.duration_secs;
+                }
+                item.file_size = audio.size;
+                item.file_url = self.storage.get_public_url(&audio.path);
+                item.mime_type = audio::mime_type(&audio.ext);
+                item.file_path = audio.path;
+                item.removed_secs = audio.removed_secs;
+                item.chapters_url = audio
+                    .chapters_path
+                    .as_ref()
+                    .map(|p| self.storage.get_public_url(p));
+                item.chapters_path = audio.chapters_path;
+                item.transcript_url = audio
+                    .transcript_path
+                    .as_ref()
+                    .map(|p| self.storage.get_public_url(p));
+                item.transcript_path = audio.transcript_path;
+                item
+            })
+            .collect();
+        // Text is indexed along with the episodes, so it's saved first
+        let text = match subtitles {
+            Some(subtitles) => transcripts::to_text(&subtitles),
+            None => transcript,
+        };
+        if let Some(search) = &self.search
+            && !text.is_empty()
+            && let Err(e) = search.save_text(&feed.root, &episode.video_id, &text).await
+        {
+            warn!("Failed to save subtitles of {}: {:?}", episode.video_id, e);
+        }
+        let feed_url = self.add_episodes(feed, episodes.clone()).await?;
+        Ok((feed_url, episodes))
+    }
+
+    /// Processes the downloaded audio according to the feed settings and uploads it
+    async fn upload_audio(
+        &self,
+        feed: &Feed,
+        info: &VideoInfo,
+        subtitles: Option<&[Cue]>,
+    ) -> Result<Vec<StoredAudio>> {
+        let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+        let cuts = match &self.audio_processor {
+            Some(_) if info.is_youtube() && !settings.sponsorblock.is_empty() => {
+                // Episode without cuts is better than no episode
+                self.sponsorblock
+                    .segments(&info.id, &settings.sponsorblock)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!(
+                            "Failed to get SponsorBlock segments of {}: {:?}",
+                            info.id, e
+                        );
+                        vec![]
+                    })
+            }
+            _ => vec![],
+        };
+        // Speed is changed only by ffmpeg
+        let speed = match &self.audio_processor {
+            Some(_) => settings.audio.speed.unwrap_or(1.0),
+            None => 1.0,
+        };
+        // Pauses removed by the silence trimming can't be found in the source timeline,
+        // so chapters are dropped and subtitles give way to the speech-to-text
+        let keeps_timeline = self.audio_processor.is_none() || !settings.audio.trim_silence;
+        let chapters = match &info.chapters {
+            Some(chapters) if keeps_timeline => chapters::adjust(chapters, &cuts, speed),
+            _ => vec![],
+        };
+        let transcript = match subtitles {
+            _ if !settings.transcripts => Transcript::Disabled,
+            Some(cues) if keeps_timeline => {
+                Transcript::Subtitles(transcripts::adjust(cues, &cuts, speed))
+            }
+            _ if self.speech_to_text.is_some() => Transcript::SpeechToText,
+            _ => Transcript::Disabled,
+        };
+        let processed = match &self.audio_processor {
+            Some(processor)
+                if settings.audio.is_enabled() || !cuts.is_empty() || !chapters.is_empty() =>
+            {
+                let album = settings.feed_title(&feed.display_name());
+                let tags = Tags {
+                    title: &info.title,
+                    artist: info.uploader.as_deref(),
+                    album: &album,
+                    artwork_url: info.thumbnail.as_deref(),
+                };
+                Some(
+                    processor
+                        .process(
+                            &info.filepath,
+                            &info.ext,
+                            &settings.audio,
+                            &cuts,
+                            &chapters,
+                            &tags,
+                        )
+                        .await?,
+                )
+            }
+            None if settings.audio.is_enabled() || !settings.sponsorblock.is_empty() => {
+                warn!(
+                    "Audio processing of the feed {} is skipped, FFMPEG_PATH is not provided",
+                    feed.name
+                );
+                None
+            }
+            _ => None,
+        };
+        let (file, ext) = match &processed {
+            Some(p) => (p.path.to_path_buf(), p.ext.to_string()),
+            None => (info.filepath.to_path_buf(), info.ext.to_string()),
+        };
+
+        let removed = sponsorblock::removed_secs(&cuts);
+        let duration = match (&self.audio_processor, &processed) {
+            (Some(processor), Some(_)) => match processor.duration(&file).await {
+                Ok(duration) => Some(duration),
+                Err(e) => {
+                    warn!("Failed to get duration of {}: {:?}", file.display(), e);
+                    None
+                }
+            },
+            _ => info.duration,
+        };
+        let file_name = storage_file_name(&info.episode_id());
+        let stored = self
+            .upload_parts(
+                feed,
+                &settings,
+                &file,
+                &ext,
+                &file_name,
+                duration,
+                &chapters,
+                &transcript,
+            )
+            .await;
+        if processed.is_some()
+            && let Err(e) = fs::remove_file(&file)
+        {
+            warn!("Failed to remove temporary file {}: {}", file.display(), e);
+        }
+
+        let mut stored = stored?;
+        if let Some(first) = stored.first_mut() {
+            first.removed_secs = (!cuts.is_empty()).then_some(removed.round() as u64);
+        }
+        Ok(stored)
+    }
+
+    /// Uploads the audio as one episode or splits it into several ones according
+    /// to the max episode length of the feed
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_parts(
+        &self,
+        feed: &Feed,
+        settings: &FeedSettings,
+        file: &Path,
+        ext: &str,
+        file_name: &str,
+        duration: Option<f64>,
+        chapters: &[Chapter],
+        transcript: &Transcript,
+    ) -> Result<Vec<StoredAudio>> {
+        let parts = match (
+            &self.audio_processor,
+            settings.max_episode_minutes,
+            duration,
+        ) {
+            (Some(processor), Some(max_minutes), Some(duration)) => {
+                let parts = chapters::split_points(
+                    duration,
+                    (max_minutes * 60) as f64,
+                    settings.split_by_chapters.then_some(chapters),
+                );
+                (parts.len() > 1).then_some((processor, parts))
+            }
+            (None, Some(_), _) => {
+                warn!(
+                    "Episodes of the feed {} are not split, FFMPEG_PATH is not provided",
+                    feed.name
+                );
+                None
+            }
+            _ => None,
+        };
+        let Some((processor, parts)) = parts else {
+            return Ok(vec![
+                self.upload_part(
+                    feed,
+                    file,
+                    ext,
+                    file_name,
+                    chapters,
+                    transcript.clone(),
+                    duration,
+                )
+                .await?,
+            ]);
+        };
+
+        let mut stored: Vec<StoredAudio> = vec![];
+        for (i, (start, end)) in parts.iter().enumerate() {
+            let result = match processor.cut(file, i + 1, *start, *end).await {
+                Ok(part_file) => {
+                    let result = self
+                        .upload_part(
+                            feed,
+                            &part_file,
+                            ext,
+                            &format!("{}.part{}", file_name, i + 1),
+                            &chapters::slice(chapters, *start, *end),
+                            transcript.slice(*start, *end),
+                            Some(end - start),
+                        )
+                        .await;
+                    if let Err(e) = fs::remove_file(&part_file) {
+                        warn!(
+                            "Failed to remove temporary file {}: {}",
+                            part_file.display(),
+                            e
+                        );
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(mut part) => {
+                    part.part = Some((i + 1, parts.len()));
+                    stored.push(part);
+                }
+                Err(e) => {
+                    // Episode is added with all parts or not added at all
+                    let uploaded: Vec<String> = stored
+                        .iter()
+                        .flat_map(|p| {
+                            [
+                                Some(p.path.to_string()),
+                                p.chapters_path.clone(),
+                                p.transcript_path.clone(),
+                            ]
+                        })
+                        .flatten()
+                        .collect();
+                    for path in uploaded {
+                        if let Err(e) = self.storage.delete_object(&path).await {
+                            warn!("Failed to delete episode object {}: {:?}", path, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(stored)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_part(
+        &self,
+        feed: &Feed,
+        file: &Path,
+        ext: &str,
+        file_name: &str,
+        chapters: &[Chapter],
+        transcript: Transcript,
+        duration: Option<f64>,
+    ) -> Result<StoredAudio> {
+        let size = fs::metadata(file)
+            .with_context(|| format!("Can't read size of {}", file.display()))?
+            .len();
+        let path = format!("{}/{}.{}", feed.data_path(), file_name, ext);
+        self.storage
+            .upload_file(file.to_path_buf(), path.to_string())
+            .await?;
+
+        // Podcast apps, which don't read chapters from the file, load them by the link in the feed
+        let chapters_path = if chapters.is_empty() {
+            None
+        } else {
+            let chapters_path = format!("{}/{}.chapters.json", feed.data_path(), file_name);
+            self.storage
+                .upload_object(chapters::to_json(chapters)?, &chapters_path)
+                .await?;
+            Some(chapters_path)
+        };
+
+        let transcript = match transcript {
+            Transcript::Disabled => None,
+            Transcript::Subtitles(cues) => Some(cues).filter(|c| !c.is_empty()),
+            Transcript::SpeechToText => self.transcribe(file).await,
+        };
+        let transcript_path = match &transcript {
+            Some(cues) => {
+                let transcript_path = format!("{}/{}.vtt", feed.data_path(), file_name);
+                self.storage
+                    .upload_object(transcripts::to_vtt(cues).into_bytes(), &transcript_path)
+                    .await?;
+                Some(transcript_path)
+            }
+            None => None,
+        };
+
+        Ok(StoredAudio {
+            size,
+            path,
+            ext: ext.to_string(),
+            chapters_path,
+            duration_secs: duration.map(|d| d.round() as u64),
+            removed_secs: None,
+            part: None,
+            transcript_path,
+            transcript,
+        })
+    }
+
+    /// Fills the fields yt-dlp didn't provide from the YouTube Data API, if it's
+    /// configured. Episode is added anyway, so errors are only logged.
+    async fn complete_from_data_api(&self, item: &mut VideoMetadata) {
+        let Some(youtube_sdk) = &self.youtube_sdk else {
+            return;
+        };
+        if !item.description.is_empty()
+            && item.thumbnail_url.is_some()
+            && item.duration_secs.is_some()
+        {
+            return;
+        }
+        let video = match youtube_sdk.get_video_info(&item.video_id).await {
+            Ok(Some(video)) => video,
+            Ok(None) => {
+                warn!("Received empty video info about {}", item.video_id);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to get video info about {}: {:?}", item.video_id, e);
+                return;
+            }
+        };
+        if item.description.is_empty() {
+            item.description = video.snippet.description;
+        }
+        if item.thumbnail_url.is_none() {
+            item.thumbnail_url = video.snippet.thumbnails.best_url().map(|u| u.to_string());
+        }
+        if item.duration_secs.is_none() {
+            item.duration_secs = video.content_details.duration_secs();
+        }
+    }
+}
+
+fn file_hash(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file =
+        fs::File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Can't read {} for hashing", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,459 @@
+use super::metadata::FeedSettings;
+use super::{PodcastHandler, message_user};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use telegram_api::{Message, SendMessage, User};
+
+pub const DEFAULT_FEED: &str = "default";
+const MAX_FEED_NAME_LENGTH: usize = 32;
+const MIGRATE_USAGE: &str = "Использование: /migrate <id пользователя> <старое имя>";
+/// 128 bits of the hmac are enough to make the prefix unguessable
+const ROOT_LENGTH: usize = 32;
+
+/// Location of a user's podcast feed in the storage.
+/// Default feed lives right under the user's root `{root}/...`,
+/// named ones under `{root}/feeds/{name}/...`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Feed {
+    /// Name of the user shown in the default feed title
+    pub owner: String,
+    /// Storage prefix of all user's feeds
+    pub root: String,
+    pub name: String,
+}
+
+impl Feed {
+    pub fn new(user: &User, root: &str, name: &str) -> Self {
+        Self {
+            owner: user.first_name.to_string(),
+            root: root.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_FEED
+    }
+
+    fn prefix(&self) -> String {
+        if self.is_default() {
+            self.root.to_string()
+        } else {
+            format!("{}/feeds/{}", self.root, self.name)
+        }
+    }
+
+    pub fn metadata_path(&self) -> String {
+        format!("{}/metadata.mp", self.prefix())
+    }
+
+    pub fn data_path(&self) -> String {
+        format!("{}/audio", self.prefix())
+    }
+
+    pub fn rss_path(&self) -> String {
+        format!("{}/feed.xml", self.prefix())
+    }
+
+    pub fn settings_path(&self) -> String {
+        format!("{}/settings.mp", self.prefix())
+    }
+
+    /// Keys of the episodes for duplicate detection
+    pub fn index_path(&self) -> String {
+        format!("{}/index.mp", self.prefix())
+    }
+
+    /// Used in the default title of the feed
+    pub fn display_name(&self) -> String {
+        if self.is_default() {
+            self.owner.to_string()
+        } else {
+            format!("{} {}", self.owner, self.name)
+        }
+    }
+}
+
+/// List of named feeds of the user, the default feed is not stored there
+pub fn feeds_index_path(root: &str) -> String {
+    format!("{}/feeds.mp", root)
+}
+
+/// Settings of the feed, which the object belongs to, see Feed::prefix
+pub fn object_settings_path(path: &str) -> Option<String> {
+    let mut segments = path.split('/');
+    let root = segments.next().filter(|r| !r.is_empty())?;
+    match (segments.next(), segments.next()) {
+        (Some("feeds"), Some(name)) if is_valid_feed_name(name) => {
+            Some(format!("{}/feeds/{}/settings.mp", root, name))
+        }
+        (Some(_), _) => Some(format!("{}/settings.mp", root)),
+        (None, _) => None,
+    }
+}
+
+/// Derives storage roots of users' feeds from the stable telegram user id.
+/// Storage is public, so the root is a keyed hash and can't be guessed
+/// from the id.
+pub struct FeedRoots {
+    secret: String,
+}
+
+impl FeedRoots {
+    pub fn new() -> Self {
+        let secret = env::var("PODCAST_FEED_SECRET")
+            .expect("Provide PODCAST_FEED_SECRET environment variable please");
+        Self { secret }
+    }
+
+    pub fn root(&self, user: &User) -> String {
+        self.user_root(user.id)
+    }
+
+    fn user_root(&self, user_id: i32) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("Hmac accepts keys of any length");
+        mac.update(user_id.to_string().as_bytes());
+        let mut root = hex::encode(mac.finalize().into_bytes());
+        root.truncate(ROOT_LENGTH);
+        root
+    }
+
+    pub fn feed(&self, user: &User, name: &str) -> Feed {
+        Feed::new(user, &self.root(user), name)
+    }
+}
+
+/// Names go to storage paths and callback data, so they are kept short and simple
+pub fn is_valid_feed_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_FEED_NAME_LENGTH
+        && name != DEFAULT_FEED
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl PodcastHandler<'_> {
+    /// Replies to the user and returns None if there is no feed with the name
+    pub(crate) async fn find_feed(&self, m: &Message, name: Option<&str>) -> Result<Option<Feed>> {
+        let user = message_user(m)?;
+        let name = name.unwrap_or(DEFAULT_FEED);
+        if name == DEFAULT_FEED {
+            return Ok(Some(self.roots.feed(user, DEFAULT_FEED)));
+        }
+        let feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&self.roots.root(user)))
+            .await?;
+        if feeds.iter().any(|f| f == name) {
+            Ok(Some(self.roots.feed(user, name)))
+        } else {
+            self.reply(m, format!("Фид {} не найден, список фидов: /feeds", name))
+                .await?;
+            Ok(None)
+        }
+    }
+
+    pub(crate) async fn list_feeds(&self, m: &Message) -> Result<()> {
+        let user = message_user(m)?;
+        let feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&self.roots.root(user)))
+            .await?;
+        let mut lines = vec![];
+        for name in std::iter::once(DEFAULT_FEED.to_string()).chain(feeds) {
+            let feed = self.roots.feed(user, &name);
+            lines.push(format!("{}: {}", name, self.feed_url(&feed).await?));
+        }
+        self.reply(m, lines.join("\n")).await
+    }
+
+    pub(crate) async fn create_feed(&self, m: &Message, name: Option<&str>) -> Result<()> {
+        let user = message_user(m)?;
+        let Some(name) = name.filter(|n| is_valid_feed_name(n)) else {
+            return self
+                .reply(
+                    m,
+                    "Используйте /newfeed <имя>, имя из латинских букв в нижнем регистре, цифр, - и _"
+                        .to_string(),
+                )
+                .await;
+        };
+        if !self.add_feed(user, name).await? {
+            return self.reply(m, format!("Фид {} уже существует", name)).await;
+        }
+        let feed = self.roots.feed(user, name);
+        self.reply(
+            m,
+            format!(
+                "Фид {} создан и доступен по адресу: {}",
+                name,
+                self.feed_url(&feed).await?
+            ),
+        )
+        .await
+    }
+
+    /// Adds the named feed to the user's feeds, returns false if it already exists
+    pub(crate) async fn add_feed(&self, user: &User, name: &str) -> Result<bool> {
+        let (_, created) = self
+            .metadata
+            .update_feeds(&feeds_index_path(&self.roots.root(user)), |feeds| {
+                let created = !feeds.iter().any(|f| f == name);
+                if created {
+                    feeds.push(name.to_string());
+                }
+                created
+            })
+            .await?;
+        if created {
+            // Empty feed is published right away, so it can be added to a podcast app
+            self.update_feed(&self.roots.feed(user, name), |_| ())
+                .await?;
+        }
+        Ok(created)
+    }
+
+    pub(crate) async fn delete_feed(&self, m: &Message, name: Option<&str>) -> Result<()> {
+        let user = message_user(m)?;
+        let Some(name) = name.map(|n| n.trim_start_matches('#')) else {
+            return self
+                .reply(m, "Используйте /deletefeed <имя>".to_string())
+                .await;
+        };
+        let index_path = feeds_index_path(&self.roots.root(user));
+        let feeds = self.metadata.load_feeds(&index_path).await?;
+        if !feeds.iter().any(|f| f == name) {
+            return self.reply(m, format!("Фид {} не найден", name)).await;
+        }
+
+        let feed = self.roots.feed(user, name);
+        {
+            let _guard = self.lock(&feed.metadata_path()).await;
+            let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+            for path in metadata
+                .iter()
+                .flat_map(|item| self.object_paths(&feed, item))
+            {
+                self.storage.delete_object(&path).await?;
+            }
+            for path in [
+                feed.rss_path(),
+                feed.settings_path(),
+                feed.index_path(),
+                feed.metadata_path(),
+            ] {
+                self.storage.delete_object(&path).await?;
+            }
+        }
+        self.update_search(&feed, &VecDeque::new()).await;
+        if let Some(expiring_feeds) = &self.expiring_feeds {
+            expiring_feeds.set(&feed, false).await?;
+        }
+        self.metadata
+            .update_feeds(&index_path, |feeds| feeds.retain(|f| f != name))
+            .await?;
+        if let Some(subscriptions) = &self.subscriptions {
+            subscriptions
+                .update(|subscriptions| {
+                    subscriptions.retain(|s| !(s.user_id == user.id && s.feed == name))
+                })
+                .await?;
+        }
+        self.reply(m, format!("Фид {} удален", name)).await
+    }
+}
+
+impl PodcastHandler<'_> {
+    /// Moves feeds stored by the first name of the user before feeds were
+    /// keyed by the user id. Old feeds are deleted, so users resubscribe
+    /// with the new urls. First names can be changed by anyone, so only
+    /// the admin maps them to the user ids.
+    pub(crate) async fn migrate_feeds(&self, m: &Message, args: &[&str]) -> Result<()> {
+        let user = message_user(m)?;
+        if self.admin_id != Some(user.id) {
+            return self
+                .reply(
+                    m,
+                    "Перенос фидов доступен только администратору".to_string(),
+                )
+                .await;
+        }
+        let Some(user_id) = args
+            .first()
+            .and_then(|id| id.parse::<i32>().ok())
+            .filter(|_| args.len() > 1)
+        else {
+            return self.reply(m, MIGRATE_USAGE.to_string()).await;
+        };
+        let legacy_root = args[1..].join(" ");
+        let root = self.roots.user_root(user_id);
+
+        let legacy_feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&legacy_root))
+            .await?;
+        let mut migrated = vec![];
+        for name in std::iter::once(DEFAULT_FEED.to_string()).chain(legacy_feeds) {
+            let legacy_feed = Feed {
+                owner: legacy_root.to_string(),
+                root: legacy_root.to_string(),
+                name: name.to_string(),
+            };
+            let feed = Feed {
+                root: root.to_string(),
+                ..legacy_feed.clone()
+            };
+            if self
+                .storage
+                .download_object(&legacy_feed.metadata_path())
+                .await?
+                .is_none()
+            {
+                continue;
+            }
+            self.migrate_feed(&legacy_feed, &feed).await?;
+            if !feed.is_default() {
+                self.metadata
+                    .update_feeds(&feeds_index_path(&root), |feeds| {
+                        if !feeds.contains(&name) {
+                            feeds.push(name.to_string());
+                        }
+                    })
+                    .await?;
+            }
+            migrated.push(format!("{}: {}", name, self.feed_url(&feed).await?));
+        }
+        if migrated.is_empty() {
+            return self.reply(m, "Нет фидов для переноса".to_string()).await;
+        }
+        self.storage
+            .delete_object(&feeds_index_path(&legacy_root))
+            .await?;
+        // Private chat with the bot has the id of the user
+        if let Err(e) = self
+            .telegram_client
+            .async_send_message(SendMessage {
+                chat_id: user_id.to_string(),
+                text: format!("Фиды перенесены на новые адреса:\n{}", migrated.join("\n")),
+                reply_to_message_id: None,
+                reply_markup: None,
+            })
+            .await
+        {
+            warn!("Failed to send migrated feeds to {}: {:?}", user_id, e);
+        }
+        self.reply(m, format!("Перенесено фидов: {}", migrated.len()))
+            .await
+    }
+
+    async fn migrate_feed(&self, legacy_feed: &Feed, feed: &Feed) -> Result<()> {
+        let _legacy_guard = self.lock(&legacy_feed.metadata_path()).await;
+        let mut legacy_metadata = self
+            .metadata
+            .load_metadata(&legacy_feed.metadata_path())
+            .await?;
+        let mut legacy_paths = vec![];
+        let mut copied_paths = vec![];
+        for item in legacy_metadata.iter_mut() {
+            if let Some(legacy_path) = self.object_path(item) {
+                let file_name = legacy_path.rsplit('/').next().unwrap_or(&legacy_path);
+                let path = format!("{}/{}", feed.data_path(), file_name);
+                self.storage.copy_object(&legacy_path, &path).await?;
+                item.file_url = self.storage.get_public_url(&path);
+                item.file_path = path.to_string();
+                legacy_paths.push(legacy_path);
+                copied_paths.push(path);
+            }
+        }
+
+        let legacy_settings = self
+            .metadata
+            .load_settings(&legacy_feed.settings_path())
+            .await?;
+        self.metadata
+            .update_settings(&feed.settings_path(), |settings| {
+                if *settings == FeedSettings::default() {
+                    *settings = legacy_settings.clone();
+                }
+            })
+            .await?;
+        // Episodes added after the switch to the new root are newer
+        self.update_feed(feed, |metadata| {
+            metadata.extend(legacy_metadata.iter().cloned())
+        })
+        .await?;
+
+        // Copies of the episodes dropped by the retention aren't referenced
+        let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+        let kept_paths: HashSet<String> = metadata
+            .iter()
+            .flat_map(|i| self.object_paths(feed, i))
+            .collect();
+        for path in copied_paths.iter().filter(|p| !kept_paths.contains(*p)) {
+            self.storage.delete_object(path).await?;
+        }
+
+        // Old feed url is guessable from the name, so it must not lead to the new one
+        self.storage.delete_object(&legacy_feed.rss_path()).await?;
+        for path in legacy_paths {
+            self.storage.delete_object(&path).await?;
+        }
+        self.storage
+            .delete_object(&legacy_feed.settings_path())
+            .await?;
+        self.storage
+            .delete_object(&legacy_feed.metadata_path())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_simple_feed_names() {
+        assert!(is_valid_feed_name("lectures"));
+        assert!(is_valid_feed_name("talks-2024_en"));
+        assert!(is_valid_feed_name(&"a".repeat(MAX_FEED_NAME_LENGTH)));
+    }
+
+    #[test]
+    fn rejects_feed_names_unsafe_for_paths() {
+        assert!(!is_valid_feed_name(""));
+        assert!(!is_valid_feed_name(DEFAULT_FEED));
+        assert!(!is_valid_feed_name(&"a".repeat(MAX_FEED_NAME_LENGTH + 1)));
+        assert!(!is_valid_feed_name("Lectures"));
+        assert!(!is_valid_feed_name("лекции"));
+        assert!(!is_valid_feed_name("a/b"));
+        assert!(!is_valid_feed_name(".."));
+        assert!(!is_valid_feed_name("a:b"));
+        assert!(!is_valid_feed_name("a b"));
+    }
+
+    #[test]
+    fn finds_settings_of_object_feed() {
+        assert_eq!(
+            object_settings_path("root/audio/episode.m4a").as_deref(),
+            Some("root/settings.mp")
+        );
+        assert_eq!(
+            object_settings_path("root/feeds/talks/audio/episode.m4a").as_deref(),
+            Some("root/feeds/talks/settings.mp")
+        );
+        // Invalid names can't belong to a named feed
+        assert_eq!(
+            object_settings_path("root/feeds/../audio/episode.m4a").as_deref(),
+            Some("root/settings.mp")
+        );
+        assert_eq!(object_settings_path("root"), None);
+        assert_eq!(object_settings_path("/audio/episode.m4a"), None);
+    }
+}
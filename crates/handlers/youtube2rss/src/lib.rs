@@ -1,274 +1,590 @@
+mod attachments;
+mod audio;
+mod backup;
+mod chapters;
+mod episodes;
+mod feeds;
+mod local_storage;
 mod metadata;
+mod private_feeds;
+mod retention;
+mod rss_feed;
 mod s3_storage;
+mod search;
+mod sponsorblock;
+mod state_file;
+mod storage;
+mod subscriptions;
+mod transcripts;
 mod youtube_sdk;
+mod yt_dlp;
 
+use attachments::Attachment;
+use audio::AudioProcessor;
+use backup::{IMPORT_CALLBACK, IMPORT_USAGE};
+use feeds::{DEFAULT_FEED, Feed, FeedRoots, feeds_index_path};
 use handler_core::{AsyncHandler, HandlerContext};
-use s3_storage::S3Storage;
-use shlex::Shlex;
+use private_feeds::PrivateFeeds;
+use retention::ExpiringFeeds;
+use search::SearchIndex;
+use sponsorblock::SponsorBlock;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
-use std::{
-    collections::VecDeque, env, env::temp_dir, fs, path::PathBuf, process::Output, time::SystemTime,
+use std::{collections::VecDeque, env, env::temp_dir, path::PathBuf};
+use storage::PodcastStorage;
+use subscriptions::{Subscriptions, is_youtube_collection};
+use telegram_api::{
+    AnswerCallbackQuery, CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, Message,
+    SendMessage, TelegramClient, User,
 };
-use telegram_api::{Message, SendMessage, TelegramClient, User};
+use transcripts::{SpeechToText, Summarizer};
 use youtube_sdk::YoutubeSdk;
+use yt_dlp::YtDlp;
 
 use reqwest::Client;
 use reqwest::header::CONTENT_TYPE;
 
 use metadata::*;
 
-use regex::Regex;
-
-use rss::ChannelBuilder;
-use rss::{Enclosure, Item as RItem};
-
-use chrono::DateTime;
-use chrono::offset::Utc;
-
-use log::warn;
+use log::{error, warn};
 
 use anyhow::anyhow;
 use anyhow::{Context, Result};
 
 use async_trait::async_trait;
 
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+const FEED_CALLBACK_PREFIX: &str = "podcast_feed:";
+const REFETCH_CALLBACK_PREFIX: &str = "podcast_refetch:";
+/// Links waiting for the choice of a feed in the inline keyboard
+const PENDING_LINKS_LIMIT: usize = 20;
+const DEFAULT_SUBSCRIPTIONS_CHECK_MINUTES: u64 = 60;
+/// Extractors are matched by the name before the colon, e.g. twitch for twitch:vod
+const DEFAULT_EXTRACTORS: &str = "youtube,vimeo,soundcloud,twitch,bandcamp";
+
+fn message_user(m: &Message) -> Result<&User> {
+    m.from.as_ref().ok_or(anyhow!(
+        "Empty user of message. Can't manage podcasts for empty user"
+    ))
+}
 
-use tokio::process::Command;
+#[derive(Clone, Copy, Debug)]
+enum LinkKind {
+    /// Any page yt-dlp can extract audio from
+    Video,
+    Audio,
+    /// Playlist or channel, new videos of which are added to the feed
+    Subscription,
+    /// Audio, voice note or video sent to the bot, url is empty
+    Attachment,
+}
 
-fn metadata_path(user: &str) -> String {
-    format!("{}/metadata.mp", user)
+/// Episode with the same video id, link or content is already in the feed
+#[derive(Debug)]
+struct DuplicateEpisode {
+    name: String,
 }
 
-fn data_path(user: &str) -> String {
-    format!("{}/audio", user)
+impl std::fmt::Display for DuplicateEpisode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Episode {} is already in the feed", self.name)
+    }
 }
 
-fn rss_path(user: &str) -> String {
-    format!("{}/feed.xml", user)
+impl std::error::Error for DuplicateEpisode {}
+
+struct PendingLink {
+    message: Message,
+    url: String,
+    kind: LinkKind,
 }
 
 pub struct PodcastHandler<'a> {
-    youtube_extractor: String,
-    youtube_extractor_opts: Vec<String>,
-    youtube_sdk: YoutubeSdk,
-    id_regex: Regex,
+    yt_dlp: YtDlp,
+    extractors: Vec<String>,
+    audio_processor: Option<AudioProcessor<'a>>,
+    sponsorblock: SponsorBlock<'a>,
+    youtube_sdk: Option<YoutubeSdk>,
     tmp_dir: PathBuf,
-    s3_client: S3Storage,
-    metadata: Mutex<MetadataStorage>,
+    storage: Arc<dyn PodcastStorage + Send + Sync>,
+    metadata: MetadataStorage,
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    roots: FeedRoots,
+    /// The only user allowed to run /migrate
+    admin_id: Option<i32>,
+    pending_links: std::sync::Mutex<VecDeque<(String, PendingLink)>>,
+    subscriptions: Option<Subscriptions>,
+    subscriptions_check_interval: Duration,
+    expiring_feeds: Option<ExpiringFeeds>,
+    search: Option<SearchIndex>,
+    speech_to_text: Option<SpeechToText>,
+    summarizer: Option<Summarizer>,
+    private_feeds: Option<PrivateFeeds>,
     telegram_client: &'a TelegramClient<'a>,
     http_client: &'a Client,
 }
 
 impl<'a> PodcastHandler<'a> {
     pub fn new(handler_context: &'a HandlerContext) -> Self {
-        let youtube_extractor = {
-            env::var("YOUTUBE_EXTRACTOR")
-                .expect("Provide YOUTUBE_EXTRACTOR environment variable please")
-        };
-
-        let youtube_extractor_opts =
-            Shlex::new(&env::var("YOUTUBE_EXTRACTOR_OPTS").unwrap_or(String::from("")))
-                .collect::<Vec<String>>();
+        let subscriptions_check_minutes = env::var("PODCAST_SUBSCRIPTIONS_CHECK_MINUTES")
+            .ok()
+            .filter(|m| !m.is_empty())
+            .map(|m| {
+                m.parse::<u64>()
+                    .expect("PODCAST_SUBSCRIPTIONS_CHECK_MINUTES must be a number of minutes")
+            })
+            .unwrap_or(DEFAULT_SUBSCRIPTIONS_CHECK_MINUTES);
 
         let tmp_dir = temp_dir();
 
+        let storage = storage::from_env();
+
         Self {
-            youtube_extractor,
-            youtube_extractor_opts,
+            yt_dlp: YtDlp::new(),
+            extractors: env::var("PODCAST_EXTRACTORS")
+                .ok()
+                .filter(|e| !e.is_empty())
+                .unwrap_or(DEFAULT_EXTRACTORS.to_string())
+                .split(',')
+                .map(|e| e.trim().to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect(),
+            audio_processor: AudioProcessor::new(handler_context.async_proxy_http_client),
+            sponsorblock: SponsorBlock::new(handler_context.async_proxy_http_client),
             youtube_sdk: YoutubeSdk::new(),
-            id_regex: Regex::new(r"(v=|live/|youtu.be/)(?P<id>[^&?]*)")
-                .expect("Failed to compile video id Regex"),
             tmp_dir,
-            s3_client: S3Storage::new(),
-            metadata: Mutex::new(MetadataStorage::new()),
+            metadata: MetadataStorage::new(storage.clone()),
+            locks: std::sync::Mutex::new(HashMap::new()),
+            storage,
+            roots: FeedRoots::new(),
+            admin_id: env::var("PODCAST_ADMIN_ID")
+                .ok()
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse::<i32>()
+                        .expect("PODCAST_ADMIN_ID must be a telegram user id")
+                }),
+            pending_links: std::sync::Mutex::new(VecDeque::new()),
+            subscriptions: Subscriptions::new(),
+            subscriptions_check_interval: Duration::from_secs(subscriptions_check_minutes * 60),
+            expiring_feeds: ExpiringFeeds::new(),
+            search: SearchIndex::new(),
+            speech_to_text: SpeechToText::new(),
+            summarizer: Summarizer::new(),
+            private_feeds: PrivateFeeds::new(),
             telegram_client: handler_context.telegram_client,
             http_client: handler_context.async_proxy_http_client,
         }
     }
 
-    async fn process_mp3(&self, user: Option<&User>, url: String) -> Result<String> {
-        let username = &user
-            .ok_or(anyhow!(
-                "Empty user of message. Can't manage podcasts for empty user"
-            ))?
-            .first_name;
-        let content = self.http_client.get(&url).send().await?.bytes().await?;
-        let file_name = url
-            .split("/")
-            .last()
-            .ok_or(anyhow!("Can't extract mp3 file name"))?;
-
-        let s3_result_file_path: String = format!("{}/{}.mp3", data_path(&username), file_name);
-        self.s3_client
-            .upload_bytes(content.to_vec(), s3_result_file_path.to_string())
+    /// Adds episodes to the top of the feed and returns the feed url.
+    /// Previously added copies of the episodes are replaced.
+    async fn add_episodes(&self, feed: &Feed, items: Vec<VideoMetadata>) -> Result<String> {
+        let keys: Vec<EpisodeKeys> = items.iter().map(|i| i.keys()).collect();
+        self.update_feed(feed, |metadata| {
+            metadata.retain(|old| {
+                let old_keys = old.keys();
+                !keys.iter().any(|k| k.matches(&old_keys))
+            });
+            for item in &items {
+                metadata.push_front(item.clone());
+            }
+        })
+        .await?;
+        self.feed_url(feed).await
+    }
+
+    /// Serializes updates of the object inside of the process, so concurrent
+    /// messages don't waste conditional writes on each other
+    async fn lock(&self, path: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .expect("Feed locks are poisoned")
+            .entry(path.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Changes episodes of the feed, applies the retention policy, regenerates
+    /// the feed and deletes audio objects of removed episodes. The change can be
+    /// applied several times if the metadata is concurrently modified.
+    async fn update_feed<R>(
+        &self,
+        feed: &Feed,
+        mut change: impl FnMut(&mut VecDeque<VideoMetadata>) -> R,
+    ) -> Result<R> {
+        let _guard = self.lock(&feed.metadata_path()).await;
+        let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+        let update = self
+            .metadata
+            .update_metadata(&feed.metadata_path(), |metadata| {
+                let result = change(metadata);
+                settings.apply_retention(metadata);
+                result
+            })
+            .await?;
+        self.publish_feed(feed, &settings, &update.new).await?;
+        self.metadata
+            .store_index(&feed.index_path(), &EpisodeIndex::build(&update.new))
             .await?;
+        self.update_search(feed, &update.new).await;
+        if let Some(expiring_feeds) = &self.expiring_feeds
+            && let Err(e) = expiring_feeds
+                .set(feed, settings.max_age_days.is_some())
+                .await
+        {
+            warn!("Failed to register expiring feed {}: {:?}", feed.name, e);
+        }
 
-        let file_size = content.len() as u64;
+        let kept_urls: HashSet<&str> = update.new.iter().map(|i| i.file_url.as_str()).collect();
+        let kept_paths: HashSet<String> = update
+            .new
+            .iter()
+            .flat_map(|i| self.object_paths(feed, i))
+            .collect();
+        for item in update
+            .old
+            .iter()
+            .filter(|i| !kept_urls.contains(i.file_url.as_str()))
         {
-            let mp3_metadata = VideoMetadata {
-                file_size,
-                file_url: self.s3_client.get_public_url(&s3_result_file_path),
-                video_id: String::new(),
-                created_at: SystemTime::now(),
-                name: file_name.to_string(),
-                original_link: url,
-                mime_type: "audio/mp3".to_string(),
-            };
-            let metadta_storage = self.metadata.lock().await;
-            let metadata = metadta_storage
-                .add_metadata(&metadata_path(&username), mp3_metadata)
-                .await?;
-
-            let rss = Self::generate_rss(&username, &metadata)?;
-            self.s3_client
-                .upload_object(rss.into_bytes(), &rss_path(&username))
-                .await?;
-        };
+            if self.object_path(item).is_none() {
+                warn!("Can't find storage path of the audio {}", item.file_url);
+            }
+            // The same video could be added to the feed twice
+            for path in self
+                .object_paths(feed, item)
+                .into_iter()
+                .filter(|p| !kept_paths.contains(p))
+            {
+                if let Err(e) = self.storage.delete_object(&path).await {
+                    warn!("Failed to delete episode object {}: {:?}", path, e);
+                }
+            }
+        }
+        Ok(update.result)
+    }
 
-        Ok(self.s3_client.get_public_url(&rss_path(&username)))
+    /// Returns the name of the episode in the feed with any of the keys
+    async fn find_duplicate(&self, feed: &Feed, keys: &EpisodeKeys) -> Result<Option<String>> {
+        let index = match self.metadata.load_index(&feed.index_path()).await? {
+            Some(index) => index,
+            // Feeds created before the index get it with the first lookup
+            None => {
+                let _guard = self.lock(&feed.metadata_path()).await;
+                let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+                let index = EpisodeIndex::build(&metadata);
+                if !metadata.is_empty() {
+                    self.metadata
+                        .store_index(&feed.index_path(), &index)
+                        .await?;
+                }
+                index
+            }
+        };
+        Ok(index.find(keys).map(|name| name.to_string()))
     }
 
-    async fn process_url(
+    async fn publish_feed(
         &self,
-        url: &str,
-        user: Option<&User>,
-        message_id: i64,
-        extension: String,
-    ) -> Result<String> {
-        let username = &user
-            .ok_or(anyhow!(
-                "Empty user of message. Can't manage podcasts for empty user"
-            ))?
-            .first_name;
-        let video_id = self.extract_id(url)?;
-        let download_path = self
-            .tmp_dir
-            .join(format!("{}{}", message_id, "%(id)s.%(ext)s"))
-            .to_str()
-            .expect("Failed to convert to string file path")
-            .to_string();
-        let downloaded_file_path: PathBuf =
-            String::from_utf8(self.download(url, &download_path).await?.stdout)
-                .with_context(|| "Can't stringify download path")?
-                .trim()
-                .into();
-
-        let file_size = {
-            let metadata = fs::metadata(
-                downloaded_file_path
-                    .to_str()
-                    .ok_or(anyhow!("Failed to lossy convert file path"))?,
-            )?;
-            metadata.len()
+        feed: &Feed,
+        settings: &FeedSettings,
+        metadata: &VecDeque<VideoMetadata>,
+    ) -> Result<()> {
+        let feed_url = self.object_url(settings, &feed.rss_path());
+        // Metadata keeps public urls, signed ones change with the feed secret
+        let signed: VecDeque<VideoMetadata>;
+        let metadata = match &self.private_feeds {
+            Some(_) => {
+                signed = metadata
+                    .iter()
+                    .map(|i| self.sign_episode(settings, i))
+                    .collect();
+                &signed
+            }
+            None => metadata,
         };
+        let rss = rss_feed::generate_rss(&feed.display_name(), &feed_url, settings, metadata)?;
+        self.storage
+            .upload_object(rss.into_bytes(), &feed.rss_path())
+            .await
+    }
 
-        let s3_result_file_path = format!("{}/{}.{}", data_path(&username), &video_id, extension);
-        let upload_result = self
-            .s3_client
-            .upload_file(
-                downloaded_file_path.to_path_buf(),
-                s3_result_file_path.to_string(),
-            )
-            .await;
-
-        if let Err(e) = fs::remove_file(&downloaded_file_path) {
-            warn!(
-                "Failed to remove temporary file {}: {}",
-                downloaded_file_path.display(),
-                e
-            );
-        }
+    /// Audio and the other objects uploaded for the episode. Only objects of
+    /// the feed are returned, so imported urls can't point deletes elsewhere.
+    fn object_paths(&self, feed: &Feed, item: &VideoMetadata) -> Vec<String> {
+        let data_path = format!("{}/", feed.data_path());
+        self.object_path(item)
+            .into_iter()
+            .chain(item.chapters_path.clone())
+            .chain(item.transcript_path.clone())
+            .filter(|p| p.starts_with(&data_path))
+            .collect()
+    }
 
-        upload_result?;
-
-        if let Some(video_info) = self.youtube_sdk.get_video_info(&video_id).await? {
-            let video_metadata = VideoMetadata {
-                file_size,
-                file_url: self.s3_client.get_public_url(&s3_result_file_path),
-                video_id: video_id.to_string(),
-                created_at: SystemTime::now(),
-                name: video_info.title,
-                original_link: url.to_string(),
-                mime_type: format!("audio/{}", extension),
-            };
+    fn object_path(&self, item: &VideoMetadata) -> Option<String> {
+        if item.file_path.is_empty() {
+            item.file_url
+                .strip_prefix(&self.storage.get_public_url(""))
+                .map(|p| p.to_string())
+        } else {
+            Some(item.file_path.to_string())
+        }
+    }
 
-            {
-                let metadata_storage = self.metadata.lock().await;
-                let metadata = metadata_storage
-                    .add_metadata(&metadata_path(&username), video_metadata)
-                    .await?;
-
-                let rss = Self::generate_rss(&username, &metadata)?;
-                self.s3_client
-                    .upload_object(rss.into_bytes(), &rss_path(&username))
-                    .await?;
-            };
+    async fn process_command(&self, m: &Message, text: &str) -> Result<()> {
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let mut args: Vec<&str> = words.collect();
+        match command {
+            "/feeds" => return self.list_feeds(m).await,
+            "/newfeed" => return self.create_feed(m, args.first().copied()).await,
+            "/deletefeed" => return self.delete_feed(m, args.first().copied()).await,
+            "/migrate" => return self.migrate_feeds(m, &args).await,
+            "/subscriptions" => return self.list_subscriptions(m).await,
+            "/unsubscribe" => return self.unsubscribe(m, args.first().copied()).await,
+            "/export" => return self.export_feeds(m).await,
+            "/find" => return self.find_episodes(m, &args.join(" ")).await,
+            "/import" => {
+                return match &m.reply_to_message {
+                    Some(file_message) if file_message.document.is_some() => {
+                        self.import_file(m, file_message, false).await
+                    }
+                    _ => self.reply(m, IMPORT_USAGE.to_string()).await,
+                };
+            }
+            "/episodes" | "/delete" | "/move" | "/retention" | "/feedinfo" | "/audio"
+            | "/sponsorblock" | "/split" | "/transcripts" | "/transcript" | "/rotate" => {}
+            _ => return Ok(()),
+        }
 
-            Ok(self.s3_client.get_public_url(&rss_path(&username)))
-        } else {
-            Err(anyhow!("Received empty video info about {}", url))
+        // Feed is chosen by #name right after the command, default feed otherwise
+        let feed_name = args.first().copied().and_then(|a| a.strip_prefix('#'));
+        if feed_name.is_some() {
+            args.remove(0);
+        }
+        let Some(feed) = self.find_feed(m, feed_name).await? else {
+            return Ok(());
+        };
+        match (command, args.first().copied(), args.get(1).copied()) {
+            ("/episodes", _, _) => self.list_episodes(m, &feed).await,
+            ("/delete", Some(n), _) => self.delete_episode(m, &feed, n).await,
+            ("/move", Some(from), Some(to)) => self.move_episode(m, &feed, from, to).await,
+            ("/retention", policy, value) => self.set_retention(m, &feed, policy, value).await,
+            ("/feedinfo", _, _) => self.set_feed_info(m, &feed, &args.join(" ")).await,
+            ("/audio", option, value) => self.set_audio(m, &feed, option, value).await,
+            ("/sponsorblock", _, _) => self.set_sponsorblock(m, &feed, &args).await,
+            ("/split", minutes, mode) => self.set_split(m, &feed, minutes, mode).await,
+            ("/transcripts", value, _) => self.set_transcripts(m, &feed, value).await,
+            ("/transcript", Some(n), _) => self.send_transcript(m, &feed, n).await,
+            ("/rotate", _, _) => self.rotate_secret(m, &feed).await,
+            _ => Ok(()),
         }
     }
 
-    fn generate_rss(user: &str, metadata: &VecDeque<VideoMetadata>) -> Result<String> {
-        let mut items = vec![];
-        for item in metadata {
-            let pub_date: DateTime<Utc> = item.created_at.into();
-            let mut ritem = RItem::default();
-            ritem.set_title(item.name.to_string());
-            ritem.set_pub_date(pub_date.to_rfc2822());
-            let mut enc = Enclosure::default();
-            enc.set_mime_type(item.mime_type.to_string());
-            enc.set_url(item.file_url.to_string());
-            enc.set_length(item.file_size.to_string());
-            ritem.set_enclosure(enc);
-            items.push(ritem)
+    /// Adds the link to the feed from the message or asks the user to choose one
+    async fn add_link(
+        &self,
+        m: &Message,
+        url: &str,
+        kind: LinkKind,
+        feed_name: Option<&str>,
+    ) -> Result<()> {
+        if feed_name.is_some() {
+            return match self.find_feed(m, feed_name).await? {
+                Some(feed) => self.process_link(m, url, kind, &feed, false).await,
+                None => Ok(()),
+            };
+        }
+
+        let user = message_user(m)?;
+        let feeds = self
+            .metadata
+            .load_feeds(&feeds_index_path(&self.roots.root(user)))
+            .await?;
+        if feeds.is_empty() {
+            return self
+                .process_link(m, url, kind, &self.roots.feed(user, DEFAULT_FEED), false)
+                .await;
         }
 
-        let channel = ChannelBuilder::default()
-            .title(format!("Куточок {}", user))
-            .items(items)
-            .build();
-        Ok(channel.to_string())
+        self.remember_link(m, url, kind);
+        let inline_keyboard = std::iter::once(DEFAULT_FEED.to_string())
+            .chain(feeds)
+            .map(|name| {
+                vec![InlineKeyboardButton {
+                    callback_data: format!("{}{}:{}", FEED_CALLBACK_PREFIX, m.message_id, name),
+                    text: name,
+                }]
+            })
+            .collect();
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: m.chat.id.to_string(),
+                text: "В какой фид добавить?".to_string(),
+                reply_to_message_id: Some(&m.message_id),
+                reply_markup: Some(InlineKeyboardMarkup { inline_keyboard }),
+            })
+            .await
     }
 
-    async fn download(&self, url: &str, path: &str) -> Result<Output> {
-        let res = Command::new(&self.youtube_extractor)
-            .args(&self.youtube_extractor_opts)
-            .args(&["-f", "bestaudio[ext=m4a]"])
-            .args(&["-o", path])
-            .args(&["--print", "after_move:filepath"])
-            .arg(url)
-            .output()
+    /// Keeps the link until the user presses a button of the reply
+    fn remember_link(&self, m: &Message, url: &str, kind: LinkKind) {
+        let mut pending_links = self
+            .pending_links
+            .lock()
+            .expect("Pending links lock is poisoned");
+        let key = format!("{}:{}", m.chat.id, m.message_id);
+        pending_links.retain(|(k, _)| *k != key);
+        pending_links.push_front((
+            key,
+            PendingLink {
+                message: m.clone(),
+                url: url.to_string(),
+                kind,
+            },
+        ));
+        pending_links.truncate(PENDING_LINKS_LIMIT);
+    }
+
+    /// Replies that the episode is already in the feed with the button to add it again
+    async fn offer_refetch(
+        &self,
+        m: &Message,
+        url: &str,
+        kind: LinkKind,
+        feed: &Feed,
+        name: &str,
+    ) -> Result<()> {
+        self.remember_link(m, url, kind);
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: m.chat.id.to_string(),
+                text: format!("Эпизод {} уже есть в фиде {}", name, feed.name),
+                reply_to_message_id: Some(&m.message_id),
+                reply_markup: Some(InlineKeyboardMarkup {
+                    inline_keyboard: vec![vec![InlineKeyboardButton {
+                        text: "Загрузить заново".to_string(),
+                        callback_data: format!(
+                            "{}{}:{}",
+                            REFETCH_CALLBACK_PREFIX, m.message_id, feed.name
+                        ),
+                    }]],
+                }),
+            })
             .await
-            .with_context(|| {
-                format!(
-                    "Failed to execute the yt-dlp command to download url {}",
-                    url
-                )
-            })?;
-        if res.status.success() {
-            Ok(res)
-        } else {
-            Err(anyhow!(
-                "Exit code of youtube-dl command was not 0, output: {:?}",
-                res
-            ))
-        }
     }
 
-    fn extract_id(&self, s: &str) -> Result<String> {
-        self.id_regex
-            .captures(s)
-            .and_then(|cap| {
-                if let Some(id) = cap.name("id") {
-                    Some(id.as_str().to_string())
+    /// Adds the link chosen by the button, force adds it even if it's already in the feed
+    async fn add_pending_link(
+        &self,
+        q: &CallbackQuery,
+        message: &Message,
+        data: &str,
+        force: bool,
+    ) -> Result<()> {
+        let (link_message_id, feed_name) = data
+            .split_once(':')
+            .ok_or(anyhow!("Malformed feed callback data {}", data))?;
+        let key = format!("{}:{}", message.chat.id, link_message_id);
+        let pending = {
+            let mut pending_links = self
+                .pending_links
+                .lock()
+                .expect("Pending links lock is poisoned");
+            pending_links
+                .iter()
+                .position(|(k, _)| *k == key)
+                .and_then(|i| pending_links.remove(i))
+                .map(|(_, link)| link)
+        };
+
+        let Some(pending) = pending else {
+            return self
+                .telegram_client
+                .async_answer_callback_query(AnswerCallbackQuery {
+                    callback_query_id: &q.id,
+                    text: Some("Ссылка устарела, отправьте ее еще раз".to_string()),
+                })
+                .await;
+        };
+        self.telegram_client
+            .async_answer_callback_query(AnswerCallbackQuery {
+                callback_query_id: &q.id,
+                text: Some(if force {
+                    format!("Загружаю заново в фид {}", feed_name)
                 } else {
-                    None
-                }
+                    format!("Добавляю в фид {}", feed_name)
+                }),
+            })
+            .await?;
+        match self.find_feed(&pending.message, Some(feed_name)).await? {
+            Some(feed) => {
+                self.process_link(&pending.message, &pending.url, pending.kind, &feed, force)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn process_link(
+        &self,
+        m: &Message,
+        url: &str,
+        kind: LinkKind,
+        feed: &Feed,
+        force: bool,
+    ) -> Result<()> {
+        if !force && let Some(name) = self.find_duplicate(feed, &link_keys(m, url, kind)).await? {
+            return self.offer_refetch(m, url, kind, feed, &name).await;
+        }
+        let result = match kind {
+            LinkKind::Video => self.process_url(url, feed, m.message_id, force).await,
+            LinkKind::Audio => self
+                .process_mp3(feed, url.to_string(), force)
+                .await
+                .map(|rss_feed_url| (rss_feed_url, vec![])),
+            LinkKind::Subscription => return self.subscribe(m, url, feed).await,
+            LinkKind::Attachment => self.process_attachment(m, feed, force).await,
+        };
+        let (rss_feed_url, episodes) = match result {
+            Ok(added) => added,
+            Err(e) => {
+                return match e.downcast_ref::<DuplicateEpisode>() {
+                    Some(duplicate) => {
+                        self.offer_refetch(m, url, kind, feed, &duplicate.name)
+                            .await
+                    }
+                    None => Err(e),
+                };
+            }
+        };
+        let mut notes = vec![];
+        if let Some(secs) = episodes.first().and_then(|e| e.removed_secs) {
+            notes.push(format!(
+                "SponsorBlock вырезал {}",
+                rss_feed::format_duration(secs)
+            ));
+        }
+        if episodes.len() > 1 {
+            notes.push(format!("Запись разделена на {} эпизодов", episodes.len()));
+        }
+        self.send_success_message(&m.chat.id.to_string(), m.message_id, &rss_feed_url, &notes)
+            .await
+    }
+
+    async fn reply(&self, m: &Message, text: String) -> Result<()> {
+        self.telegram_client
+            .async_send_message(SendMessage {
+                chat_id: m.chat.id.to_string(),
+                text,
+                reply_to_message_id: Some(&m.message_id),
+                reply_markup: None,
             })
-            .ok_or(anyhow!("Can't parse video id from youtube url {}", s))
+            .await
     }
 
     async fn send_success_message(
@@ -276,19 +592,35 @@ impl<'a> PodcastHandler<'a> {
         chat_id: &str,
         message_id: i64,
         rss_feed_url: &str,
+        notes: &[String],
     ) -> Result<()> {
+        let text = std::iter::once(format!(
+            "RSS фид успешно обновлен и доступен по адресу: {}",
+            rss_feed_url
+        ))
+        .chain(notes.iter().cloned())
+        .collect::<Vec<String>>()
+        .join("\n");
         self.telegram_client
             .async_send_message(SendMessage {
                 chat_id: chat_id.to_string(),
-                text: format!(
-                    "RSS фид успешно обновлен и доступен по адресу: {}",
-                    rss_feed_url
-                ),
+                text,
                 reply_to_message_id: Some(&message_id),
+                reply_markup: None,
             })
             .await
     }
 
+    /// Single videos of allowed sites, yt-dlp is asked which extractor handles the url
+    async fn is_supported_video(&self, url: &str) -> bool {
+        match self.yt_dlp.url_info(url).await {
+            Some(info) => {
+                !info.is_playlist() && is_allowed_extractor(&self.extractors, &info.extractor)
+            }
+            None => false,
+        }
+    }
+
     async fn is_audio(&self, url: &str) -> Result<bool> {
         match self
             .http_client
@@ -312,27 +644,199 @@ impl<'a> AsyncHandler for PodcastHandler<'a> {
         String::from("Youtube2Rss")
     }
 
+    async fn run_background(&self) -> Result<()> {
+        // Subscriptions are checked even if the local storage server is down
+        let serve = async {
+            if let Err(e) = self.storage.serve().await {
+                error!("Podcast storage server failed: {:?}", e);
+            }
+        };
+        let serve_private = async {
+            if let Some(private_feeds) = &self.private_feeds
+                && let Err(e) = private_feeds.serve(self.storage.clone()).await
+            {
+                error!("Private podcast feeds server failed: {:?}", e);
+            }
+        };
+        let (_, _, check, expire) = tokio::join!(
+            serve,
+            serve_private,
+            self.check_subscriptions(),
+            self.expire_episodes()
+        );
+        check.and(expire)
+    }
+
     async fn process(&self, m: &Message) -> Result<()> {
-        match m {
-            Message { text: Some(s), .. }
-                if s.starts_with("https://www.youtube.com/watch")
-                    || s.starts_with("https://www.youtube.com/live")
-                    || s.starts_with("https://youtu.be/") =>
+        let Some(text) = &m.text else {
+            if m.document.is_some()
+                && m.caption
+                    .as_deref()
+                    .is_some_and(|c| c.trim_start().starts_with("/import"))
             {
-                let rss_feed_url = self
-                    .process_url(s, m.from.as_ref(), m.message_id, "m4a".to_string())
-                    .await?;
-                self.send_success_message(&m.chat.id.to_string(), m.message_id, &rss_feed_url)
+                return self.import_file(m, m, false).await;
+            }
+            return self.add_attachment(m).await;
+        };
+        if text.starts_with('/') {
+            return self.process_command(m, text).await;
+        }
+
+        // Link can be followed by the name of the target feed: URL name or URL #name
+        let mut words = text.split_whitespace();
+        let (Some(url), feed_name) = (words.next(), words.next()) else {
+            return Ok(());
+        };
+        let feed_name = feed_name.map(|f| f.trim_start_matches('#'));
+        if is_youtube_collection(url) {
+            self.add_link(m, url, LinkKind::Subscription, feed_name)
+                .await
+        } else if !url.starts_with("http") {
+            Ok(())
+        } else if is_youtube_video(url) {
+            if is_allowed_extractor(&self.extractors, "youtube") {
+                self.add_link(m, url, LinkKind::Video, feed_name).await
+            } else {
+                Ok(())
+            }
+        } else if url.ends_with(".mp3") || self.is_audio(url).await? {
+            self.add_link(m, url, LinkKind::Audio, feed_name).await
+        } else if self.is_supported_video(url).await {
+            self.add_link(m, url, LinkKind::Video, feed_name).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn process_callback(&self, q: &CallbackQuery) -> Result<()> {
+        match (&q.message, &q.data) {
+            (Some(m), Some(data)) if data.starts_with(FEED_CALLBACK_PREFIX) => {
+                self.add_pending_link(q, m, &data[FEED_CALLBACK_PREFIX.len()..], false)
                     .await
             }
-            Message { text: Some(s), .. }
-                if s.starts_with("http") && (s.ends_with(".mp3") || self.is_audio(s).await?) =>
-            {
-                let rss_feed_url = self.process_mp3(m.from.as_ref(), s.clone()).await?;
-                self.send_success_message(&m.chat.id.to_string(), m.message_id, &rss_feed_url)
+            (Some(m), Some(data)) if data.starts_with(REFETCH_CALLBACK_PREFIX) => {
+                self.add_pending_link(q, m, &data[REFETCH_CALLBACK_PREFIX.len()..], true)
                     .await
             }
+            (Some(m), Some(data)) if data == IMPORT_CALLBACK => self.apply_import(q, m).await,
             _ => Ok(()),
         }
     }
 }
+
+/// Keys of the link known before downloading it
+fn link_keys(m: &Message, url: &str, kind: LinkKind) -> EpisodeKeys {
+    match kind {
+        LinkKind::Attachment => EpisodeKeys {
+            id: Attachment::from_message(m).map(|a| format!("telegram:{}", a.unique_id)),
+            ..Default::default()
+        },
+        _ => EpisodeKeys {
+            id: youtube_video_id(url),
+            link: Some(url.to_string()),
+            hash: None,
+        },
+    }
+}
+
+/// Id of the video from youtube.com/watch?v=, youtu.be, /live and /shorts links
+fn youtube_video_id(url: &str) -> Option<String> {
+    if !is_youtube_video(url) {
+        return None;
+    }
+    let (_, rest) = ["youtu.be/", "/live/", "/shorts/", "v="]
+        .iter()
+        .find_map(|p| url.split_once(p))?;
+    let id = rest.split(['?', '&', '#', '/']).next()?;
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+fn is_youtube_video(url: &str) -> bool {
+    url.starts_with("https://www.youtube.com/watch")
+        || url.starts_with("https://www.youtube.com/live")
+        || url.starts_with("https://www.youtube.com/shorts/")
+        || url.starts_with("https://youtu.be/")
+}
+
+/// Extractors are allowed by the full name like twitch:vod or by the site name
+fn is_allowed_extractor(extractors: &[String], extractor: &str) -> bool {
+    let extractor = extractor.to_lowercase();
+    let name = extractor.split(':').next().unwrap_or(&extractor);
+    extractors.iter().any(|e| *e == extractor || e == name)
+}
+
+/// Episode ids of other sites contain the extractor name like twitch:vod:123
+fn storage_file_name(episode_id: &str) -> String {
+    episode_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Converts 1-based episode number from the user to the index in the feed
+fn parse_index(n: &str, len: usize) -> Option<usize> {
+    n.parse::<usize>()
+        .ok()
+        .filter(|n| (1..=len).contains(n))
+        .map(|n| n - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_youtube_video_ids() {
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42s",
+            "https://www.youtube.com/watch?feature=share&v=dQw4w9WgXcQ",
+            "https://www.youtube.com/live/dQw4w9WgXcQ?si=abc",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=42",
+            "https://youtu.be/dQw4w9WgXcQ#comments",
+        ] {
+            assert_eq!(
+                youtube_video_id(url).as_deref(),
+                Some("dQw4w9WgXcQ"),
+                "{}",
+                url
+            );
+        }
+        assert_eq!(youtube_video_id("https://www.youtube.com/watch?v="), None);
+        assert_eq!(
+            youtube_video_id("https://www.youtube.com/@channel/videos"),
+            None
+        );
+        assert_eq!(youtube_video_id("https://vimeo.com/123?v=abc"), None);
+    }
+
+    #[test]
+    fn allows_extractors_by_site_name() {
+        let extractors: Vec<String> = ["youtube", "twitch:vod"]
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert!(is_allowed_extractor(&extractors, "youtube"));
+        assert!(is_allowed_extractor(&extractors, "YouTube"));
+        assert!(is_allowed_extractor(&extractors, "youtube:tab"));
+        assert!(is_allowed_extractor(&extractors, "twitch:vod"));
+        assert!(!is_allowed_extractor(&extractors, "twitch:clips"));
+        assert!(!is_allowed_extractor(&extractors, "vimeo"));
+        assert!(!is_allowed_extractor(&extractors, "generic"));
+    }
+
+    #[test]
+    fn makes_storage_file_names_safe() {
+        assert_eq!(storage_file_name("dQw4w9WgXcQ"), "dQw4w9WgXcQ");
+        assert_eq!(storage_file_name("twitch:vod:v123"), "twitch_vod_v123");
+        assert_eq!(storage_file_name("../../etc/passwd"), "______etc_passwd");
+        assert_eq!(storage_file_name("видео 1"), "______1");
+    }
+}
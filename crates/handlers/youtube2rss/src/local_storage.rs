@@ -0,0 +1,313 @@
+use super::private_feeds::{query_param, sign, verify};
+use super::storage::PodcastStorage;
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use log::info;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tower_http::services::ServeDir;
+
+/// Keeps objects in a local directory and serves them with the bot's own
+/// http server, so no external object storage is required.
+/// Conditional writes are serialized inside of the process only,
+/// so the directory must not be shared by several bot instances.
+/// With private feeds only presigned urls are served.
+pub struct LocalStorage {
+    root: PathBuf,
+    listen_address: String,
+    public_url: String,
+    /// Key of the presigned urls, set only with private feeds
+    secret: Option<String>,
+    conditional_writes: Mutex<()>,
+}
+
+impl LocalStorage {
+    pub fn new() -> Self {
+        let root = PathBuf::from(
+            env::var("PODCAST_LOCAL_STORAGE_PATH")
+                .expect("Provide PODCAST_LOCAL_STORAGE_PATH environment variable please"),
+        );
+        let listen_address = env::var("PODCAST_HTTP_LISTEN")
+            .expect("Provide PODCAST_HTTP_LISTEN environment variable please");
+        let public_url = env::var("PODCAST_PUBLIC_URL")
+            .expect("Provide PODCAST_PUBLIC_URL environment variable please")
+            .trim_end_matches('/')
+            .to_string();
+        let secret = env::var("PODCAST_PRIVATE_URL")
+            .is_ok_and(|u| !u.is_empty())
+            .then(|| {
+                env::var("PODCAST_FEED_SECRET").expect(
+                    "Provide PODCAST_FEED_SECRET environment variable please, \
+                     it is required when PODCAST_PRIVATE_URL is set",
+                )
+            });
+        Self {
+            root,
+            listen_address,
+            public_url,
+            secret,
+            conditional_writes: Mutex::new(()),
+        }
+    }
+
+    /// Object paths are built from user input, so they must not leave the root
+    fn file_path(&self, path: &str) -> Result<PathBuf> {
+        let relative = Path::new(path);
+        if !path.is_empty()
+            && relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            Ok(self.root.join(relative))
+        } else {
+            Err(anyhow!("Object path '{}' is not allowed", path))
+        }
+    }
+
+    async fn prepare_parent(&self, file_path: &Path) -> Result<()> {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PodcastStorage for LocalStorage {
+    async fn download_object(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.file_path(path)?).await {
+            Ok(d) => Ok(Some(d)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read the object {}", path)),
+        }
+    }
+
+    async fn upload_object(&self, data: Vec<u8>, path: &str) -> Result<()> {
+        let file_path = self.file_path(path)?;
+        self.prepare_parent(&file_path).await?;
+        // Readers must never see a partially written feed or metadata,
+        // concurrent uploads of the same object don't share the temporary file
+        let mut tmp_path = file_path.clone().into_os_string();
+        tmp_path.push(format!(".{}.tmp", hex::encode(rand::random::<[u8; 8]>())));
+        fs::write(&tmp_path, data)
+            .await
+            .with_context(|| format!("Failed to write the object {}", path))?;
+        fs::rename(&tmp_path, &file_path)
+            .await
+            .with_context(|| format!("Failed to move the object {} in place", path))
+    }
+
+    async fn download_versioned(&self, path: &str) -> Result<Option<(Vec<u8>, String)>> {
+        Ok(self.download_object(path).await?.map(|data| {
+            let version = hex::encode(Sha256::digest(&data));
+            (data, version)
+        }))
+    }
+
+    async fn upload_if_version(
+        &self,
+        data: Vec<u8>,
+        path: &str,
+        version: Option<&str>,
+    ) -> Result<bool> {
+        let _guard = self.conditional_writes.lock().await;
+        let current = self.download_versioned(path).await?;
+        if current.as_ref().map(|(_, v)| v.as_str()) != version {
+            return Ok(false);
+        }
+        self.upload_object(data, path).await?;
+        Ok(true)
+    }
+
+    async fn upload_file(&self, file: PathBuf, path: String) -> Result<()> {
+        let file_path = self.file_path(&path)?;
+        self.prepare_parent(&file_path).await?;
+        fs::copy(&file, &file_path).await.with_context(|| {
+            format!(
+                "Failed to copy file {} to the path {}",
+                file.display(),
+                path
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn copy_object(&self, from: &str, to: &str) -> Result<()> {
+        let to_path = self.file_path(to)?;
+        self.prepare_parent(&to_path).await?;
+        fs::copy(self.file_path(from)?, &to_path)
+            .await
+            .with_context(|| format!("Failed to copy the object {} to {}", from, to))?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, path: &str) -> Result<()> {
+        match fs::remove_file(self.file_path(path)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to delete the object {}", path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_public_url(&self, path: &str) -> String {
+        format!("{}/{}", self.public_url, path)
+    }
+
+    async fn get_presigned_url(&self, path: &str, expires_in: Duration) -> Result<String> {
+        let secret = self.secret.as_ref().ok_or(anyhow!(
+            "Presigned urls are served only with PODCAST_PRIVATE_URL"
+        ))?;
+        let expires = (SystemTime::now() + expires_in)
+            .duration_since(UNIX_EPOCH)?
+            .as_secs();
+        Ok(format!(
+            "{}/{}?expires={}&signature={}",
+            self.public_url,
+            path,
+            expires,
+            sign(secret, &format!("{}:{}", expires, path))
+        ))
+    }
+
+    async fn serve(&self) -> Result<()> {
+        fs::create_dir_all(&self.root)
+            .await
+            .with_context(|| format!("Failed to create directory {}", self.root.display()))?;
+        let listener = TcpListener::bind(&self.listen_address)
+            .await
+            .with_context(|| format!("Failed to listen on {}", self.listen_address))?;
+        info!(
+            "Serving podcasts from {} on {}",
+            self.root.display(),
+            self.listen_address
+        );
+        let mut app = Router::new().fallback_service(ServeDir::new(&self.root));
+        if let Some(secret) = &self.secret {
+            app = app.layer(middleware::from_fn_with_state(
+                secret.to_string(),
+                check_signature,
+            ));
+        }
+        axum::serve(listener, app)
+            .await
+            .with_context(|| "Podcast http server failed")
+    }
+}
+
+/// Rejects urls, which are not presigned or are expired
+async fn check_signature(State(secret): State<String>, request: Request, next: Next) -> Response {
+    let query = request.uri().query();
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/')).decode_utf8();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let valid = match (
+        path,
+        query_param(query, "expires"),
+        query_param(query, "signature"),
+    ) {
+        (Ok(path), Some(expires), Some(signature)) => {
+            expires.parse::<u64>().is_ok_and(|e| e > now)
+                && verify(&secret, &format!("{}:{}", expires, path), signature)
+        }
+        _ => false,
+    };
+    if valid {
+        next.run(request).await
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(root: &Path, secret: Option<&str>) -> LocalStorage {
+        LocalStorage {
+            root: root.to_path_buf(),
+            listen_address: "127.0.0.1:0".to_string(),
+            public_url: "https://podcasts.example.com".to_string(),
+            secret: secret.map(|s| s.to_string()),
+            conditional_writes: Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn keeps_object_paths_inside_of_the_root() {
+        let storage = storage(Path::new("/srv/podcasts"), None);
+        assert_eq!(
+            storage.file_path("root/audio/abc.m4a").unwrap(),
+            PathBuf::from("/srv/podcasts/root/audio/abc.m4a")
+        );
+        for path in [
+            "",
+            "../etc/passwd",
+            "root/../../etc/passwd",
+            "/etc/passwd",
+            "//etc/passwd",
+        ] {
+            assert!(storage.file_path(path).is_err(), "{}", path);
+        }
+        // Names starting like a parent or the root are plain names
+        assert!(storage.file_path("..feed.xml").is_ok());
+        assert!(storage.file_path("root/...").is_ok());
+        assert_eq!(
+            storage.file_path("root/./feed.xml").unwrap(),
+            PathBuf::from("/srv/podcasts/root/feed.xml")
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_objects_without_leftovers() {
+        let root = env::temp_dir().join(format!(
+            "local-storage-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let storage = storage(&root, None);
+
+        storage
+            .upload_object(b"first".to_vec(), "root/feed.xml")
+            .await
+            .unwrap();
+        storage
+            .upload_object(b"second".to_vec(), "root/feed.xml")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.download_object("root/feed.xml").await.unwrap(),
+            Some(b"second".to_vec())
+        );
+        let mut entries = fs::read_dir(root.join("root")).await.unwrap();
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        assert_eq!(names, ["feed.xml"]);
+
+        // Presigned urls need the secret of private feeds
+        assert!(
+            storage
+                .get_presigned_url("root/feed.xml", Duration::from_secs(60))
+                .await
+                .is_err()
+        );
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -1,12 +1,21 @@
-use super::s3_storage::S3Storage;
-use anyhow::{Context, Result};
+use super::audio::AudioSettings;
+use super::sponsorblock;
+use super::storage::PodcastStorage;
+use anyhow::{Context, Result, anyhow};
+use log::warn;
 use rmp_serde;
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::time::SystemTime;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Version 0 is the bare list of episodes encoded as an array,
+/// later versions are maps with the version and the episodes
+pub const METADATA_VERSION: u32 = 1;
+const MAX_UPDATE_ATTEMPTS: u32 = 10;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoMetadata {
     pub file_size: u64,
     pub file_url: String,
@@ -16,60 +25,616 @@ pub struct VideoMetadata {
     pub original_link: String,
     #[serde(default = "default_mime_type")]
     pub mime_type: String,
+    /// Path of the audio object in the storage, empty for old items,
+    /// which only have the public url
+    #[serde(default)]
+    pub file_path: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub thumbnail_url: Option<String>,
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    /// Upload date of the original video, created_at is the time it was added to the feed
+    #[serde(default)]
+    pub published_at: Option<SystemTime>,
+    /// Duration of SponsorBlock segments cut out of the audio
+    #[serde(default)]
+    pub removed_secs: Option<u64>,
+    /// Podcasting 2.0 JSON chapters uploaded next to the audio
+    #[serde(default)]
+    pub chapters_url: Option<String>,
+    #[serde(default)]
+    pub chapters_path: Option<String>,
+    /// Sha256 of the downloaded audio
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// WebVTT transcript uploaded next to the audio
+    #[serde(default)]
+    pub transcript_url: Option<String>,
+    #[serde(default)]
+    pub transcript_path: Option<String>,
+}
+
+impl VideoMetadata {
+    /// Parts of a split recording share the id of the recording
+    pub fn base_id(&self) -> &str {
+        match self.video_id.rsplit_once(":part") {
+            Some((id, part)) if part.parse::<usize>().is_ok() => id,
+            _ => &self.video_id,
+        }
+    }
+
+    pub fn keys(&self) -> EpisodeKeys {
+        EpisodeKeys {
+            id: Some(self.base_id().to_string()).filter(|id| !id.is_empty()),
+            link: Some(self.original_link.to_string()).filter(|l| !l.is_empty()),
+            hash: self.content_hash.clone(),
+        }
+    }
+}
+
+/// Ways to recognize the same episode: video id, original link and content hash
+#[derive(Debug, Default)]
+pub struct EpisodeKeys {
+    pub id: Option<String>,
+    pub link: Option<String>,
+    pub hash: Option<String>,
+}
+
+impl EpisodeKeys {
+    pub fn matches(&self, other: &EpisodeKeys) -> bool {
+        let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+        same(&self.id, &other.id) || same(&self.link, &other.link) || same(&self.hash, &other.hash)
+    }
+}
+
+/// Keys of the episodes of the feed with their names, so duplicates
+/// are found without loading the whole metadata
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EpisodeIndex {
+    ids: HashMap<String, String>,
+    links: HashMap<String, String>,
+    hashes: HashMap<String, String>,
+}
+
+impl EpisodeIndex {
+    pub fn build(metadata: &VecDeque<VideoMetadata>) -> Self {
+        let mut index = Self::default();
+        for item in metadata {
+            let keys = item.keys();
+            for (map, key) in [
+                (&mut index.ids, keys.id),
+                (&mut index.links, keys.link),
+                (&mut index.hashes, keys.hash),
+            ] {
+                if let Some(key) = key {
+                    map.insert(key, item.name.to_string());
+                }
+            }
+        }
+        index
+    }
+
+    /// Returns the name of the episode with any of the keys
+    pub fn find(&self, keys: &EpisodeKeys) -> Option<&str> {
+        [
+            (&self.ids, &keys.id),
+            (&self.links, &keys.link),
+            (&self.hashes, &keys.hash),
+        ]
+        .into_iter()
+        .find_map(|(map, key)| key.as_ref().and_then(|k| map.get(k)))
+        .map(|name| name.as_str())
+    }
+}
+
+/// Per-user feed options, stored next to the metadata. Encoded as a map,
+/// but settings stored before as arrays are decoded by position, so new
+/// fields go to the end with defaults.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedSettings {
+    pub max_episodes: Option<usize>,
+    pub max_age_days: Option<u64>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Url of the channel artwork
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    /// SponsorBlock categories cut out of YouTube videos
+    #[serde(default)]
+    pub sponsorblock: Vec<String>,
+    /// Longer recordings are split into several episodes
+    #[serde(default)]
+    pub max_episode_minutes: Option<u64>,
+    /// Parts end on chapter boundaries if the recording has chapters
+    #[serde(default)]
+    pub split_by_chapters: bool,
+    /// Transcripts from subtitles or the speech-to-text engine
+    #[serde(default)]
+    pub transcripts: bool,
+    /// Key of the signed urls of the private feed, changed by /rotate
+    #[serde(default)]
+    pub url_secret: Option<String>,
+}
+
+impl FeedSettings {
+    pub fn feed_title(&self, owner: &str) -> String {
+        self.title
+            .clone()
+            .unwrap_or_else(|| format!("Куточок {}", owner))
+    }
+
+    /// Imported settings get the same checks as the commands changing them
+    pub fn validate(&self) -> Result<()> {
+        if self.max_episodes == Some(0) || self.max_age_days == Some(0) {
+            return Err(anyhow!("Retention limits must be positive"));
+        }
+        if self.max_episode_minutes == Some(0) {
+            return Err(anyhow!("Parts must be at least a minute long"));
+        }
+        if let Some(category) = self
+            .sponsorblock
+            .iter()
+            .find(|c| !sponsorblock::is_category(c))
+        {
+            return Err(anyhow!("Unknown SponsorBlock category {}", category));
+        }
+        self.audio.validate()
+    }
+
+    /// Removes episodes, which don't fit the retention policy,
+    /// and returns them for the cleanup of audio objects
+    pub fn apply_retention(&self, metadata: &mut VecDeque<VideoMetadata>) -> Vec<VideoMetadata> {
+        let mut removed = vec![];
+        if let Some(max_age_days) = self.max_age_days {
+            let max_age = Duration::from_secs(max_age_days * 24 * 60 * 60);
+            let (kept, expired): (VecDeque<_>, VecDeque<_>) =
+                metadata.drain(..).partition(|item| {
+                    item.created_at
+                        .elapsed()
+                        .map(|age| age <= max_age)
+                        .unwrap_or(true)
+                });
+            *metadata = kept;
+            removed.extend(expired);
+        }
+        if let Some(max_episodes) = self.max_episodes
+            && metadata.len() > max_episodes
+        {
+            removed.extend(metadata.drain(max_episodes..));
+        }
+        removed
+    }
 }
 
 fn default_mime_type() -> String {
     "audio/mp3".to_string()
 }
 
+#[derive(Serialize)]
+struct MetadataFile<'a> {
+    version: u32,
+    items: &'a VecDeque<VideoMetadata>,
+}
+
+#[derive(Deserialize)]
+struct VersionedMetadata {
+    version: u32,
+    items: VecDeque<VideoMetadata>,
+}
+
+/// Object, which is updated with load-modify-store cycles
+trait StoredObject: Clone + Default {
+    fn decode(data: &[u8]) -> Result<Self>;
+
+    fn encode(&self) -> Result<Vec<u8>>;
+}
+
+impl StoredObject for VecDeque<VideoMetadata> {
+    fn decode(data: &[u8]) -> Result<Self> {
+        match data.first() {
+            // Array markers: fixarray, array 16 and array 32
+            Some(0x90..=0x9f | 0xdc | 0xdd) => Ok(rmp_serde::from_slice(data)?),
+            _ => {
+                let metadata: VersionedMetadata = rmp_serde::from_slice(data)?;
+                if metadata.version > METADATA_VERSION {
+                    return Err(anyhow!(
+                        "Metadata version {} is newer than supported {}",
+                        metadata.version,
+                        METADATA_VERSION
+                    ));
+                }
+                Ok(metadata.items)
+            }
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        MetadataFile {
+            version: METADATA_VERSION,
+            items: self,
+        }
+        .serialize(&mut Serializer::new(&mut buf).with_struct_map())?;
+        Ok(buf)
+    }
+}
+
+impl StoredObject for FeedSettings {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buf).with_struct_map())?;
+        Ok(buf)
+    }
+}
+
+impl StoredObject for Vec<String> {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        encode_array(self)
+    }
+}
+
+impl StoredObject for EpisodeIndex {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        encode_array(self)
+    }
+}
+
+fn encode_array<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    value.serialize(&mut Serializer::new(&mut buf))?;
+    Ok(buf)
+}
+
+/// Result of the metadata update, old and new episodes are used
+/// to find audio objects which are not in the feed anymore
+pub struct MetadataUpdate<R> {
+    pub old: VecDeque<VideoMetadata>,
+    pub new: VecDeque<VideoMetadata>,
+    pub result: R,
+}
+
 pub struct MetadataStorage {
-    s3_storage: S3Storage,
+    storage: Arc<dyn PodcastStorage + Send + Sync>,
 }
 
 impl MetadataStorage {
-    pub fn new() -> Self {
-        Self {
-            s3_storage: S3Storage::new(),
+    pub fn new(storage: Arc<dyn PodcastStorage + Send + Sync>) -> Self {
+        Self { storage }
+    }
+
+    async fn load<T: StoredObject>(&self, s3_path: &str) -> Result<(T, Option<String>)> {
+        match self
+            .storage
+            .download_versioned(s3_path)
+            .await
+            .with_context(|| format!("Can't load the object from the path: '{}'", s3_path))?
+        {
+            Some((d, version)) => Ok((
+                T::decode(&d).with_context(|| {
+                    format!("Can't decode the object from the path: '{}'", s3_path)
+                })?,
+                Some(version),
+            )),
+            None => Ok((T::default(), None)),
         }
     }
 
-    pub async fn load_metadata<'a>(&self, s3_path: &'a str) -> Result<VecDeque<VideoMetadata>> {
-        Ok(match self.s3_storage.download_object(s3_path).await {
-            Ok(d) => Ok(rmp_serde::from_read(d.as_slice())?),
-            Err(e) => match e.downcast_ref::<rusoto_core::RusotoError<rusoto_s3::GetObjectError>>()
+    /// Optimistic load-modify-store: the object is written only if nobody changed
+    /// it since loading, otherwise the change is applied again to the fresh copy
+    async fn update<T: StoredObject, R>(
+        &self,
+        s3_path: &str,
+        mut change: impl FnMut(&mut T) -> R,
+    ) -> Result<(T, T, R)> {
+        for attempt in 0..MAX_UPDATE_ATTEMPTS {
+            let (old, version) = self.load::<T>(s3_path).await?;
+            let mut new = old.clone();
+            let result = change(&mut new);
+            let data = new.encode().with_context(|| {
+                format!("Can't serialize the object for the path: '{}'", s3_path)
+            })?;
+            if version.is_some() && data == old.encode()? {
+                return Ok((old, new, result));
+            }
+            if self
+                .storage
+                .upload_if_version(data, s3_path, version.as_deref())
+                .await
+                .with_context(|| format!("Can't upload the object to the path: '{}'", s3_path))?
             {
-                Some(rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(
-                    _,
-                ))) => Ok(VecDeque::new()),
-                _ => Err(e)
-                    .with_context(|| format!("Can't load metadata from the path: '{}'", s3_path)),
-            },
-        }?)
+                return Ok((old, new, result));
+            }
+            warn!(
+                "Object {} was concurrently modified, retrying the update",
+                s3_path
+            );
+            tokio::time::sleep(Duration::from_millis(50 * 2u64.pow(attempt))).await;
+        }
+        Err(anyhow!(
+            "Failed to update the object {} after {} attempts",
+            s3_path,
+            MAX_UPDATE_ATTEMPTS
+        ))
+    }
+
+    pub async fn load_metadata(&self, s3_path: &str) -> Result<VecDeque<VideoMetadata>> {
+        Ok(self.load(s3_path).await?.0)
     }
 
-    pub async fn update_metadata(
+    pub async fn update_metadata<R>(
         &self,
         s3_path: &str,
-        data: &VecDeque<VideoMetadata>,
-    ) -> Result<()> {
-        let mut buf = Vec::new();
-        data.serialize(&mut Serializer::new(&mut buf))
-            .with_context(|| format!("Can't serialize metadata for the path: '{}'", s3_path))?;
-        self.s3_storage
-            .upload_object(buf, s3_path)
+        change: impl FnMut(&mut VecDeque<VideoMetadata>) -> R,
+    ) -> Result<MetadataUpdate<R>> {
+        let (old, new, result) = self.update(s3_path, change).await?;
+        Ok(MetadataUpdate { old, new, result })
+    }
+
+    pub async fn load_settings(&self, s3_path: &str) -> Result<FeedSettings> {
+        Ok(self.load(s3_path).await?.0)
+    }
+
+    pub async fn update_settings<R>(
+        &self,
+        s3_path: &str,
+        change: impl FnMut(&mut FeedSettings) -> R,
+    ) -> Result<(FeedSettings, R)> {
+        let (_, new, result) = self.update(s3_path, change).await?;
+        Ok((new, result))
+    }
+
+    /// Returns None if the index wasn't built yet
+    pub async fn load_index(&self, s3_path: &str) -> Result<Option<EpisodeIndex>> {
+        let (index, version) = self.load(s3_path).await?;
+        Ok(version.map(|_| index))
+    }
+
+    /// Index is derived from the metadata, so it's just overwritten
+    pub async fn store_index(&self, s3_path: &str, index: &EpisodeIndex) -> Result<()> {
+        self.storage
+            .upload_object(index.encode()?, s3_path)
             .await
-            .with_context(|| format!("Can't upload metadata to the path: '{}'", s3_path))?;
-        Ok(())
+            .with_context(|| format!("Can't upload the index to the path: '{}'", s3_path))
     }
 
-    pub async fn add_metadata(
+    pub async fn load_feeds(&self, s3_path: &str) -> Result<Vec<String>> {
+        Ok(self.load(s3_path).await?.0)
+    }
+
+    pub async fn update_feeds<R>(
         &self,
         s3_path: &str,
-        data: VideoMetadata,
-    ) -> Result<VecDeque<VideoMetadata>> {
-        let mut metadata = self.load_metadata(s3_path).await?;
-        metadata.push_front(data);
-        self.update_metadata(s3_path, &metadata).await?;
-        Ok(metadata)
+        change: impl FnMut(&mut Vec<String>) -> R,
+    ) -> Result<(Vec<String>, R)> {
+        let (_, new, result) = self.update(s3_path, change).await?;
+        Ok((new, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn episode(video_id: &str, age: Duration) -> VideoMetadata {
+        VideoMetadata {
+            file_size: 1024,
+            file_url: format!("https://storage.example.com/user/audio/{}.m4a", video_id),
+            video_id: video_id.to_string(),
+            created_at: SystemTime::now() - age,
+            name: video_id.to_string(),
+            original_link: format!("https://www.youtube.com/watch?v={}", video_id),
+            mime_type: "audio/m4a".to_string(),
+            file_path: format!("user/audio/{}.m4a", video_id),
+            description: String::new(),
+            thumbnail_url: None,
+            duration_secs: None,
+            uploader: None,
+            published_at: None,
+            removed_secs: None,
+            chapters_url: None,
+            chapters_path: None,
+            content_hash: None,
+            transcript_url: None,
+            transcript_path: None,
+        }
+    }
+
+    #[test]
+    fn decodes_version_0_metadata() {
+        // Bare array of episodes with the fields of the first release
+        let data = rmp_serde::to_vec(&vec![(
+            1024u64,
+            "https://storage.example.com/user/audio/abc.m4a",
+            "abc",
+            (1_600_000_000u64, 0u32),
+            "Первый",
+            "https://www.youtube.com/watch?v=abc",
+        )])
+        .unwrap();
+        assert_eq!(data[0], 0x91);
+
+        let metadata = VecDeque::<VideoMetadata>::decode(&data).unwrap();
+        assert_eq!(metadata.len(), 1);
+        let item = &metadata[0];
+        assert_eq!(item.video_id, "abc");
+        assert_eq!(item.name, "Первый");
+        assert_eq!(
+            item.created_at,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+        );
+        assert_eq!(item.mime_type, default_mime_type());
+        assert!(item.file_path.is_empty());
+        assert_eq!(item.content_hash, None);
+    }
+
+    #[test]
+    fn round_trips_current_metadata() {
+        let metadata = VecDeque::from([episode("abc", DAY), episode("def", 2 * DAY)]);
+        let mut decoded = VecDeque::<VideoMetadata>::decode(&metadata.encode().unwrap()).unwrap();
+        assert_eq!(ids(decoded.make_contiguous()), ["abc", "def"]);
+    }
+
+    #[test]
+    fn rejects_newer_metadata_version() {
+        let mut data = Vec::new();
+        MetadataFile {
+            version: METADATA_VERSION + 1,
+            items: &VecDeque::from([episode("abc", DAY)]),
+        }
+        .serialize(&mut Serializer::new(&mut data).with_struct_map())
+        .unwrap();
+        assert!(VecDeque::<VideoMetadata>::decode(&data).is_err());
+    }
+
+    #[test]
+    fn decodes_array_encoded_settings() {
+        let settings = FeedSettings {
+            max_episodes: Some(10),
+            title: Some("Лекции".to_string()),
+            transcripts: true,
+            ..Default::default()
+        };
+        let data = encode_array(&settings).unwrap();
+        assert_eq!(FeedSettings::decode(&data).unwrap(), settings);
+        assert_eq!(
+            FeedSettings::decode(&settings.encode().unwrap()).unwrap(),
+            settings
+        );
+        // Settings of the first release had only the retention limits
+        let data = rmp_serde::to_vec(&(Some(5u64), None::<u64>)).unwrap();
+        let settings = FeedSettings::decode(&data).unwrap();
+        assert_eq!(settings.max_episodes, Some(5));
+        assert_eq!(settings.audio, AudioSettings::default());
+    }
+
+    fn ids(metadata: &[VideoMetadata]) -> Vec<&str> {
+        metadata.iter().map(|i| i.video_id.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_episodes_without_retention() {
+        let mut metadata = VecDeque::from([episode("new", DAY), episode("old", 400 * DAY)]);
+        let removed = FeedSettings::default().apply_retention(&mut metadata);
+        assert!(removed.is_empty());
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn removes_oldest_episodes_over_the_limit() {
+        // New episodes are pushed to the front
+        let mut metadata = VecDeque::from([
+            episode("c", DAY),
+            episode("b", 2 * DAY),
+            episode("a", 3 * DAY),
+        ]);
+        let settings = FeedSettings {
+            max_episodes: Some(2),
+            ..Default::default()
+        };
+        let removed = settings.apply_retention(&mut metadata);
+        assert_eq!(ids(metadata.make_contiguous()), ["c", "b"]);
+        assert_eq!(ids(&removed), ["a"]);
+    }
+
+    #[test]
+    fn removes_expired_episodes() {
+        let mut metadata = VecDeque::from([
+            episode("fresh", DAY),
+            episode("expired", 10 * DAY),
+            episode("edge", 6 * DAY),
+        ]);
+        let settings = FeedSettings {
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        let removed = settings.apply_retention(&mut metadata);
+        assert_eq!(ids(metadata.make_contiguous()), ["fresh", "edge"]);
+        assert_eq!(ids(&removed), ["expired"]);
+    }
+
+    #[test]
+    fn applies_age_before_count() {
+        let mut metadata = VecDeque::from([
+            episode("c", DAY),
+            episode("b", 2 * DAY),
+            episode("a", 30 * DAY),
+        ]);
+        let settings = FeedSettings {
+            max_episodes: Some(1),
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        let removed = settings.apply_retention(&mut metadata);
+        assert_eq!(ids(metadata.make_contiguous()), ["c"]);
+        assert_eq!(ids(&removed), ["a", "b"]);
+    }
+
+    #[test]
+    fn finds_duplicates_by_any_key() {
+        let mut first = episode("first", DAY);
+        first.content_hash = Some("hash".to_string());
+        let index = EpisodeIndex::build(&VecDeque::from([first, episode("second", DAY)]));
+        let find = |id: Option<&str>, link: Option<&str>, hash: Option<&str>| {
+            index.find(&EpisodeKeys {
+                id: id.map(|i| i.to_string()),
+                link: link.map(|l| l.to_string()),
+                hash: hash.map(|h| h.to_string()),
+            })
+        };
+        assert_eq!(find(Some("first"), None, None), Some("first"));
+        assert_eq!(
+            find(None, Some("https://www.youtube.com/watch?v=second"), None),
+            Some("second")
+        );
+        assert_eq!(find(Some("other"), None, Some("hash")), Some("first"));
+        assert_eq!(find(Some("other"), Some("https://example.com"), None), None);
+        assert_eq!(find(None, None, None), None);
+    }
+
+    #[test]
+    fn finds_split_recordings_by_their_id() {
+        let mut part = episode("recording:part2", DAY);
+        part.name = "Recording (part 2/2)".to_string();
+        let index = EpisodeIndex::build(&VecDeque::from([part]));
+        let keys = EpisodeKeys {
+            id: Some("recording".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(index.find(&keys), Some("Recording (part 2/2)"));
+    }
+
+    #[test]
+    fn matches_keys_only_by_known_values() {
+        let attachment = EpisodeKeys {
+            id: Some("telegram:unique".to_string()),
+            ..Default::default()
+        };
+        assert!(!EpisodeKeys::default().matches(&EpisodeKeys::default()));
+        assert!(!attachment.matches(&EpisodeKeys::default()));
+        assert!(attachment.matches(&episode("telegram:unique", DAY).keys()));
     }
 }
@@ -0,0 +1,310 @@
+use super::PodcastHandler;
+use super::feeds::{Feed, object_settings_path};
+use super::metadata::{FeedSettings, MetadataStorage, VideoMetadata};
+use super::storage::PodcastStorage;
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use sha2::Sha256;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use telegram_api::Message;
+use tokio::net::TcpListener;
+
+/// 128 bits of the hmac, like the feed roots
+const TOKEN_LENGTH: usize = 16;
+/// Podcast apps follow the redirect right away, so storage urls may be short-lived
+pub const PRESIGNED_URL_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Feeds in a private bucket. Urls of feeds and their episodes point to the
+/// bot's http server with a token signed by the feed secret, which is checked
+/// before redirecting to the presigned url of the storage. Tokens don't
+/// expire, so podcast apps keep working until the secret is rotated.
+#[derive(Clone)]
+pub struct PrivateFeeds {
+    secret: String,
+    public_url: String,
+    listen_address: String,
+}
+
+impl PrivateFeeds {
+    /// Returns None if PODCAST_PRIVATE_URL is not provided
+    pub fn new() -> Option<Self> {
+        let public_url = env::var("PODCAST_PRIVATE_URL")
+            .ok()
+            .filter(|u| !u.is_empty())?
+            .trim_end_matches('/')
+            .to_string();
+        let secret = env::var("PODCAST_FEED_SECRET")
+            .expect("Provide PODCAST_FEED_SECRET environment variable please");
+        let listen_address = env::var("PODCAST_PRIVATE_LISTEN")
+            .expect("Provide PODCAST_PRIVATE_LISTEN environment variable please");
+        Some(Self {
+            secret,
+            public_url,
+            listen_address,
+        })
+    }
+
+    /// Long-lived url of the object, feed secret is None until the first /rotate
+    pub fn signed_url(&self, feed_secret: Option<&str>, path: &str) -> String {
+        format!(
+            "{}/{}?token={}",
+            self.public_url,
+            path,
+            sign(&self.secret, &token_message(feed_secret, path))
+        )
+    }
+
+    pub async fn serve(&self, storage: Arc<dyn PodcastStorage + Send + Sync>) -> Result<()> {
+        let listener = TcpListener::bind(&self.listen_address)
+            .await
+            .with_context(|| format!("Failed to listen on {}", self.listen_address))?;
+        info!("Serving private podcast feeds on {}", self.listen_address);
+        let gateway = Arc::new(Gateway {
+            feeds: self.clone(),
+            metadata: MetadataStorage::new(storage.clone()),
+            storage,
+        });
+        let app = Router::new().fallback(redirect).with_state(gateway);
+        axum::serve(listener, app)
+            .await
+            .with_context(|| "Private podcast feeds server failed")
+    }
+}
+
+struct Gateway {
+    feeds: PrivateFeeds,
+    storage: Arc<dyn PodcastStorage + Send + Sync>,
+    metadata: MetadataStorage,
+}
+
+async fn redirect(State(gateway): State<Arc<Gateway>>, request: Request) -> Response {
+    let Ok(path) = percent_decode_str(request.uri().path().trim_start_matches('/')).decode_utf8()
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (Some(token), Some(settings_path)) = (
+        query_param(request.uri().query(), "token"),
+        object_settings_path(&path),
+    ) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let result = async {
+        let settings = gateway.metadata.load_settings(&settings_path).await?;
+        let message = token_message(settings.url_secret.as_deref(), &path);
+        if !verify(&gateway.feeds.secret, &message, token) {
+            return Ok(None);
+        }
+        gateway
+            .storage
+            .get_presigned_url(&path, PRESIGNED_URL_LIFETIME)
+            .await
+            .map(Some)
+    }
+    .await;
+    match result {
+        Ok(Some(url)) => Redirect::temporary(&url).into_response(),
+        Ok(None) => StatusCode::FORBIDDEN.into_response(),
+        Err(e) => {
+            warn!("Failed to check access to {}: {:?}", path, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn token_message(feed_secret: Option<&str>, path: &str) -> String {
+    format!("{}:{}", feed_secret.unwrap_or_default(), path)
+}
+
+/// Truncated hex hmac of the message
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("Hmac accepts keys of any length");
+    mac.update(message.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..TOKEN_LENGTH])
+}
+
+/// Compares tokens in constant time
+pub fn verify(secret: &str, message: &str, token: &str) -> bool {
+    let Ok(token) = hex::decode(token) else {
+        return false;
+    };
+    if token.len() != TOKEN_LENGTH {
+        return false;
+    }
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("Hmac accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac.verify_truncated_left(&token).is_ok()
+}
+
+/// Tokens and signatures are hex, so the values are not decoded
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+impl PodcastHandler<'_> {
+    /// Url of the rss, which is signed if the feeds are private
+    pub(crate) async fn feed_url(&self, feed: &Feed) -> Result<String> {
+        match &self.private_feeds {
+            Some(_) => {
+                let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+                Ok(self.object_url(&settings, &feed.rss_path()))
+            }
+            None => Ok(self.storage.get_public_url(&feed.rss_path())),
+        }
+    }
+
+    pub(crate) fn object_url(&self, settings: &FeedSettings, path: &str) -> String {
+        match &self.private_feeds {
+            Some(private_feeds) => private_feeds.signed_url(settings.url_secret.as_deref(), path),
+            None => self.storage.get_public_url(path),
+        }
+    }
+
+    /// Signed url of the audio for private feeds
+    pub(crate) async fn episode_url(&self, feed: &Feed, file_url: &str) -> Result<String> {
+        match (
+            &self.private_feeds,
+            file_url.strip_prefix(&self.storage.get_public_url("")),
+        ) {
+            (Some(_), Some(path)) => {
+                let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+                Ok(self.object_url(&settings, path))
+            }
+            _ => Ok(file_url.to_string()),
+        }
+    }
+
+    /// Episode with the signed urls of its objects, external urls are kept
+    pub(crate) fn sign_episode(
+        &self,
+        settings: &FeedSettings,
+        item: &VideoMetadata,
+    ) -> VideoMetadata {
+        let mut item = item.clone();
+        if let Some(path) = self.object_path(&item) {
+            item.file_url = self.object_url(settings, &path);
+        }
+        if let Some(path) = &item.chapters_path {
+            item.chapters_url = Some(self.object_url(settings, path));
+        }
+        if let Some(path) = &item.transcript_path {
+            item.transcript_url = Some(self.object_url(settings, path));
+        }
+        item
+    }
+
+    /// Links of the feed and its episodes shared before stop working,
+    /// podcast apps must be subscribed to the new link
+    pub(crate) async fn rotate_secret(&self, m: &Message, feed: &Feed) -> Result<()> {
+        if self.private_feeds.is_none() {
+            return self
+                .reply(
+                    m,
+                    "Приватные фиды не настроены: укажите PODCAST_PRIVATE_URL".to_string(),
+                )
+                .await;
+        }
+        let secret = hex::encode(rand::random::<[u8; 16]>());
+        self.metadata
+            .update_settings(&feed.settings_path(), |settings| {
+                settings.url_secret = Some(secret.to_string())
+            })
+            .await?;
+        self.update_feed(feed, |_| ()).await?;
+        self.reply(
+            m,
+            format!(
+                "Ссылки фида {} обновлены, старые больше не работают. Новый адрес: {}",
+                feed.name,
+                self.feed_url(feed).await?
+            ),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "feed-secret";
+    const PATH: &str = "root/feeds/talks/audio/episode.m4a";
+
+    fn private_feeds() -> PrivateFeeds {
+        PrivateFeeds {
+            secret: SECRET.to_string(),
+            public_url: "https://podcasts.example.com".to_string(),
+            listen_address: "127.0.0.1:0".to_string(),
+        }
+    }
+
+    fn token(url: &str) -> &str {
+        query_param(url.split_once('?').map(|(_, q)| q), "token").unwrap()
+    }
+
+    #[test]
+    fn verifies_signed_tokens() {
+        let token = sign(SECRET, "message");
+        assert_eq!(token.len(), TOKEN_LENGTH * 2);
+        assert!(verify(SECRET, "message", &token));
+        assert!(!verify("other-secret", "message", &token));
+        assert!(!verify(SECRET, "other message", &token));
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let token = sign(SECRET, "message");
+        assert!(!verify(SECRET, "message", ""));
+        assert!(!verify(SECRET, "message", "not hex"));
+        assert!(!verify(SECRET, "message", &token[..token.len() - 2]));
+        assert!(!verify(SECRET, "message", &format!("{}00", token)));
+    }
+
+    #[test]
+    fn signs_urls_with_the_feed_secret() {
+        let feeds = private_feeds();
+        let url = feeds.signed_url(None, PATH);
+        assert!(url.starts_with(&format!("https://podcasts.example.com/{}?token=", PATH)));
+        assert!(verify(SECRET, &token_message(None, PATH), token(&url)));
+        // Urls of another object can't be derived from the token
+        assert!(!verify(
+            SECRET,
+            &token_message(None, "root/feeds/talks/audio/other.m4a"),
+            token(&url)
+        ));
+        // Rotation of the feed secret revokes old urls
+        assert!(!verify(
+            SECRET,
+            &token_message(Some("rotated"), PATH),
+            token(&url)
+        ));
+        let rotated = feeds.signed_url(Some("rotated"), PATH);
+        assert!(verify(
+            SECRET,
+            &token_message(Some("rotated"), PATH),
+            token(&rotated)
+        ));
+    }
+
+    #[test]
+    fn finds_query_params_by_name() {
+        let query = Some("expires=10&signature=abc&empty=");
+        assert_eq!(query_param(query, "signature"), Some("abc"));
+        assert_eq!(query_param(query, "empty"), Some(""));
+        assert_eq!(query_param(query, "sign"), None);
+        assert_eq!(query_param(None, "signature"), None);
+    }
+}
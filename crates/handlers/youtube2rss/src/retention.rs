@@ -0,0 +1,151 @@
+use super::PodcastHandler;
+use super::feeds::{Feed, feeds_index_path};
+use super::metadata::FeedSettings;
+use super::state_file::StateFile;
+use anyhow::Result;
+use log::{error, warn};
+use telegram_api::Message;
+
+const EXPIRY_DISABLED: &str = "Ограничение по возрасту не настроено: укажите PODCAST_STATE_DIR";
+
+/// Feeds with an age limit. Episodes expire without any changes of the feed,
+/// so these feeds are checked in the background. Feeds are registered when
+/// they are updated, since the storage can't list them.
+pub struct ExpiringFeeds {
+    file: StateFile<Vec<Feed>>,
+}
+
+impl ExpiringFeeds {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            file: StateFile::new("expiring_feeds.json")?,
+        })
+    }
+
+    pub async fn load(&self) -> Result<Vec<Feed>> {
+        self.file.load().await
+    }
+
+    /// Adds or removes the feed
+    pub async fn set(&self, feed: &Feed, expiring: bool) -> Result<()> {
+        self.file
+            .update(|feeds| {
+                let position = feeds
+                    .iter()
+                    .position(|f| f.root == feed.root && f.name == feed.name);
+                match (position, expiring) {
+                    (None, true) => feeds.push(feed.clone()),
+                    (Some(i), false) => {
+                        feeds.remove(i);
+                    }
+                    _ => {}
+                }
+            })
+            .await
+    }
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn set_retention(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        policy: Option<&str>,
+        value: Option<&str>,
+    ) -> Result<()> {
+        let value = value.and_then(|v| v.parse::<u64>().ok()).filter(|v| *v > 0);
+        match (policy, value) {
+            (None, _) => {
+                let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+                return self.reply(m, format_retention(&settings)).await;
+            }
+            // Episodes expire by age only in the background
+            (Some("days"), Some(_)) if self.expiring_feeds.is_none() => {
+                return self.reply(m, EXPIRY_DISABLED.to_string()).await;
+            }
+            (Some("episodes" | "days"), Some(_)) | (Some("off"), _) => {}
+            _ => {
+                return self
+                    .reply(
+                        m,
+                        "Используйте /retention episodes <N>, /retention days <N> или /retention off"
+                            .to_string(),
+                    )
+                    .await;
+            }
+        }
+        let (settings, _) = self
+            .metadata
+            .update_settings(&feed.settings_path(), |settings| match policy {
+                Some("episodes") => settings.max_episodes = value.map(|v| v as usize),
+                Some("days") => settings.max_age_days = value,
+                _ => {
+                    settings.max_episodes = None;
+                    settings.max_age_days = None;
+                }
+            })
+            .await?;
+        self.update_feed(feed, |_| ()).await?;
+        self.reply(m, format_retention(&settings)).await
+    }
+
+    /// Episodes of the feeds with an age limit expire even if nothing is added
+    pub(crate) async fn expire_episodes(&self) -> Result<()> {
+        let Some(expiring_feeds) = &self.expiring_feeds else {
+            return Ok(());
+        };
+        let mut interval = tokio::time::interval(self.subscriptions_check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let feeds = match expiring_feeds.load().await {
+                Ok(feeds) => feeds,
+                Err(e) => {
+                    error!("Failed to load expiring podcast feeds: {:?}", e);
+                    continue;
+                }
+            };
+            for feed in feeds {
+                if let Err(e) = self.expire_feed_episodes(expiring_feeds, &feed).await {
+                    warn!(
+                        "Failed to expire episodes of the feed {}: {:?}",
+                        feed.name, e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn expire_feed_episodes(
+        &self,
+        expiring_feeds: &ExpiringFeeds,
+        feed: &Feed,
+    ) -> Result<()> {
+        if !feed.is_default()
+            && !self
+                .metadata
+                .load_feeds(&feeds_index_path(&feed.root))
+                .await?
+                .contains(&feed.name)
+        {
+            return expiring_feeds.set(feed, false).await;
+        }
+        let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+        let mut metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+        // Feed is rewritten only if some episodes expired, update_feed
+        // unregisters the feed if the limit was turned off
+        if settings.max_age_days.is_none() || !settings.apply_retention(&mut metadata).is_empty() {
+            self.update_feed(feed, |_| ()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn format_retention(settings: &FeedSettings) -> String {
+    match (settings.max_episodes, settings.max_age_days) {
+        (None, None) => "Эпизоды хранятся без ограничений".to_string(),
+        (Some(n), None) => format!("Хранятся последние {} эпизодов", n),
+        (None, Some(d)) => format!("Хранятся эпизоды не старше {} дней", d),
+        (Some(n), Some(d)) => format!("Хранятся последние {} эпизодов не старше {} дней", n, d),
+    }
+}
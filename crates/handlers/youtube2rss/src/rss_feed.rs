@@ -0,0 +1,364 @@
+use super::PodcastHandler;
+use super::chapters::CHAPTERS_MIME_TYPE;
+use super::feeds::Feed;
+use super::metadata::{FeedSettings, VideoMetadata};
+use super::transcripts::TRANSCRIPT_MIME_TYPE;
+use anyhow::Result;
+use chrono::DateTime;
+use chrono::offset::Utc;
+use rss::extension::itunes::{ITunesChannelExtension, ITunesItemExtension};
+use rss::extension::{Extension, ExtensionMap};
+use rss::{Channel, Enclosure, Guid, Image, Item};
+use std::collections::{BTreeMap, VecDeque};
+use telegram_api::Message;
+
+pub const PODCAST_NAMESPACE: &str = "https://podcastindex.org/namespace/1.0";
+
+pub fn generate_rss(
+    user: &str,
+    feed_url: &str,
+    settings: &FeedSettings,
+    metadata: &VecDeque<VideoMetadata>,
+) -> Result<String> {
+    let title = settings.feed_title(user);
+    let author = settings.author.clone().unwrap_or_else(|| user.to_string());
+    let description = settings
+        .description
+        .clone()
+        .unwrap_or_else(|| title.clone());
+
+    let mut itunes = ITunesChannelExtension::default();
+    itunes.set_author(author);
+    itunes.set_summary(description.clone());
+    itunes.set_explicit("false".to_string());
+    itunes.set_image(settings.image.clone());
+
+    let mut channel = Channel::default();
+    channel.set_title(title.clone());
+    channel.set_link(feed_url.to_string());
+    channel.set_description(description);
+    if let Some(url) = &settings.image {
+        let mut image = Image::default();
+        image.set_url(url.to_string());
+        image.set_title(title);
+        image.set_link(feed_url.to_string());
+        channel.set_image(image);
+    }
+    channel.set_itunes_ext(itunes);
+    channel.set_namespaces(BTreeMap::from([(
+        "podcast".to_string(),
+        PODCAST_NAMESPACE.to_string(),
+    )]));
+    channel.set_extensions(podcast_extensions(vec![podcast_extension(
+        "medium", "podcast",
+    )]));
+    channel.set_items(metadata.iter().map(generate_item).collect::<Vec<_>>());
+    Ok(channel.to_string())
+}
+
+fn generate_item(item: &VideoMetadata) -> Item {
+    let pub_date: DateTime<Utc> = item.created_at.into();
+
+    // Podcast apps rely on guid to tell new episodes from already known ones
+    let mut guid = Guid::default();
+    guid.set_permalink(false);
+    guid.set_value(if item.video_id.is_empty() {
+        item.original_link.to_string()
+    } else {
+        item.video_id.to_string()
+    });
+
+    let mut enclosure = Enclosure::default();
+    enclosure.set_mime_type(item.mime_type.to_string());
+    enclosure.set_url(item.file_url.to_string());
+    enclosure.set_length(item.file_size.to_string());
+
+    let mut itunes = ITunesItemExtension::default();
+    itunes.set_image(item.thumbnail_url.clone());
+    itunes.set_duration(item.duration_secs.map(format_duration));
+    itunes.set_episode_type("full".to_string());
+    itunes.set_author(item.uploader.clone());
+
+    let mut ritem = Item::default();
+    ritem.set_title(item.name.to_string());
+    // Episodes sent to the bot as files have no original link
+    if !item.original_link.is_empty() {
+        ritem.set_link(item.original_link.to_string());
+    }
+    if !item.description.is_empty() {
+        ritem.set_description(item.description.to_string());
+    }
+    ritem.set_pub_date(pub_date.to_rfc2822());
+    ritem.set_guid(guid);
+    ritem.set_enclosure(enclosure);
+    ritem.set_itunes_ext(itunes);
+    let links = [
+        ("chapters", &item.chapters_url, CHAPTERS_MIME_TYPE),
+        ("transcript", &item.transcript_url, TRANSCRIPT_MIME_TYPE),
+    ];
+    let extensions: Vec<Extension> = links
+        .into_iter()
+        .filter_map(|(name, url, mime_type)| Some(podcast_link(name, url.as_ref()?, mime_type)))
+        .collect();
+    if !extensions.is_empty() {
+        ritem.set_extensions(podcast_extensions(extensions));
+    }
+    ritem
+}
+
+/// Element of the Podcasting 2.0 namespace referencing a file of the episode
+fn podcast_link(name: &str, url: &str, mime_type: &str) -> Extension {
+    let mut extension = Extension::default();
+    extension.set_name(format!("podcast:{}", name));
+    extension.attrs = BTreeMap::from([
+        ("url".to_string(), url.to_string()),
+        ("type".to_string(), mime_type.to_string()),
+    ]);
+    extension
+}
+
+pub fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Extension element of the Podcasting 2.0 namespace
+pub fn podcast_extension(name: &str, value: &str) -> Extension {
+    let mut extension = Extension::default();
+    extension.set_name(format!("podcast:{}", name));
+    extension.set_value(value.to_string());
+    extension
+}
+
+pub fn podcast_extensions(extensions: Vec<Extension>) -> ExtensionMap {
+    let mut elements: BTreeMap<String, Vec<Extension>> = BTreeMap::new();
+    for extension in extensions {
+        let local_name = extension
+            .name
+            .strip_prefix("podcast:")
+            .unwrap_or(&extension.name)
+            .to_string();
+        elements.entry(local_name).or_default().push(extension);
+    }
+    BTreeMap::from([("podcast".to_string(), elements)])
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn set_feed_info(&self, m: &Message, feed: &Feed, args: &str) -> Result<()> {
+        let (field, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        // Empty value resets the field to the default one
+        let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        match field {
+            "" => {
+                let settings = self.metadata.load_settings(&feed.settings_path()).await?;
+                return self.reply(m, format_feed_info(&settings)).await;
+            }
+            "title" | "author" | "description" | "image" => {}
+            _ => {
+                return self
+                    .reply(
+                        m,
+                        "Используйте /feedinfo title|author|description|image <значение>"
+                            .to_string(),
+                    )
+                    .await;
+            }
+        }
+        let (settings, _) = self
+            .metadata
+            .update_settings(&feed.settings_path(), |settings| {
+                let target = match field {
+                    "title" => &mut settings.title,
+                    "author" => &mut settings.author,
+                    "description" => &mut settings.description,
+                    _ => &mut settings.image,
+                };
+                *target = value.clone();
+            })
+            .await?;
+        self.update_feed(feed, |_| ()).await?;
+        self.reply(m, format_feed_info(&settings)).await
+    }
+}
+
+fn format_feed_info(settings: &FeedSettings) -> String {
+    let field = |v: &Option<String>| v.clone().unwrap_or("по умолчанию".to_string());
+    format!(
+        "Название: {}\nАвтор: {}\nОписание: {}\nОбложка: {}",
+        field(&settings.title),
+        field(&settings.author),
+        field(&settings.description),
+        field(&settings.image)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn video(video_id: &str, name: &str) -> VideoMetadata {
+        VideoMetadata {
+            file_size: 1024,
+            file_url: format!("https://storage.example.com/user/audio/{}.m4a", video_id),
+            video_id: video_id.to_string(),
+            created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            name: name.to_string(),
+            original_link: format!("https://www.youtube.com/watch?v={}", video_id),
+            mime_type: "audio/m4a".to_string(),
+            file_path: format!("user/audio/{}.m4a", video_id),
+            description: "Описание <эпизода> & ссылки".to_string(),
+            thumbnail_url: Some(format!("https://i.ytimg.com/vi/{}/maxres.jpg", video_id)),
+            duration_secs: Some(3723),
+            uploader: Some("Канал".to_string()),
+            published_at: None,
+            removed_secs: None,
+            chapters_url: None,
+            chapters_path: None,
+            content_hash: None,
+            transcript_url: None,
+            transcript_path: None,
+        }
+    }
+
+    fn parse(xml: &str) -> Channel {
+        Channel::read_from(xml.as_bytes()).expect("Generated feed must be valid RSS")
+    }
+
+    #[test]
+    fn generates_itunes_episode_fields() {
+        let metadata = VecDeque::from([video("abc", "Первый"), video("def", "Второй")]);
+        let xml = generate_rss(
+            "user",
+            "https://storage.example.com/user/feed.xml",
+            &FeedSettings::default(),
+            &metadata,
+        )
+        .unwrap();
+        let channel = parse(&xml);
+
+        assert_eq!(channel.title(), "Куточок user");
+        assert_eq!(channel.link(), "https://storage.example.com/user/feed.xml");
+        assert_eq!(channel.items().len(), 2);
+
+        let item = &channel.items()[0];
+        assert_eq!(item.title(), Some("Первый"));
+        assert_eq!(item.guid().map(|g| g.value()), Some("abc"));
+        assert_eq!(item.guid().map(|g| g.is_permalink()), Some(false));
+        assert_eq!(item.link(), Some("https://www.youtube.com/watch?v=abc"));
+        assert_eq!(item.description(), Some("Описание <эпизода> & ссылки"));
+        assert_eq!(
+            item.enclosure().map(|e| e.url()),
+            Some("https://storage.example.com/user/audio/abc.m4a")
+        );
+
+        let itunes = item.itunes_ext().expect("Item must have itunes extension");
+        assert_eq!(itunes.duration(), Some("1:02:03"));
+        assert_eq!(itunes.author(), Some("Канал"));
+        assert_eq!(
+            itunes.image(),
+            Some("https://i.ytimg.com/vi/abc/maxres.jpg")
+        );
+    }
+
+    #[test]
+    fn uses_feed_settings_for_channel() {
+        let settings = FeedSettings {
+            title: Some("Лекции".to_string()),
+            author: Some("Автор".to_string()),
+            description: Some("Записи лекций".to_string()),
+            image: Some("https://example.com/cover.jpg".to_string()),
+            ..Default::default()
+        };
+        let xml = generate_rss(
+            "user",
+            "https://storage.example.com/user/feed.xml",
+            &settings,
+            &VecDeque::new(),
+        )
+        .unwrap();
+        let channel = parse(&xml);
+
+        assert_eq!(channel.title(), "Лекции");
+        assert_eq!(channel.description(), "Записи лекций");
+        assert_eq!(
+            channel.image().map(|i| i.url()),
+            Some("https://example.com/cover.jpg")
+        );
+        let itunes = channel
+            .itunes_ext()
+            .expect("Channel must have itunes extension");
+        assert_eq!(itunes.author(), Some("Автор"));
+        assert_eq!(itunes.image(), Some("https://example.com/cover.jpg"));
+        assert_eq!(itunes.explicit(), Some("false"));
+        assert_eq!(
+            channel.namespaces().get("podcast").map(|n| n.as_str()),
+            Some(PODCAST_NAMESPACE)
+        );
+        assert!(xml.contains("<podcast:medium>podcast</podcast:medium>"));
+    }
+
+    #[test]
+    fn references_chapters_of_episode() {
+        let mut item = video("abc", "С главами");
+        item.chapters_url =
+            Some("https://storage.example.com/user/audio/abc.chapters.json".to_string());
+        let xml = generate_rss(
+            "user",
+            "",
+            &FeedSettings::default(),
+            &VecDeque::from([item]),
+        )
+        .unwrap();
+        let channel = parse(&xml);
+        let chapters = &channel.items()[0].extensions()["podcast"]["chapters"][0];
+        assert_eq!(
+            chapters.attrs().get("url").map(|u| u.as_str()),
+            Some("https://storage.example.com/user/audio/abc.chapters.json")
+        );
+        assert_eq!(
+            chapters.attrs().get("type").map(|t| t.as_str()),
+            Some(CHAPTERS_MIME_TYPE)
+        );
+    }
+
+    #[test]
+    fn references_transcript_next_to_chapters() {
+        let mut item = video("abc", "С расшифровкой");
+        item.chapters_url =
+            Some("https://storage.example.com/user/audio/abc.chapters.json".to_string());
+        item.transcript_url = Some("https://storage.example.com/user/audio/abc.vtt".to_string());
+        let xml = generate_rss(
+            "user",
+            "",
+            &FeedSettings::default(),
+            &VecDeque::from([item]),
+        )
+        .unwrap();
+        let channel = parse(&xml);
+        let podcast = &channel.items()[0].extensions()["podcast"];
+        assert_eq!(podcast["chapters"].len(), 1);
+        let transcript = &podcast["transcript"][0];
+        assert_eq!(
+            transcript.attrs().get("url").map(|u| u.as_str()),
+            Some("https://storage.example.com/user/audio/abc.vtt")
+        );
+        assert_eq!(
+            transcript.attrs().get("type").map(|t| t.as_str()),
+            Some(TRANSCRIPT_MIME_TYPE)
+        );
+    }
+
+    #[test]
+    fn keeps_guid_of_mp3_episodes_stable() {
+        let mut mp3 = video("", "file.mp3");
+        mp3.original_link = "https://example.com/file.mp3".to_string();
+        let xml =
+            generate_rss("user", "", &FeedSettings::default(), &VecDeque::from([mp3])).unwrap();
+        let channel = parse(&xml);
+        let item = &channel.items()[0];
+        assert_eq!(
+            item.guid().map(|g| g.value()),
+            Some("https://example.com/file.mp3")
+        );
+    }
+}
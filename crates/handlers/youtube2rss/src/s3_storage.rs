@@ -1,120 +1,229 @@
+use super::storage::PodcastStorage;
 use anyhow::{Context, Result};
-use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3, S3Client};
+use async_trait::async_trait;
+use aws_config::environment::credentials::EnvironmentVariableCredentialsProvider;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::config::{BehaviorVersion, Region};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use std::env;
-use std::io::Read;
 use std::path::PathBuf;
-use tokio::io::AsyncReadExt;
+use std::time::Duration;
 
+const DEFAULT_ENDPOINT: &str = "https://storage.yandexcloud.net";
+const DEFAULT_REGION: &str = "ru-central1";
+const MAX_ATTEMPTS: u32 = 5;
+/// CopySource header must be url encoded, but keeps the path separators
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Any S3 compatible storage: AWS, Yandex Object Storage, MinIO etc.
+/// One client is shared by all requests, so connections are reused.
 pub struct S3Storage {
     bucket_name: String,
+    public_url: String,
+    client: Client,
 }
 
 impl S3Storage {
-    fn s3_client(&self) -> S3Client {
-        let region = rusoto_core::region::Region::Custom {
-            name: "ru-central1".to_owned(),
-            endpoint: "storage.yandexcloud.net".to_owned(),
-        };
-
-        rusoto_s3::S3Client::new(region)
-    }
-
     pub fn new() -> Self {
         let bucket_name = {
             env::var("BOT_BUCKET_NAME")
                 .expect("Provide BOT_BUCKET_NAME environment variable please")
         };
-        Self { bucket_name }
+        let endpoint = env::var("BOT_S3_ENDPOINT")
+            .ok()
+            .filter(|e| !e.is_empty())
+            .unwrap_or(DEFAULT_ENDPOINT.to_string());
+        let endpoint = if endpoint.contains("://") {
+            endpoint.trim_end_matches('/').to_string()
+        } else {
+            format!("https://{}", endpoint.trim_end_matches('/'))
+        };
+        let region = env::var("BOT_S3_REGION")
+            .ok()
+            .filter(|r| !r.is_empty())
+            .unwrap_or(DEFAULT_REGION.to_string());
+        let path_style = env::var("BOT_S3_PATH_STYLE")
+            .map(|p| p != "false")
+            .unwrap_or(true);
+        let public_url = match env::var("BOT_S3_PUBLIC_URL") {
+            Ok(u) if !u.is_empty() => u.trim_end_matches('/').to_string(),
+            _ if path_style => format!("{}/{}", endpoint, bucket_name),
+            _ => {
+                let (scheme, host) = endpoint
+                    .split_once("://")
+                    .expect("S3 endpoint always has a scheme");
+                format!("{}://{}.{}", scheme, bucket_name, host)
+            }
+        };
+
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(region))
+            .endpoint_url(endpoint)
+            .force_path_style(path_style)
+            .credentials_provider(EnvironmentVariableCredentialsProvider::new())
+            .retry_config(
+                RetryConfig::standard()
+                    .with_max_attempts(MAX_ATTEMPTS)
+                    .with_initial_backoff(Duration::from_millis(500)),
+            )
+            .build();
+
+        Self {
+            bucket_name,
+            public_url,
+            client: Client::from_conf(config),
+        }
+    }
+}
+
+#[async_trait]
+impl PodcastStorage for S3Storage {
+    async fn download_object(&self, s3_path: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .download_versioned(s3_path)
+            .await?
+            .map(|(data, _)| data))
     }
 
-    pub async fn download_object(&self, s3_path: &str) -> Result<Vec<u8>> {
-        let response = self
-            .s3_client()
-            .get_object(GetObjectRequest {
-                bucket: self.bucket_name.to_owned(),
-                key: s3_path.to_string(),
-                ..Default::default()
-            })
+    async fn download_versioned(&self, s3_path: &str) -> Result<Option<(Vec<u8>, String)>> {
+        let response = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(s3_path)
+            .send()
             .await
-            .with_context(|| {
+        {
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                return Ok(None);
+            }
+            r => r.with_context(|| {
                 format!(
                     "Can't GetObject with the path '{}' for downloading",
                     s3_path
                 )
-            })?;
-        if let Some(stream) = response.body {
-            let mut buf = Vec::new();
-            stream
-                .into_async_read()
-                .read_to_end(&mut buf)
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to read response body for downloading the object {}",
-                        s3_path
-                    )
-                })?;
-            Ok(buf)
-        } else {
-            Ok(Vec::new())
+            })?,
+        };
+        let etag = response.e_tag().unwrap_or_default().to_string();
+        let body = response.body.collect().await.with_context(|| {
+            format!(
+                "Failed to read response body for downloading the object {}",
+                s3_path
+            )
+        })?;
+        Ok(Some((body.to_vec(), etag)))
+    }
+
+    async fn upload_if_version(
+        &self,
+        data: Vec<u8>,
+        s3_path: &str,
+        version: Option<&str>,
+    ) -> Result<bool> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(s3_path)
+            .body(ByteStream::from(data));
+        let request = match version {
+            Some(etag) => request.if_match(etag),
+            None => request.if_none_match("*"),
+        };
+        match request.send().await {
+            Ok(_) => Ok(true),
+            // 412 for a failed precondition, 409 for a concurrent conditional write
+            Err(e)
+                if e.raw_response()
+                    .is_some_and(|r| matches!(r.status().as_u16(), 409 | 412)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to conditionally upload the object {}", s3_path)),
         }
     }
 
-    pub async fn upload_object(&self, data: Vec<u8>, s3_path: &str) -> anyhow::Result<()> {
-        Ok(self
-            .s3_client()
-            .put_object(PutObjectRequest {
-                bucket: self.bucket_name.to_owned(),
-                key: s3_path.to_string(),
-                body: Some(data.into()),
-                ..Default::default()
-            })
+    async fn upload_object(&self, data: Vec<u8>, s3_path: &str) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(s3_path)
+            .body(ByteStream::from(data))
+            .send()
             .await
             .with_context(|| format!("Failed to upload the object {}", s3_path))
-            .map(|_| ())?)
+            .map(|_| ())
     }
 
-    pub async fn upload_file(&self, file: PathBuf, s3_path: String) -> Result<()> {
-        let body = {
-            let f_p = file.clone();
-            let s3_p = s3_path.clone();
-            tokio::task::spawn_blocking(move || {
-                let mut f = std::fs::File::open(&f_p).with_context(|| {
-                    format!(
-                        "Failed to open file {} during file upload to the path {}",
-                        f_p.to_string_lossy(),
-                        s3_p
-                    )
-                })?;
-                let mut body: Vec<u8> = vec![];
-                f.read_to_end(&mut body)?;
-                Ok::<Vec<u8>, anyhow::Error>(body)
-            })
-            .await?
-        }?;
+    async fn upload_file(&self, file: PathBuf, s3_path: String) -> Result<()> {
+        let body = ByteStream::from_path(&file).await.with_context(|| {
+            format!(
+                "Failed to open file {} during file upload to the path {}",
+                file.to_string_lossy(),
+                s3_path
+            )
+        })?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(&s3_path)
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to upload the file to the path {}", s3_path))
+            .map(|_| ())
+    }
 
-        self.upload_bytes(body, s3_path).await
+    async fn copy_object(&self, from: &str, to: &str) -> Result<()> {
+        self.client
+            .copy_object()
+            .bucket(&self.bucket_name)
+            .copy_source(format!(
+                "{}/{}",
+                self.bucket_name,
+                utf8_percent_encode(from, COPY_SOURCE)
+            ))
+            .key(to)
+            .send()
+            .await
+            .with_context(|| format!("Failed to copy the object {} to {}", from, to))
+            .map(|_| ())
     }
 
-    pub async fn upload_bytes(&self, bytes: Vec<u8>, s3_path: String) -> Result<()> {
-        Ok(self
-            .s3_client()
-            .put_object(PutObjectRequest {
-                bucket: self.bucket_name.to_owned(),
-                key: s3_path.to_string(),
-                body: Some(bytes.into()),
-                ..Default::default()
-            })
+    async fn delete_object(&self, s3_path: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(s3_path)
+            .send()
             .await
-            .with_context(|| format!("Failed to put object {}", s3_path))
-            .map(|_| ())?)
+            .with_context(|| format!("Failed to delete the object {}", s3_path))
+            .map(|_| ())
     }
 
-    pub fn get_public_url(&self, s3_path: &str) -> String {
-        format!(
-            "https://storage.yandexcloud.net/{bucket}/{file}",
-            bucket = self.bucket_name,
-            file = s3_path
-        )
+    fn get_public_url(&self, s3_path: &str) -> String {
+        format!("{}/{}", self.public_url, s3_path)
+    }
+
+    async fn get_presigned_url(&self, s3_path: &str, expires_in: Duration) -> Result<String> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(s3_path)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await
+            .with_context(|| format!("Failed to presign the object {}", s3_path))?;
+        Ok(request.uri().to_string())
     }
 }
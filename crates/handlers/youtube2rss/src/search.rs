@@ -0,0 +1,364 @@
+use super::feeds::{DEFAULT_FEED, Feed, feeds_index_path};
+use super::metadata::VideoMetadata;
+use super::state_file::{create_parent, read_file, replace_file, state_dir};
+use super::{PodcastHandler, message_user, storage_file_name};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use telegram_api::Message;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+const MAX_FOUND_EPISODES: usize = 10;
+const SEARCH_DISABLED: &str = "Поиск не настроен: укажите PODCAST_STATE_DIR";
+/// Shorter words of the query are matched exactly, longer ones as prefixes,
+/// so "лекци" finds "лекция" and "лекции"
+const MIN_PREFIX_LENGTH: usize = 3;
+
+/// Episode found by the search
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedEpisode {
+    pub feed: String,
+    pub base_id: String,
+    pub name: String,
+    pub original_link: String,
+    pub file_url: String,
+    pub created_at: SystemTime,
+}
+
+/// Inverted index of all feeds of a user, episodes are numbered
+/// to keep postings small
+#[derive(Default, Serialize, Deserialize)]
+struct UserIndex {
+    next_id: u32,
+    episodes: HashMap<u32, IndexedEpisode>,
+    /// Episode numbers by the normalized word
+    postings: HashMap<String, Vec<u32>>,
+}
+
+impl UserIndex {
+    fn insert(&mut self, episode: IndexedEpisode, text: &str) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let words: HashSet<String> = tokenize(&episode.name).chain(tokenize(text)).collect();
+        for word in words {
+            self.postings.entry(word).or_default().push(id);
+        }
+        self.episodes.insert(id, episode);
+    }
+
+    fn remove(&mut self, ids: &HashSet<u32>) {
+        self.episodes.retain(|id, _| !ids.contains(id));
+        self.postings.retain(|_, postings| {
+            postings.retain(|id| !ids.contains(id));
+            !postings.is_empty()
+        });
+    }
+
+    /// Episodes with a word matching the word of the query
+    fn matching(&self, word: &str) -> HashSet<u32> {
+        if word.chars().count() < MIN_PREFIX_LENGTH {
+            return self
+                .postings
+                .get(word)
+                .into_iter()
+                .flatten()
+                .copied()
+                .collect();
+        }
+        self.postings
+            .iter()
+            .filter(|(w, _)| w.starts_with(word))
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect()
+    }
+}
+
+/// Full-text search over titles, descriptions and subtitles of episodes.
+/// Indexes are kept in the local state directory next to subscriptions,
+/// subtitles are saved there at the time the episode is added.
+pub struct SearchIndex {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            dir: state_dir()?.join("search"),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Feeds added before the search are indexed with the first query
+    pub async fn is_indexed(&self, root: &str) -> bool {
+        fs::try_exists(self.index_path(root)).await.unwrap_or(false)
+    }
+
+    /// Keeps the text for the episode, which is not added to the feed yet
+    pub async fn save_text(&self, root: &str, base_id: &str, text: &str) -> Result<()> {
+        let path = self.text_path(root, base_id);
+        create_parent(&path).await?;
+        fs::write(&path, text)
+            .await
+            .with_context(|| format!("Failed to write episode text {}", path.display()))
+    }
+
+    /// Brings the index of the feed in line with its episodes
+    pub async fn update_feed(
+        &self,
+        root: &str,
+        feed: &str,
+        items: &VecDeque<VideoMetadata>,
+    ) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut index = self.read(root).await?;
+
+        let current: HashSet<(&str, &str)> = items
+            .iter()
+            .map(|i| (i.base_id(), i.file_url.as_str()))
+            .collect();
+        let removed: HashSet<u32> = index
+            .episodes
+            .iter()
+            .filter(|(_, e)| {
+                e.feed == feed && !current.contains(&(e.base_id.as_str(), e.file_url.as_str()))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let removed_ids: HashSet<String> = removed
+            .iter()
+            .map(|id| index.episodes[id].base_id.to_string())
+            .collect();
+        index.remove(&removed);
+
+        let indexed: HashSet<(String, String)> = index
+            .episodes
+            .values()
+            .filter(|e| e.feed == feed)
+            .map(|e| (e.base_id.to_string(), e.file_url.to_string()))
+            .collect();
+        for item in items {
+            let key = (item.base_id().to_string(), item.file_url.to_string());
+            if indexed.contains(&key) {
+                continue;
+            }
+            let subtitles = self.read_text(root, item.base_id()).await?;
+            index.insert(
+                IndexedEpisode {
+                    feed: feed.to_string(),
+                    base_id: key.0,
+                    name: item.name.to_string(),
+                    original_link: item.original_link.to_string(),
+                    file_url: key.1,
+                    created_at: item.created_at,
+                },
+                &format!("{}\n{}", item.description, subtitles.unwrap_or_default()),
+            );
+        }
+
+        // Texts are shared by parts of a recording and copies in other feeds
+        let kept: HashSet<&str> = index
+            .episodes
+            .values()
+            .map(|e| e.base_id.as_str())
+            .collect();
+        for base_id in removed_ids.iter().filter(|id| !kept.contains(id.as_str())) {
+            let path = self.text_path(root, base_id);
+            if let Err(e) = fs::remove_file(&path).await
+                && e.kind() != ErrorKind::NotFound
+            {
+                return Err(e)
+                    .with_context(|| format!("Failed to remove episode text {}", path.display()));
+            }
+        }
+        self.write(root, &index).await
+    }
+
+    /// Returns episodes matching all words of the query, the ones with
+    /// the words in the title go first, newer first otherwise
+    pub async fn find(&self, root: &str, query: &str, limit: usize) -> Result<Vec<IndexedEpisode>> {
+        let words: Vec<String> = tokenize(query).collect();
+        if words.is_empty() {
+            return Ok(vec![]);
+        }
+        let _guard = self.lock.lock().await;
+        let index = self.read(root).await?;
+        let mut found: Option<HashSet<u32>> = None;
+        for word in &words {
+            let matching = index.matching(word);
+            found = Some(match found {
+                Some(found) => found.intersection(&matching).copied().collect(),
+                None => matching,
+            });
+        }
+        let mut episodes: Vec<(usize, &IndexedEpisode)> = found
+            .unwrap_or_default()
+            .iter()
+            .map(|id| {
+                let episode = &index.episodes[id];
+                let title: Vec<String> = tokenize(&episode.name).collect();
+                let score = words
+                    .iter()
+                    .filter(|w| title.iter().any(|t| t.starts_with(w.as_str())))
+                    .count();
+                (score, episode)
+            })
+            .collect();
+        episodes.sort_by(|(a_score, a), (b_score, b)| {
+            b_score
+                .cmp(a_score)
+                .then_with(|| b.created_at.cmp(&a.created_at))
+        });
+        Ok(episodes
+            .into_iter()
+            .take(limit)
+            .map(|(_, e)| e.clone())
+            .collect())
+    }
+
+    fn index_path(&self, root: &str) -> PathBuf {
+        self.dir.join(root).join("index.mp")
+    }
+
+    fn text_path(&self, root: &str, base_id: &str) -> PathBuf {
+        self.dir
+            .join(root)
+            .join("texts")
+            .join(format!("{}.txt", storage_file_name(base_id)))
+    }
+
+    async fn read_text(&self, root: &str, base_id: &str) -> Result<Option<String>> {
+        let path = self.text_path(root, base_id);
+        match fs::read_to_string(&path).await {
+            Ok(text) => Ok(Some(text)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read episode text {}", path.display()))
+            }
+        }
+    }
+
+    async fn read(&self, root: &str) -> Result<UserIndex> {
+        let path = self.index_path(root);
+        match read_file(&path).await? {
+            Some(d) => rmp_serde::from_slice(&d)
+                .with_context(|| format!("Failed to parse search index {}", path.display())),
+            None => Ok(UserIndex::default()),
+        }
+    }
+
+    async fn write(&self, root: &str, index: &UserIndex) -> Result<()> {
+        replace_file(&self.index_path(root), rmp_serde::to_vec(index)?).await
+    }
+}
+
+/// Lowercase words, ё is the same as е for the search
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 1)
+        .map(|w| w.to_lowercase().replace('ё', "е"))
+}
+
+impl PodcastHandler<'_> {
+    /// Searches episodes of all feeds of the user
+    pub(crate) async fn find_episodes(&self, m: &Message, query: &str) -> Result<()> {
+        let Some(search) = &self.search else {
+            return self.reply(m, SEARCH_DISABLED.to_string()).await;
+        };
+        if query.trim().is_empty() {
+            return self
+                .reply(
+                    m,
+                    "Используйте /find <слова из названия или описания>".to_string(),
+                )
+                .await;
+        }
+        let user = message_user(m)?;
+        let root = self.roots.root(user);
+        if !search.is_indexed(&root).await {
+            let feeds = self.metadata.load_feeds(&feeds_index_path(&root)).await?;
+            for name in std::iter::once(DEFAULT_FEED.to_string()).chain(feeds) {
+                let feed = self.roots.feed(user, &name);
+                let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+                search.update_feed(&root, &name, &metadata).await?;
+            }
+        }
+
+        let found = search.find(&root, query, MAX_FOUND_EPISODES).await?;
+        if found.is_empty() {
+            return self.reply(m, "Ничего не найдено".to_string()).await;
+        }
+        let mut episodes = vec![];
+        for (i, e) in found.iter().enumerate() {
+            let mut lines = vec![format!("{}. {} ({})", i + 1, e.name, e.feed)];
+            if !e.original_link.is_empty() {
+                lines.push(e.original_link.to_string());
+            }
+            let feed = self.roots.feed(user, &e.feed);
+            lines.push(self.episode_url(&feed, &e.file_url).await?);
+            episodes.push(lines.join("\n"));
+        }
+        self.reply(m, episodes.join("\n\n")).await
+    }
+
+    /// Search is secondary, so its failures don't fail changes of the feed
+    pub(crate) async fn update_search(&self, feed: &Feed, metadata: &VecDeque<VideoMetadata>) {
+        if let Some(search) = &self.search
+            && let Err(e) = search.update_feed(&feed.root, &feed.name, metadata).await
+        {
+            warn!(
+                "Failed to update search index of the feed {}: {:?}",
+                feed.name, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(name: &str) -> IndexedEpisode {
+        IndexedEpisode {
+            feed: "default".to_string(),
+            base_id: name.to_string(),
+            name: name.to_string(),
+            original_link: String::new(),
+            file_url: String::new(),
+            created_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn normalizes_words() {
+        let words: Vec<String> = tokenize("Ёлка, Rust-лекция и 2 C++").collect();
+        assert_eq!(words, ["елка", "rust", "лекция"]);
+    }
+
+    #[test]
+    fn matches_long_words_by_prefix() {
+        let mut index = UserIndex::default();
+        index.insert(episode("Лекция"), "про ai и базы");
+        index.insert(episode("Лекции"), "про airflow");
+        assert_eq!(index.matching("лекци").len(), 2);
+        assert_eq!(index.matching("ai"), HashSet::from([0]));
+        assert!(index.matching("баз").contains(&0));
+        assert!(index.matching("нет").is_empty());
+    }
+
+    #[test]
+    fn removes_postings_of_removed_episodes() {
+        let mut index = UserIndex::default();
+        index.insert(episode("first"), "shared only");
+        index.insert(episode("second"), "shared");
+        index.remove(&HashSet::from([0]));
+        assert_eq!(index.matching("shared"), HashSet::from([1]));
+        assert!(!index.postings.contains_key("only"));
+        assert_eq!(index.episodes.len(), 1);
+    }
+}
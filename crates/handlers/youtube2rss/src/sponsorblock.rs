@@ -0,0 +1,280 @@
+use super::PodcastHandler;
+use super::feeds::Feed;
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::env;
+use std::time::Duration;
+use telegram_api::Message;
+
+const DEFAULT_API_URL: &str = "https://sponsor.ajay.app";
+/// Segments are optional, so a slow API must not hold the episode
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CATEGORIES: [&str; 8] = [
+    "sponsor",
+    "intro",
+    "outro",
+    "selfpromo",
+    "interaction",
+    "preview",
+    "music_offtopic",
+    "filler",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Segment {
+    /// Start and end in seconds
+    segment: (f64, f64),
+    #[serde(default)]
+    action_type: Option<String>,
+}
+
+/// Client of the SponsorBlock API, base url can be changed with SPONSORBLOCK_API_URL
+pub struct SponsorBlock<'a> {
+    http_client: &'a Client,
+    api_url: String,
+}
+
+impl<'a> SponsorBlock<'a> {
+    pub fn new(http_client: &'a Client) -> Self {
+        let api_url = env::var("SPONSORBLOCK_API_URL")
+            .ok()
+            .filter(|u| !u.is_empty())
+            .unwrap_or(DEFAULT_API_URL.to_string());
+        Self::with_api_url(http_client, &api_url)
+    }
+
+    fn with_api_url(http_client: &'a Client, api_url: &str) -> Self {
+        Self {
+            http_client,
+            api_url: api_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns sorted not overlapping segments of the categories to cut out of the video
+    pub async fn segments(&self, video_id: &str, categories: &[String]) -> Result<Vec<(f64, f64)>> {
+        let response = self
+            .http_client
+            .get(format!("{}/api/skipSegments", self.api_url))
+            .query(&[
+                ("videoID", video_id.to_string()),
+                ("categories", serde_json::to_string(categories)?),
+            ])
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("Failed to request SponsorBlock segments of {}", video_id))?;
+        // There is no segments for the video
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        let segments: Vec<Segment> = response
+            .error_for_status()
+            .with_context(|| format!("SponsorBlock failed to return segments of {}", video_id))?
+            .json()
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to deserialize SponsorBlock segments of {}",
+                    video_id
+                )
+            })?;
+        Ok(merge_segments(
+            segments
+                .into_iter()
+                .filter(|s| s.action_type.as_deref().unwrap_or("skip") == "skip")
+                .map(|s| s.segment)
+                .collect(),
+        ))
+    }
+}
+
+fn merge_segments(mut segments: Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    segments.retain(|(start, end)| end > start);
+    segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = vec![];
+    for (start, end) in segments {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+pub fn is_category(category: &str) -> bool {
+    CATEGORIES.contains(&category)
+}
+
+pub fn removed_secs(segments: &[(f64, f64)]) -> f64 {
+    segments.iter().map(|(start, end)| end - start).sum()
+}
+
+impl PodcastHandler<'_> {
+    pub(crate) async fn set_sponsorblock(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        args: &[&str],
+    ) -> Result<()> {
+        let categories: Option<Vec<String>> = match args {
+            [] => None,
+            ["off"] => Some(vec![]),
+            _ => {
+                let categories: Vec<String> = args
+                    .iter()
+                    .flat_map(|a| a.split(','))
+                    .filter(|c| !c.is_empty())
+                    .map(|c| c.to_string())
+                    .collect();
+                if categories.iter().any(|c| !is_category(c)) {
+                    return self
+                        .reply(
+                            m,
+                            format!(
+                                "Используйте /sponsorblock <категории> или /sponsorblock off, категории: {}",
+                                CATEGORIES.join(", ")
+                            ),
+                        )
+                        .await;
+                }
+                Some(categories)
+            }
+        };
+        let settings = match categories {
+            Some(categories) => {
+                self.metadata
+                    .update_settings(&feed.settings_path(), |settings| {
+                        settings.sponsorblock = categories.clone()
+                    })
+                    .await?
+                    .0
+            }
+            None => self.metadata.load_settings(&feed.settings_path()).await?,
+        };
+        let mut text = if settings.sponsorblock.is_empty() {
+            "SponsorBlock выключен".to_string()
+        } else {
+            format!(
+                "SponsorBlock вырезает: {}",
+                settings.sponsorblock.join(", ")
+            )
+        };
+        if self.audio_processor.is_none() {
+            text.push_str("\nСегменты не вырезаются: не настроен FFMPEG_PATH");
+        }
+        self.reply(m, text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::extract::RawQuery;
+    use axum::routing::get;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// Serves the canned answer of /api/skipSegments and keeps the queries
+    async fn start_api(
+        status: StatusCode,
+        body: &'static str,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let queries = Arc::new(Mutex::new(vec![]));
+        let recorded = queries.clone();
+        let app = Router::new().route(
+            "/api/skipSegments",
+            get(move |RawQuery(query): RawQuery| async move {
+                recorded.lock().unwrap().push(query.unwrap_or_default());
+                (status, body)
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (api_url, queries)
+    }
+
+    /// Proxies of the environment must not intercept local requests
+    fn http_client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_segments() {
+        assert_eq!(
+            merge_segments(vec![(30.0, 40.0), (0.0, 10.0), (5.0, 12.0), (12.0, 15.0)]),
+            [(0.0, 15.0), (30.0, 40.0)]
+        );
+        // Nested segments don't shorten the outer one
+        assert_eq!(
+            merge_segments(vec![(10.0, 50.0), (20.0, 30.0)]),
+            [(10.0, 50.0)]
+        );
+        assert_eq!(
+            merge_segments(vec![(10.0, 20.0), (20.5, 30.0)]),
+            [(10.0, 20.0), (20.5, 30.0)]
+        );
+        // Empty and reversed segments are dropped
+        assert_eq!(merge_segments(vec![(5.0, 5.0), (9.0, 3.0)]), []);
+    }
+
+    #[test]
+    fn sums_removed_seconds() {
+        assert_eq!(removed_secs(&[]), 0.0);
+        assert_eq!(removed_secs(&[(0.0, 15.0), (30.0, 40.5)]), 25.5);
+    }
+
+    #[tokio::test]
+    async fn fetches_skip_segments_of_categories() {
+        let (api_url, queries) = start_api(
+            StatusCode::OK,
+            r#"[
+                {"segment":[60.0,90.0],"category":"sponsor","actionType":"skip"},
+                {"segment":[0.0,12.5],"category":"intro","actionType":"skip"},
+                {"segment":[85.0,100.0],"category":"sponsor"},
+                {"segment":[200.0,210.0],"category":"sponsor","actionType":"mute"}
+            ]"#,
+        )
+        .await;
+        let http_client = http_client();
+        // Mirror of the API with the trailing slash in SPONSORBLOCK_API_URL
+        let sponsorblock = SponsorBlock::with_api_url(&http_client, &format!("{}/", api_url));
+
+        let categories = ["sponsor".to_string(), "intro".to_string()];
+        let segments = sponsorblock.segments("abc", &categories).await.unwrap();
+        assert_eq!(segments, [(0.0, 12.5), (60.0, 100.0)]);
+
+        let query = queries.lock().unwrap()[0].to_string();
+        let url = reqwest::Url::parse(&format!("http://localhost/?{}", query)).unwrap();
+        let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(
+            params,
+            [
+                ("videoID".to_string(), "abc".to_string()),
+                (
+                    "categories".to_string(),
+                    r#"["sponsor","intro"]"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_no_segments_for_unknown_video() {
+        let (api_url, _) = start_api(StatusCode::NOT_FOUND, "Not Found").await;
+        let http_client = http_client();
+        let sponsorblock = SponsorBlock::with_api_url(&http_client, &api_url);
+
+        let categories = ["sponsor".to_string()];
+        assert!(
+            sponsorblock
+                .segments("abc", &categories)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Local directory for the state, which must not be kept in the podcast storage.
+/// Returns None if PODCAST_STATE_DIR is not configured.
+pub fn state_dir() -> Option<PathBuf> {
+    env::var("PODCAST_STATE_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+}
+
+/// JSON file in the state directory, changes of the file are serialized
+pub struct StateFile<T> {
+    path: PathBuf,
+    lock: Mutex<()>,
+    data: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> StateFile<T> {
+    /// Returns None if the state directory is not configured
+    pub fn new(name: &str) -> Option<Self> {
+        Some(Self::with_path(state_dir()?.join(name)))
+    }
+
+    fn with_path(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+            data: PhantomData,
+        }
+    }
+
+    pub async fn load(&self) -> Result<T> {
+        let _guard = self.lock.lock().await;
+        Ok(self.read().await?.0)
+    }
+
+    /// Applies the change to the stored data, the file is written only if it changes
+    pub async fn update<R>(&self, change: impl FnOnce(&mut T) -> R) -> Result<R> {
+        let _guard = self.lock.lock().await;
+        let (mut data, current) = self.read().await?;
+        let result = change(&mut data);
+        let updated = serde_json::to_vec_pretty(&data)?;
+        if updated != current {
+            replace_file(&self.path, updated).await?;
+        }
+        Ok(result)
+    }
+
+    /// Returns the data along with the current content of the file,
+    /// missing file is the same as the default data
+    async fn read(&self) -> Result<(T, Vec<u8>)> {
+        match read_file(&self.path).await? {
+            Some(d) => Ok((
+                serde_json::from_slice(&d)
+                    .with_context(|| format!("Failed to parse {}", self.path.display()))?,
+                d,
+            )),
+            None => {
+                let data = T::default();
+                let content = serde_json::to_vec_pretty(&data)?;
+                Ok((data, content))
+            }
+        }
+    }
+}
+
+/// Returns None if the file doesn't exist
+pub async fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(d) => Ok(Some(d)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Readers never see a partially written file, since it is moved in place
+pub async fn replace_file(path: &Path, data: Vec<u8>) -> Result<()> {
+    create_parent(path).await?;
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(format!(".{}.tmp", hex::encode(rand::random::<[u8; 8]>())));
+    fs::write(&tmp_path, data)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to move {} in place", path.display()))
+}
+
+pub async fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_only_changed_data() {
+        let dir = env::temp_dir().join(format!(
+            "state-file-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let path = dir.join("state").join("numbers.json");
+        let file = StateFile::<Vec<u32>>::with_path(path.clone());
+
+        assert_eq!(file.load().await.unwrap(), Vec::<u32>::new());
+        // Nothing is written for the unchanged default
+        file.update(|_| ()).await.unwrap();
+        assert!(!fs::try_exists(&path).await.unwrap());
+
+        file.update(|n| n.push(1)).await.unwrap();
+        assert_eq!(file.update(|n| n.len()).await.unwrap(), 1);
+        assert_eq!(file.load().await.unwrap(), [1]);
+
+        // Temporary files don't stay next to the state
+        let mut entries = fs::read_dir(dir.join("state")).await.unwrap();
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        assert_eq!(names, ["numbers.json"]);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use super::local_storage::LocalStorage;
+use super::s3_storage::S3Storage;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Storage of podcast audio files, metadata and rss feeds
+#[async_trait]
+pub trait PodcastStorage {
+    /// Returns None if there is no object with the path
+    async fn download_object(&self, path: &str) -> Result<Option<Vec<u8>>>;
+
+    async fn upload_object(&self, data: Vec<u8>, path: &str) -> Result<()>;
+
+    /// Returns the object together with its version (ETag),
+    /// None if there is no object with the path
+    async fn download_versioned(&self, path: &str) -> Result<Option<(Vec<u8>, String)>>;
+
+    /// Writes the object only if it still has the expected version,
+    /// None version expects that the object doesn't exist yet.
+    /// Returns false if the object was changed by someone else.
+    async fn upload_if_version(
+        &self,
+        data: Vec<u8>,
+        path: &str,
+        version: Option<&str>,
+    ) -> Result<bool>;
+
+    async fn upload_file(&self, file: PathBuf, path: String) -> Result<()>;
+
+    async fn copy_object(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Deleting of an absent object is not an error
+    async fn delete_object(&self, path: &str) -> Result<()>;
+
+    fn get_public_url(&self, path: &str) -> String;
+
+    /// Url of the object, which works for a while even if the storage isn't public
+    async fn get_presigned_url(&self, path: &str, expires_in: Duration) -> Result<String>;
+
+    /// Serves objects over http for storages which are not public by themselves
+    async fn serve(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn from_env() -> Arc<dyn PodcastStorage + Send + Sync> {
+    match env::var("PODCAST_STORAGE").as_deref() {
+        Ok("local") => Arc::new(LocalStorage::new()),
+        Ok("s3") | Ok("") | Err(_) => Arc::new(S3Storage::new()),
+        Ok(other) => panic!("Unknown PODCAST_STORAGE {}, use s3 or local please", other),
+    }
+}
@@ -0,0 +1,382 @@
+use super::feeds::{Feed, feeds_index_path};
+use super::state_file::StateFile;
+use super::{DuplicateEpisode, PodcastHandler, message_user, parse_index};
+use anyhow::Result;
+use chrono::Utc;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::SystemTime;
+use telegram_api::{Message, SendMessage, User};
+
+/// Channels are listed from the newest videos, older ones are already seen
+const CHANNEL_CHECK_LIMIT: usize = 30;
+pub const SUBSCRIPTIONS_DISABLED: &str = "Подписки не настроены: укажите PODCAST_STATE_DIR";
+
+/// Feed, which is automatically updated with new videos of a playlist or a channel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub user_id: i32,
+    pub user_first_name: String,
+    /// Chat for notifications about new episodes
+    pub chat_id: i64,
+    pub feed: String,
+    pub url: String,
+    pub title: String,
+    /// Ids of videos, which were already in the playlist at the last check
+    pub seen: HashSet<String>,
+    pub created_at: SystemTime,
+}
+
+impl Subscription {
+    /// Feeds are located by the user, the other fields are not used there
+    pub fn user(&self) -> User {
+        User {
+            id: self.user_id,
+            is_bot: false,
+            first_name: self.user_first_name.to_string(),
+            last_name: None,
+            username: None,
+        }
+    }
+
+    /// The same playlist can be subscribed to by several users and feeds
+    pub fn is_same(&self, other: &Subscription) -> bool {
+        self.user_id == other.user_id && self.feed == other.feed && self.url == other.url
+    }
+}
+
+/// Subscriptions of all users are kept in the local state directory,
+/// since the podcast storage can be public
+pub struct Subscriptions {
+    file: StateFile<Vec<Subscription>>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            file: StateFile::new("subscriptions.json")?,
+        })
+    }
+
+    pub async fn load(&self) -> Result<Vec<Subscription>> {
+        self.file.load().await
+    }
+
+    /// Applies the change to the stored subscriptions
+    pub async fn update<R>(&self, change: impl FnOnce(&mut Vec<Subscription>) -> R) -> Result<R> {
+        self.file.update(change).await
+    }
+}
+
+impl PodcastHandler<'_> {
+    /// Subscribes the feed to the playlist or the channel. Videos, which are
+    /// already there, are marked as seen: only new ones are added to the feed.
+    pub(crate) async fn subscribe(&self, m: &Message, url: &str, feed: &Feed) -> Result<()> {
+        let Some(subscriptions) = &self.subscriptions else {
+            return self.reply(m, SUBSCRIPTIONS_DISABLED.to_string()).await;
+        };
+        let (subscription, created) = self.add_subscription(subscriptions, m, url, feed).await?;
+        if created {
+            self.reply(
+                m,
+                format!(
+                    "Подписка на {} оформлена, новые видео будут добавляться в фид {}",
+                    subscription.title, subscription.feed
+                ),
+            )
+            .await
+        } else {
+            self.reply(
+                m,
+                format!(
+                    "Фид {} уже подписан на {}",
+                    subscription.feed, subscription.title
+                ),
+            )
+            .await
+        }
+    }
+
+    /// Returns the subscription and false if the feed was already subscribed
+    pub(crate) async fn add_subscription(
+        &self,
+        subscriptions: &Subscriptions,
+        m: &Message,
+        url: &str,
+        feed: &Feed,
+    ) -> Result<(Subscription, bool)> {
+        let user = message_user(m)?;
+        let url = subscription_listing_url(url);
+        let playlist = self.yt_dlp.list_playlist(&url, listing_limit(&url)).await?;
+        let subscription = Subscription {
+            user_id: user.id,
+            user_first_name: user.first_name.to_string(),
+            chat_id: m.chat.id,
+            feed: feed.name.to_string(),
+            title: playlist.title.unwrap_or(url.to_string()),
+            url,
+            seen: playlist.entries.into_iter().map(|e| e.id).collect(),
+            created_at: SystemTime::now(),
+        };
+        let created = subscriptions
+            .update(|subscriptions| {
+                let created = !subscriptions.iter().any(|s| s.is_same(&subscription));
+                if created {
+                    subscriptions.push(subscription.clone());
+                }
+                created
+            })
+            .await?;
+        Ok((subscription, created))
+    }
+
+    pub(crate) async fn list_subscriptions(&self, m: &Message) -> Result<()> {
+        let Some(subscriptions) = &self.subscriptions else {
+            return self.reply(m, SUBSCRIPTIONS_DISABLED.to_string()).await;
+        };
+        let user = message_user(m)?;
+        let lines: Vec<String> = subscriptions
+            .load()
+            .await?
+            .iter()
+            .filter(|s| s.user_id == user.id)
+            .enumerate()
+            .map(|(i, s)| format!("{}. {} -> {}\n{}", i + 1, s.title, s.feed, s.url))
+            .collect();
+        if lines.is_empty() {
+            return self.reply(m, "У вас нет подписок".to_string()).await;
+        }
+        self.reply(m, lines.join("\n")).await
+    }
+
+    pub(crate) async fn unsubscribe(&self, m: &Message, n: Option<&str>) -> Result<()> {
+        let Some(subscriptions) = &self.subscriptions else {
+            return self.reply(m, SUBSCRIPTIONS_DISABLED.to_string()).await;
+        };
+        let user = message_user(m)?;
+        let Some(n) = n else {
+            return self
+                .reply(
+                    m,
+                    "Используйте /unsubscribe <номер из /subscriptions>".to_string(),
+                )
+                .await;
+        };
+        let removed = subscriptions
+            .update(|subscriptions| {
+                let positions: Vec<usize> = subscriptions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, s)| s.user_id == user.id)
+                    .map(|(i, _)| i)
+                    .collect();
+                parse_index(n, positions.len()).map(|i| subscriptions.remove(positions[i]))
+            })
+            .await?;
+        match removed {
+            Some(s) => {
+                self.reply(m, format!("Подписка на {} отменена", s.title))
+                    .await
+            }
+            None => self.reply(m, format!("Нет подписки с номером {}", n)).await,
+        }
+    }
+
+    /// Periodically adds new videos of subscribed playlists and channels to the feeds
+    pub(crate) async fn check_subscriptions(&self) -> Result<()> {
+        let Some(subscriptions) = &self.subscriptions else {
+            return Ok(());
+        };
+        let mut interval = tokio::time::interval(self.subscriptions_check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let all = match subscriptions.load().await {
+                Ok(all) => all,
+                Err(e) => {
+                    error!("Failed to load podcast subscriptions: {:?}", e);
+                    continue;
+                }
+            };
+            for subscription in all {
+                if let Err(e) = self.check_subscription(subscriptions, &subscription).await {
+                    warn!(
+                        "Failed to check subscription {} of the feed {}: {:?}",
+                        subscription.url, subscription.feed, e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn check_subscription(
+        &self,
+        subscriptions: &Subscriptions,
+        subscription: &Subscription,
+    ) -> Result<()> {
+        let user = subscription.user();
+        let feed = self.roots.feed(&user, &subscription.feed);
+        if !feed.is_default()
+            && !self
+                .metadata
+                .load_feeds(&feeds_index_path(&feed.root))
+                .await?
+                .contains(&subscription.feed)
+        {
+            warn!(
+                "Feed {} of the subscription {} doesn't exist",
+                subscription.feed, subscription.url
+            );
+            return Ok(());
+        }
+
+        let playlist = self
+            .yt_dlp
+            .list_playlist(&subscription.url, listing_limit(&subscription.url))
+            .await?;
+        let mut new_entries: Vec<_> = playlist
+            .entries
+            .iter()
+            .filter(|e| !subscription.seen.contains(&e.id))
+            .collect();
+        // Channels list the newest videos first, feed gets them in the order of publishing
+        if is_channel(&subscription.url) {
+            new_entries.reverse();
+        }
+        for entry in new_entries {
+            let url = entry.video_url();
+            let text = match self
+                .process_url(&url, &feed, Utc::now().timestamp_millis(), false)
+                .await
+            {
+                Err(e) if e.is::<DuplicateEpisode>() => None,
+                Ok(_) => Some(format!(
+                    "Новый эпизод {} из {} добавлен в фид {}",
+                    entry.title.as_deref().unwrap_or(&url),
+                    subscription.title,
+                    subscription.feed
+                )),
+                Err(e) => {
+                    warn!("Failed to add {} to the feed: {:?}", url, e);
+                    Some(format!(
+                        "Не удалось добавить {} из {} в фид {}",
+                        url, subscription.title, subscription.feed
+                    ))
+                }
+            };
+            // Failed videos are not retried on every check
+            subscriptions
+                .update(|subscriptions| {
+                    for s in subscriptions.iter_mut().filter(|s| s.is_same(subscription)) {
+                        s.seen.insert(entry.id.to_string());
+                    }
+                })
+                .await?;
+            // Videos already added to the feed by hand are skipped silently
+            let Some(text) = text else {
+                continue;
+            };
+            self.telegram_client
+                .async_send_message(SendMessage {
+                    chat_id: subscription.chat_id.to_string(),
+                    text,
+                    reply_to_message_id: None,
+                    reply_markup: None,
+                })
+                .await?;
+        }
+
+        // Videos removed from the playlist are forgotten, so seen ids don't grow forever
+        let listed: HashSet<String> = playlist.entries.into_iter().map(|e| e.id).collect();
+        subscriptions
+            .update(|subscriptions| {
+                for s in subscriptions.iter_mut().filter(|s| s.is_same(subscription)) {
+                    s.seen.retain(|id| listed.contains(id));
+                }
+            })
+            .await
+    }
+}
+
+/// Playlists and channels are subscribed to instead of being downloaded at once
+pub fn is_youtube_collection(url: &str) -> bool {
+    let Some(path) = [
+        "https://www.youtube.com/",
+        "https://youtube.com/",
+        "https://m.youtube.com/",
+    ]
+    .iter()
+    .find_map(|prefix| url.strip_prefix(prefix)) else {
+        return false;
+    };
+    path.starts_with("playlist?")
+        || ["@", "channel/", "c/", "user/"]
+            .iter()
+            .any(|p| path.starts_with(p))
+}
+
+fn is_channel(url: &str) -> bool {
+    !url.contains("/playlist?")
+}
+
+/// Root page of a channel lists its tabs, so the videos tab is subscribed to
+pub fn subscription_listing_url(url: &str) -> String {
+    if !is_channel(url) {
+        return url.to_string();
+    }
+    let url = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .trim_end_matches('/');
+    if ["/videos", "/streams", "/shorts"]
+        .iter()
+        .any(|tab| url.ends_with(tab))
+    {
+        url.to_string()
+    } else {
+        format!("{}/videos", url)
+    }
+}
+
+/// Playlists are listed in full, channels only with the latest videos
+fn listing_limit(url: &str) -> Option<usize> {
+    is_channel(url).then_some(CHANNEL_CHECK_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_playlists_and_channels() {
+        assert!(is_youtube_collection(
+            "https://www.youtube.com/playlist?list=PL123"
+        ));
+        assert!(is_youtube_collection("https://youtube.com/@channel"));
+        assert!(is_youtube_collection(
+            "https://m.youtube.com/channel/UC123/videos"
+        ));
+        assert!(!is_youtube_collection(
+            "https://www.youtube.com/watch?v=abc"
+        ));
+        assert!(!is_youtube_collection("https://example.com/@channel"));
+    }
+
+    #[test]
+    fn subscribes_to_videos_tab_of_channels() {
+        assert_eq!(
+            subscription_listing_url("https://www.youtube.com/@channel/?si=123"),
+            "https://www.youtube.com/@channel/videos"
+        );
+        assert_eq!(
+            subscription_listing_url("https://www.youtube.com/@channel/streams"),
+            "https://www.youtube.com/@channel/streams"
+        );
+        assert_eq!(
+            subscription_listing_url("https://www.youtube.com/playlist?list=PL123"),
+            "https://www.youtube.com/playlist?list=PL123"
+        );
+    }
+}
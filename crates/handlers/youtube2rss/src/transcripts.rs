@@ -0,0 +1,485 @@
+use super::chapters;
+use super::feeds::Feed;
+use super::yt_dlp::VideoInfo;
+use super::{PodcastHandler, parse_index};
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use shlex::Shlex;
+use std::env;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use telegram_api::{FileKind, Message};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+pub const TRANSCRIPT_MIME_TYPE: &str = "text/vtt";
+/// Local language models are slow on CPU, but must not hang the episode forever
+const SUMMARY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Whisper is slower than the audio on CPU with large models
+const TRANSCRIBE_TIMEOUT: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// Where the transcript of the episode comes from
+#[derive(Clone)]
+pub enum Transcript {
+    Disabled,
+    /// Cues already moved to the timeline of the processed audio
+    Subtitles(Vec<Cue>),
+    SpeechToText,
+}
+
+impl Transcript {
+    pub fn slice(&self, start: f64, end: f64) -> Self {
+        match self {
+            Transcript::Subtitles(cues) => Transcript::Subtitles(slice(cues, start, end)),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Cue of subtitles, times are in seconds
+#[derive(Clone, Debug)]
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Parses WebVTT or SRT subtitles. Auto-generated subtitles repeat the
+/// previous line in every cue, so repeated lines are dropped.
+pub fn parse(subtitles: &str) -> Vec<Cue> {
+    let mut cues: Vec<Cue> = vec![];
+    let mut last_line = String::new();
+    let mut current: Option<Cue> = None;
+    for line in subtitles.lines() {
+        // Cues are separated by empty lines, numbers and ids of cues go before the times
+        if line.is_empty() {
+            cues.extend(current.take().filter(|c| !c.text.is_empty()));
+            continue;
+        }
+        let line = line.trim();
+        if let Some((start, end)) = line.split_once("-->") {
+            cues.extend(current.take().filter(|c| !c.text.is_empty()));
+            // Cue settings can follow the end time
+            let end = end.split_whitespace().next().unwrap_or_default();
+            current = match (parse_time(start.trim()), parse_time(end)) {
+                (Some(start), Some(end)) => Some(Cue {
+                    start,
+                    end,
+                    text: String::new(),
+                }),
+                _ => None,
+            };
+            continue;
+        }
+        let Some(cue) = current.as_mut() else {
+            continue;
+        };
+        let text = strip_tags(line);
+        if text.is_empty() || text == last_line {
+            continue;
+        }
+        if !cue.text.is_empty() {
+            cue.text.push('\n');
+        }
+        cue.text.push_str(&text);
+        last_line = text;
+    }
+    cues.extend(current.filter(|c| !c.text.is_empty()));
+    cues
+}
+
+/// Moves cues to the timeline of the processed audio like chapters
+pub fn adjust(cues: &[Cue], cuts: &[(f64, f64)], speed: f32) -> Vec<Cue> {
+    cues.iter()
+        .map(|c| Cue {
+            start: chapters::position(c.start, cuts, speed),
+            end: chapters::position(c.end, cuts, speed),
+            text: c.text.to_string(),
+        })
+        .filter(|c| c.end > c.start)
+        .collect()
+}
+
+/// Cues of the part of the audio moved to the timeline of the part
+pub fn slice(cues: &[Cue], start: f64, end: f64) -> Vec<Cue> {
+    cues.iter()
+        .map(|c| Cue {
+            start: c.start.max(start) - start,
+            end: c.end.min(end) - start,
+            text: c.text.to_string(),
+        })
+        .filter(|c| c.end > c.start)
+        .collect()
+}
+
+pub fn to_vtt(cues: &[Cue]) -> String {
+    let mut vtt = "WEBVTT\n".to_string();
+    for cue in cues {
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            format_time(cue.start),
+            format_time(cue.end),
+            cue.text
+        ));
+    }
+    vtt
+}
+
+pub fn to_text(cues: &[Cue]) -> String {
+    cues.iter()
+        .map(|c| c.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// hh:mm:ss.mmm or mm:ss.mmm of WebVTT, SRT uses a comma before milliseconds
+fn parse_time(time: &str) -> Option<f64> {
+    let mut secs = 0.0;
+    for part in time.replace(',', ".").split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(secs)
+}
+
+fn format_time(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn strip_tags(line: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Local speech recognition with the whisper.cpp cli or a compatible binary,
+/// which accepts -m <model> -f <audio> -ovtt -of <output without extension>
+pub struct SpeechToText {
+    path: String,
+    model: String,
+    opts: Vec<String>,
+}
+
+impl SpeechToText {
+    /// Returns None if WHISPER_PATH is not provided
+    pub fn new() -> Option<Self> {
+        let path = env::var("WHISPER_PATH").ok().filter(|p| !p.is_empty())?;
+        let model =
+            env::var("WHISPER_MODEL").expect("Provide WHISPER_MODEL environment variable please");
+        let opts = Shlex::new(&env::var("WHISPER_OPTS").unwrap_or(String::from("")))
+            .collect::<Vec<String>>();
+        Some(Self { path, model, opts })
+    }
+
+    pub async fn transcribe(&self, audio: &Path) -> Result<Vec<Cue>> {
+        let output = audio.with_extension("transcript");
+        let vtt_path = output.with_extension("transcript.vtt");
+        // Process is killed when the output is dropped by the timeout
+        let res = timeout(
+            TRANSCRIBE_TIMEOUT,
+            Command::new(&self.path)
+                .args(&self.opts)
+                .args(["-m", &self.model, "-ovtt", "-f"])
+                .arg(audio)
+                .arg("-of")
+                .arg(&output)
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await;
+        let Ok(res) = res else {
+            // Partial transcript may be left by the killed process
+            let _ = fs::remove_file(&vtt_path).await;
+            return Err(anyhow!(
+                "Speech-to-text for {} didn't finish in {:?}",
+                audio.display(),
+                TRANSCRIBE_TIMEOUT
+            ));
+        };
+        let res = res
+            .with_context(|| format!("Failed to execute speech-to-text for {}", audio.display()))?;
+        if !res.status.success() {
+            return Err(anyhow!(
+                "Exit code of speech-to-text command was not 0, output: {:?}",
+                res
+            ));
+        }
+        let vtt = fs::read_to_string(&vtt_path)
+            .await
+            .with_context(|| format!("Failed to read transcript {}", vtt_path.display()));
+        if let Err(e) = fs::remove_file(&vtt_path).await {
+            warn!(
+                "Failed to remove temporary file {}: {}",
+                vtt_path.display(),
+                e
+            );
+        }
+        Ok(parse(&vtt?))
+    }
+}
+
+/// Command, which reads the transcript from stdin and prints the summary,
+/// e.g. a script running a local language model
+pub struct Summarizer {
+    command: Vec<String>,
+}
+
+impl Summarizer {
+    /// Returns None if PODCAST_SUMMARY_COMMAND is not provided
+    pub fn new() -> Option<Self> {
+        let command =
+            Shlex::new(&env::var("PODCAST_SUMMARY_COMMAND").ok()?).collect::<Vec<String>>();
+        (!command.is_empty()).then_some(Self { command })
+    }
+
+    pub async fn summarize(&self, text: &str) -> Result<String> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to execute the summary command")?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or(anyhow!("Summary command has no stdin"))?;
+        // Output is read while the transcript is written, so a command printing
+        // before it reads the whole input doesn't block on a full pipe
+        let write = async {
+            let written = stdin
+                .write_all(text.as_bytes())
+                .await
+                .context("Failed to write the transcript to the summary command");
+            drop(stdin);
+            written
+        };
+        let (written, res) = timeout(SUMMARY_TIMEOUT, async {
+            tokio::join!(write, child.wait_with_output())
+        })
+        .await
+        .map_err(|_| anyhow!("Summary command didn't finish in {:?}", SUMMARY_TIMEOUT))?;
+        let res = res.context("Failed to wait for the summary command")?;
+        // Commands may exit before reading the whole transcript
+        if let Err(e) = written {
+            warn!("{:?}", e);
+        }
+        if res.status.success() {
+            Ok(String::from_utf8_lossy(&res.stdout).trim().to_string())
+        } else {
+            Err(anyhow!(
+                "Exit code of the summary command was not 0, output: {:?}",
+                res
+            ))
+        }
+    }
+}
+
+impl PodcastHandler<'_> {
+    /// Returns cues of the downloaded subtitles in the preferred language
+    /// and removes their files
+    pub(crate) fn read_subtitles(&self, info: &VideoInfo) -> Option<Vec<Cue>> {
+        let mut cues = None;
+        for path in self.yt_dlp.subtitles_paths(info) {
+            match std::fs::read_to_string(path) {
+                Ok(subtitles) if cues.is_none() => {
+                    cues = Some(parse(&subtitles)).filter(|c| !c.is_empty())
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read subtitles {}: {}", path.display(), e),
+            }
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove temporary file {}: {}", path.display(), e);
+            }
+        }
+        cues
+    }
+
+    /// Speech recognition is slow and optional, so failures only skip the transcript
+    pub(crate) async fn transcribe(&self, file: &Path) -> Option<Vec<Cue>> {
+        let speech_to_text = self.speech_to_text.as_ref()?;
+        let result = match &self.audio_processor {
+            Some(processor) => match processor.to_wav(file).await {
+                Ok(wav) => {
+                    let result = speech_to_text.transcribe(&wav).await;
+                    if let Err(e) = std::fs::remove_file(&wav) {
+                        warn!("Failed to remove temporary file {}: {}", wav.display(), e);
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            },
+            None => speech_to_text.transcribe(file).await,
+        };
+        match result {
+            Ok(cues) => Some(cues).filter(|c| !c.is_empty()),
+            Err(e) => {
+                warn!("Failed to transcribe {}: {:?}", file.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Summary of the transcript, if the summary command is configured
+    pub(crate) async fn summarize(&self, episode_id: &str, transcript: &str) -> Option<String> {
+        let summarizer = self.summarizer.as_ref()?;
+        if transcript.is_empty() {
+            return None;
+        }
+        match summarizer.summarize(transcript).await {
+            Ok(summary) => Some(summary).filter(|s| !s.is_empty()),
+            Err(e) => {
+                warn!("Failed to summarize {}: {:?}", episode_id, e);
+                None
+            }
+        }
+    }
+
+    pub(crate) async fn set_transcripts(
+        &self,
+        m: &Message,
+        feed: &Feed,
+        value: Option<&str>,
+    ) -> Result<()> {
+        let transcripts = match value {
+            None => None,
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            Some(_) => {
+                return self
+                    .reply(
+                        m,
+                        "Используйте /transcripts on или /transcripts off".to_string(),
+                    )
+                    .await;
+            }
+        };
+        let settings = match transcripts {
+            Some(transcripts) => {
+                self.metadata
+                    .update_settings(&feed.settings_path(), |settings| {
+                        settings.transcripts = transcripts
+                    })
+                    .await?
+                    .0
+            }
+            None => self.metadata.load_settings(&feed.settings_path()).await?,
+        };
+        let mut text = if settings.transcripts {
+            "К эпизодам добавляется расшифровка".to_string()
+        } else {
+            "Расшифровка эпизодов выключена".to_string()
+        };
+        if !self.yt_dlp.downloads_subtitles() && self.speech_to_text.is_none() {
+            text.push_str(
+                "\nРасшифровка не создается: не настроены PODCAST_SUBTITLES_LANGS и WHISPER_PATH",
+            );
+        }
+        self.reply(m, text).await
+    }
+
+    pub(crate) async fn send_transcript(&self, m: &Message, feed: &Feed, n: &str) -> Result<()> {
+        let metadata = self.metadata.load_metadata(&feed.metadata_path()).await?;
+        let Some(item) = parse_index(n, metadata.len()).and_then(|i| metadata.get(i)) else {
+            return self.reply(m, format!("Нет эпизода с номером {}", n)).await;
+        };
+        let vtt = match &item.transcript_path {
+            Some(path) => self.storage.download_object(path).await?,
+            None => None,
+        };
+        let Some(vtt) = vtt else {
+            return self.reply(m, "У эпизода нет расшифровки".to_string()).await;
+        };
+        let text = to_text(&parse(&String::from_utf8_lossy(&vtt)));
+        self.telegram_client
+            .async_send_bytes(
+                &m.chat.id.to_string(),
+                format!("{}.txt", item.name),
+                text.into_bytes(),
+                FileKind::Document,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: f64, end: f64, text: &str) -> Cue {
+        Cue {
+            start,
+            end,
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(cues: &[Cue]) -> Vec<&str> {
+        cues.iter().map(|c| c.text.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_auto_generated_vtt() {
+        let vtt = "WEBVTT\nKind: captions\n\n\
+                   00:00:01.000 --> 00:00:02.500 align:start position:0%\n\
+                   hello<00:00:01.500><c> world</c>\n\n\
+                   00:00:02.500 --> 00:00:04.000\n\
+                   hello world\n\
+                   next line\n";
+        let cues = parse(vtt);
+        assert_eq!(texts(&cues), ["hello world", "next line"]);
+        assert_eq!((cues[1].start, cues[1].end), (2.5, 4.0));
+    }
+
+    #[test]
+    fn parses_srt() {
+        let srt =
+            "1\n00:01:02,500 --> 00:01:03,000\nFirst\n\n2\n01:00:00,000 --> 01:00:01,000\nSecond\n";
+        let cues = parse(srt);
+        assert_eq!(texts(&cues), ["First", "Second"]);
+        assert_eq!((cues[0].start, cues[1].start), (62.5, 3600.0));
+    }
+
+    #[test]
+    fn writes_vtt_readable_back() {
+        let cues = vec![cue(0.0, 1.25, "one"), cue(3661.5, 3662.0, "two\nlines")];
+        let vtt = to_vtt(&cues);
+        assert!(vtt.contains("01:01:01.500 --> 01:01:02.000"));
+        let parsed = parse(&vtt);
+        assert_eq!(texts(&parsed), ["one", "two\nlines"]);
+        assert_eq!(parsed[1].start, 3661.5);
+    }
+
+    #[test]
+    fn slices_cues_of_the_part() {
+        let cues = vec![
+            cue(0.0, 10.0, "before"),
+            cue(55.0, 65.0, "crossing"),
+            cue(70.0, 80.0, "inside"),
+            cue(130.0, 140.0, "after"),
+        ];
+        let part = slice(&cues, 60.0, 120.0);
+        assert_eq!(texts(&part), ["crossing", "inside"]);
+        assert_eq!((part[0].start, part[0].end), (0.0, 5.0));
+        assert_eq!((part[1].start, part[1].end), (10.0, 20.0));
+    }
+}
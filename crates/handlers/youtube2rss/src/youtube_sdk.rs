@@ -6,15 +6,16 @@ use std::{collections::VecDeque, env::var};
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Resp {
-    pub items: VecDeque<Item>,
+    pub items: VecDeque<Video>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct Item {
+pub struct Video {
     #[expect(unused)]
     pub id: String,
     pub snippet: Snippet,
+    pub content_details: ContentDetails,
 }
 
 #[derive(Debug, Deserialize)]
@@ -24,9 +25,80 @@ pub struct Snippet {
     pub published_at: String,
     #[expect(unused)]
     pub channel_id: String,
-    pub title: String,
     #[expect(unused)]
+    pub title: String,
     pub description: String,
+    #[serde(default)]
+    pub thumbnails: Thumbnails,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Thumbnails {
+    pub default: Option<Thumbnail>,
+    pub medium: Option<Thumbnail>,
+    pub high: Option<Thumbnail>,
+    pub standard: Option<Thumbnail>,
+    pub maxres: Option<Thumbnail>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Thumbnail {
+    pub url: String,
+}
+
+impl Thumbnails {
+    /// Url of the thumbnail with the highest available resolution
+    pub fn best_url(&self) -> Option<&str> {
+        [
+            &self.maxres,
+            &self.standard,
+            &self.high,
+            &self.medium,
+            &self.default,
+        ]
+        .into_iter()
+        .flatten()
+        .next()
+        .map(|t| t.url.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentDetails {
+    /// ISO 8601 duration, e.g. PT1H2M3S
+    pub duration: String,
+}
+
+impl ContentDetails {
+    pub fn duration_secs(&self) -> Option<u64> {
+        parse_iso8601_duration(&self.duration)
+    }
+}
+
+/// Parses durations of the form P[nD]T[nH][nM][nS], which YouTube returns
+fn parse_iso8601_duration(s: &str) -> Option<u64> {
+    let s = s.strip_prefix('P')?;
+    let mut secs = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if number.is_empty() => {}
+            'D' | 'H' | 'M' | 'S' => {
+                let multiplier = match c {
+                    'D' => 24 * 60 * 60,
+                    'H' => 60 * 60,
+                    'M' => 60,
+                    _ => 1,
+                };
+                secs += number.parse::<u64>().ok()? * multiplier;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    number.is_empty().then_some(secs)
 }
 
 pub struct YoutubeSdk {
@@ -35,18 +107,18 @@ pub struct YoutubeSdk {
 }
 
 impl YoutubeSdk {
-    pub fn new() -> Self {
-        let api_key =
-            { var("GOOGLE_API_KEY").expect("Provide GOOGLE_API_KEY environment variable please") };
-        Self {
+    /// Returns None if GOOGLE_API_KEY is not provided, yt-dlp is enough to get video info
+    pub fn new() -> Option<Self> {
+        let api_key = var("GOOGLE_API_KEY").ok().filter(|k| !k.is_empty())?;
+        Some(Self {
             http_client: Client::new(),
             api_key,
-        }
+        })
     }
 
-    pub async fn get_video_info(&self, video_id: &str) -> Result<Option<Snippet>> {
+    pub async fn get_video_info(&self, video_id: &str) -> Result<Option<Video>> {
         let url = format!(
-            "https://www.googleapis.com/youtube/v3/videos?part=snippet&id={}&key={}",
+            "https://www.googleapis.com/youtube/v3/videos?part=snippet,contentDetails&id={}&key={}",
             video_id, self.api_key
         );
 
@@ -69,6 +141,61 @@ impl YoutubeSdk {
                     video_id
                 )
             })?;
-        Ok(resp.items.pop_front().map(|i| i.snippet))
+        Ok(resp.items.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_youtube_durations() {
+        assert_eq!(parse_iso8601_duration("PT1H2M3S"), Some(3723));
+        assert_eq!(parse_iso8601_duration("PT15M"), Some(900));
+        assert_eq!(parse_iso8601_duration("PT45S"), Some(45));
+        assert_eq!(parse_iso8601_duration("P1DT2H"), Some(93600));
+        // Live streams have no duration
+        assert_eq!(parse_iso8601_duration("P0D"), Some(0));
+
+        assert_eq!(parse_iso8601_duration("1H2M"), None);
+        assert_eq!(parse_iso8601_duration("PT1.5S"), None);
+        assert_eq!(parse_iso8601_duration("PT5"), None);
+        assert_eq!(parse_iso8601_duration("PTH"), None);
+        assert_eq!(parse_iso8601_duration("P1W"), None);
+    }
+
+    #[test]
+    fn picks_the_largest_thumbnail() {
+        let snippet: Snippet = serde_json::from_str(
+            r#"{
+                "publishedAt": "2024-05-01T10:00:00Z",
+                "channelId": "UC123",
+                "title": "Video",
+                "description": "",
+                "thumbnails": {
+                    "default": {"url": "https://i.ytimg.com/vi/abc/default.jpg", "width": 120, "height": 90},
+                    "medium": {"url": "https://i.ytimg.com/vi/abc/mqdefault.jpg", "width": 320, "height": 180},
+                    "high": {"url": "https://i.ytimg.com/vi/abc/hqdefault.jpg", "width": 480, "height": 360}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            snippet.thumbnails.best_url(),
+            Some("https://i.ytimg.com/vi/abc/hqdefault.jpg")
+        );
+
+        let thumbnails = Thumbnails {
+            default: Some(Thumbnail {
+                url: "default.jpg".to_string(),
+            }),
+            maxres: Some(Thumbnail {
+                url: "maxresdefault.jpg".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(thumbnails.best_url(), Some("maxresdefault.jpg"));
+        assert_eq!(Thumbnails::default().best_url(), None);
     }
 }
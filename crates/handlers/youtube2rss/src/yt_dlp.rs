@@ -0,0 +1,398 @@
+use super::chapters::Chapter;
+use anyhow::{Context, Result, anyhow};
+use chrono::NaiveDate;
+use log::warn;
+use serde::Deserialize;
+use shlex::Shlex;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::SystemTime;
+use tokio::process::Command;
+
+/// Wrapper of the yt-dlp (or compatible youtube-dl fork) binary
+pub struct YtDlp {
+    path: String,
+    opts: Vec<String>,
+    /// Subtitles of these languages are downloaded with the audio for the search
+    subtitles_langs: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Playlist {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlaylistEntry {
+    pub id: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Fields of the yt-dlp info dict (the one of --dump-json) used for episodes.
+/// Only id and title are provided by every extractor.
+#[derive(Debug, Deserialize)]
+pub struct VideoInfo {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub extractor: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    /// YYYYMMDD
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    #[serde(default)]
+    pub chapters: Option<Vec<Chapter>>,
+    /// Downloaded subtitles by the language
+    #[serde(default)]
+    pub requested_subtitles: Option<HashMap<String, SubtitlesFile>>,
+    /// Final path of the downloaded file
+    pub filepath: PathBuf,
+    pub ext: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubtitlesFile {
+    /// Set only if the subtitles were written
+    #[serde(default)]
+    pub filepath: Option<PathBuf>,
+}
+
+/// What yt-dlp knows about the url without downloading it
+#[derive(Debug, Deserialize)]
+pub struct UrlInfo {
+    pub extractor: String,
+    /// playlist for playlists, url or video for single videos
+    #[serde(rename = "_type", default)]
+    pub kind: Option<String>,
+}
+
+impl UrlInfo {
+    pub fn is_playlist(&self) -> bool {
+        self.kind.as_deref() == Some("playlist")
+    }
+}
+
+impl VideoInfo {
+    pub fn published_at(&self) -> Option<SystemTime> {
+        let date = NaiveDate::parse_from_str(self.upload_date.as_deref()?, "%Y%m%d").ok()?;
+        Some(date.and_hms_opt(0, 0, 0)?.and_utc().into())
+    }
+
+    pub fn is_youtube(&self) -> bool {
+        self.extractor.as_deref() == Some("youtube")
+    }
+
+    /// Ids are unique only within an extractor, so the extractor is a part of
+    /// the episode id. YouTube ids are kept bare as in the episodes added before.
+    pub fn episode_id(&self) -> String {
+        match &self.extractor {
+            Some(extractor) if extractor != "youtube" => format!("{}:{}", extractor, self.id),
+            _ => self.id.to_string(),
+        }
+    }
+}
+
+impl PlaylistEntry {
+    /// Flat listing of some extractors has only ids of YouTube videos
+    pub fn video_url(&self) -> String {
+        self.url
+            .clone()
+            .unwrap_or_else(|| format!("https://www.youtube.com/watch?v={}", self.id))
+    }
+}
+
+impl YtDlp {
+    pub fn new() -> Self {
+        let path = env::var("YOUTUBE_EXTRACTOR")
+            .expect("Provide YOUTUBE_EXTRACTOR environment variable please");
+        let opts = Shlex::new(&env::var("YOUTUBE_EXTRACTOR_OPTS").unwrap_or(String::from("")))
+            .collect::<Vec<String>>();
+        let subtitles_langs = env::var("PODCAST_SUBTITLES_LANGS")
+            .ok()
+            .filter(|l| !l.is_empty());
+        Self {
+            path,
+            opts,
+            subtitles_langs,
+        }
+    }
+
+    async fn run(&self, args: &[&str], url: &str) -> Result<Output> {
+        let res = Command::new(&self.path)
+            .args(&self.opts)
+            .args(args)
+            .arg(url)
+            .output()
+            .await
+            .with_context(|| format!("Failed to execute the yt-dlp command for url {}", url))?;
+        if res.status.success() {
+            Ok(res)
+        } else {
+            Err(anyhow!(
+                "Exit code of youtube-dl command was not 0, output: {:?}",
+                res
+            ))
+        }
+    }
+
+    /// Returns None if yt-dlp can't extract anything from the url
+    pub async fn url_info(&self, url: &str) -> Option<UrlInfo> {
+        let output = self
+            .run(&["--flat-playlist", "--dump-single-json"], url)
+            .await
+            .ok()?;
+        serde_json::from_slice(&output.stdout).ok()
+    }
+
+    /// Downloads the best audio and returns the info about the video. m4a and mp3
+    /// are preferred by podcast apps, other sites can have only video formats.
+    /// Info dict is printed after the file is moved in place, so it has the final path.
+    /// Subtitles are optional: the audio is downloaded without them if they fail.
+    pub async fn download(&self, url: &str, path: &str) -> Result<VideoInfo> {
+        let args = [
+            "-f",
+            "bestaudio[ext=m4a]/bestaudio[ext=mp3]/bestaudio/best",
+            "-o",
+            path,
+            "--print",
+            "after_move:%()j",
+        ];
+        let output = match &self.subtitles_langs {
+            Some(langs) => {
+                let subtitles_args = [
+                    "--write-subs",
+                    "--write-auto-subs",
+                    "--sub-langs",
+                    langs,
+                    "--sub-format",
+                    "vtt/srt/best",
+                ];
+                match self.run(&[&args[..], &subtitles_args].concat(), url).await {
+                    Ok(output) => output,
+                    Err(e) => {
+                        warn!("Failed to download {} with subtitles: {:?}", url, e);
+                        self.run(&args, url).await?
+                    }
+                }
+            }
+            None => self.run(&args, url).await?,
+        };
+        serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Failed to parse yt-dlp info of {}", url))
+    }
+
+    pub fn downloads_subtitles(&self) -> bool {
+        self.subtitles_langs.is_some()
+    }
+
+    /// Downloaded subtitles of the video in the order of PODCAST_SUBTITLES_LANGS
+    pub fn subtitles_paths<'a>(&self, info: &'a VideoInfo) -> Vec<&'a Path> {
+        let langs: Vec<&str> = self
+            .subtitles_langs
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|l| l.trim())
+            .collect();
+        let mut subtitles: Vec<(usize, &Path)> = info
+            .requested_subtitles
+            .iter()
+            .flatten()
+            .filter_map(|(lang, s)| {
+                let position = langs.iter().position(|l| l == lang).unwrap_or(langs.len());
+                Some((position, s.filepath.as_deref()?))
+            })
+            .collect();
+        subtitles.sort();
+        subtitles.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// Lists videos of a playlist or a channel without downloading them,
+    /// limit takes only the first entries of the listing
+    pub async fn list_playlist(&self, url: &str, limit: Option<usize>) -> Result<Playlist> {
+        let limit = limit.map(|l| l.to_string());
+        let mut args = vec!["--flat-playlist", "--dump-single-json"];
+        if let Some(limit) = &limit {
+            args.extend(["--playlist-end", limit]);
+        }
+        let output = self.run(&args, url).await?;
+        serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Failed to parse yt-dlp playlist listing of {}", url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Trimmed info dict printed by `--print after_move:%()j` with subtitles
+    const VIDEO_INFO: &str = r#"{
+        "id": "dQw4w9WgXcQ",
+        "title": "Rick Astley - Never Gonna Give You Up",
+        "thumbnail": "https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg",
+        "description": "The official video",
+        "uploader": "Rick Astley",
+        "channel_id": "UCuAXFkgsw1L7xaCfnd5JJOw",
+        "duration": 212,
+        "view_count": 1600000000,
+        "upload_date": "20091025",
+        "chapters": null,
+        "extractor": "youtube",
+        "extractor_key": "Youtube",
+        "requested_subtitles": {
+            "en": {
+                "ext": "vtt",
+                "url": "https://www.youtube.com/api/timedtext?v=dQw4w9WgXcQ&lang=en",
+                "name": "English",
+                "filepath": "/tmp/dQw4w9WgXcQ.en.vtt"
+            },
+            "de": {
+                "ext": "vtt",
+                "url": "https://www.youtube.com/api/timedtext?v=dQw4w9WgXcQ&lang=de",
+                "name": "German",
+                "filepath": "/tmp/dQw4w9WgXcQ.de.vtt"
+            },
+            "ru": {
+                "ext": "vtt",
+                "url": "https://www.youtube.com/api/timedtext?v=dQw4w9WgXcQ&lang=ru",
+                "name": "Russian",
+                "filepath": "/tmp/dQw4w9WgXcQ.ru.vtt"
+            },
+            "fr": {
+                "ext": "vtt",
+                "url": "https://www.youtube.com/api/timedtext?v=dQw4w9WgXcQ&lang=fr",
+                "name": "French"
+            }
+        },
+        "ext": "m4a",
+        "filepath": "/tmp/dQw4w9WgXcQ.m4a"
+    }"#;
+
+    /// Trimmed output of `--flat-playlist --dump-single-json` of a channel
+    const PLAYLIST: &str = r#"{
+        "id": "UCuAXFkgsw1L7xaCfnd5JJOw",
+        "title": "Rick Astley - Videos",
+        "_type": "playlist",
+        "entries": [
+            {
+                "_type": "url",
+                "ie_key": "Youtube",
+                "id": "dQw4w9WgXcQ",
+                "url": "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                "title": "Never Gonna Give You Up",
+                "duration": 212
+            },
+            {
+                "_type": "url",
+                "ie_key": "Youtube",
+                "id": "yPYZpwSpKmA",
+                "title": "Together Forever"
+            }
+        ],
+        "extractor": "youtube:tab"
+    }"#;
+
+    fn yt_dlp(subtitles_langs: Option<&str>) -> YtDlp {
+        YtDlp {
+            path: "yt-dlp".to_string(),
+            opts: vec![],
+            subtitles_langs: subtitles_langs.map(|l| l.to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_info_of_downloaded_video() {
+        let info: VideoInfo = serde_json::from_str(VIDEO_INFO).unwrap();
+        assert!(info.is_youtube());
+        assert_eq!(info.duration, Some(212.0));
+        assert_eq!(info.filepath, PathBuf::from("/tmp/dQw4w9WgXcQ.m4a"));
+        assert!(info.chapters.is_none());
+        assert_eq!(
+            info.published_at(),
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1256428800))
+        );
+
+        let mut info = info;
+        info.upload_date = Some("2009-10-25".to_string());
+        assert_eq!(info.published_at(), None);
+        info.upload_date = None;
+        assert_eq!(info.published_at(), None);
+    }
+
+    #[test]
+    fn orders_subtitles_by_languages() {
+        let info: VideoInfo = serde_json::from_str(VIDEO_INFO).unwrap();
+        // Languages out of the list go last, unwritten subtitles are skipped
+        assert_eq!(
+            yt_dlp(Some("ru, en")).subtitles_paths(&info),
+            [
+                Path::new("/tmp/dQw4w9WgXcQ.ru.vtt"),
+                Path::new("/tmp/dQw4w9WgXcQ.en.vtt"),
+                Path::new("/tmp/dQw4w9WgXcQ.de.vtt"),
+            ]
+        );
+
+        let mut info = info;
+        info.requested_subtitles = None;
+        assert!(yt_dlp(Some("ru")).subtitles_paths(&info).is_empty());
+    }
+
+    #[test]
+    fn links_entries_of_playlist() {
+        let playlist: Playlist = serde_json::from_str(PLAYLIST).unwrap();
+        assert_eq!(playlist.title.as_deref(), Some("Rick Astley - Videos"));
+        let urls: Vec<String> = playlist.entries.iter().map(|e| e.video_url()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                "https://www.youtube.com/watch?v=yPYZpwSpKmA",
+            ]
+        );
+
+        let url_info: UrlInfo = serde_json::from_str(PLAYLIST).unwrap();
+        assert!(url_info.is_playlist());
+        assert_eq!(url_info.extractor, "youtube:tab");
+    }
+
+    fn video_info(extractor: Option<&str>, id: &str) -> VideoInfo {
+        serde_json::from_value(json!({
+            "id": id,
+            "title": "Video",
+            "extractor": extractor,
+            "filepath": "/tmp/video.m4a",
+            "ext": "m4a",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn prefixes_episode_ids_with_extractor() {
+        assert_eq!(
+            video_info(Some("youtube"), "dQw4w9WgXcQ").episode_id(),
+            "dQw4w9WgXcQ"
+        );
+        // Episodes added before the extractor was known
+        assert_eq!(video_info(None, "dQw4w9WgXcQ").episode_id(), "dQw4w9WgXcQ");
+        assert_eq!(
+            video_info(Some("twitch:vod"), "v123").episode_id(),
+            "twitch:vod:v123"
+        );
+    }
+}
@@ -1,10 +1,13 @@
-use std::path::PathBuf;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use bytes::Bytes;
 use reqwest::{Client, blocking};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct TelegramResponse<T> {
@@ -16,6 +19,18 @@ pub struct TelegramResponse<T> {
 pub struct Update {
     pub update_id: i32,
     pub message: Option<Message>,
+    #[serde(default)]
+    pub callback_query: Option<CallbackQuery>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    #[serde(default)]
+    pub message: Option<Message>,
+    #[serde(default)]
+    pub data: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -27,6 +42,18 @@ pub struct Message {
     pub text: Option<String>,
     #[serde(default)]
     pub document: Option<Document>,
+    #[serde(default)]
+    pub audio: Option<Audio>,
+    #[serde(default)]
+    pub voice: Option<Voice>,
+    #[serde(default)]
+    pub video: Option<Video>,
+    /// Text of messages with attachments
+    #[serde(default)]
+    pub caption: Option<String>,
+    /// Telegram doesn't include replies of the replied message
+    #[serde(default)]
+    pub reply_to_message: Option<Box<Message>>,
     pub chat: Chat,
 }
 
@@ -42,6 +69,52 @@ pub struct Document {
     pub mime_type: String,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Audio {
+    pub file_id: String,
+    pub file_unique_id: String,
+    /// Seconds
+    pub duration: u32,
+    #[serde(default)]
+    pub performer: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub file_name: Option<String>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub file_size: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Voice {
+    pub file_id: String,
+    pub file_unique_id: String,
+    /// Seconds
+    pub duration: u32,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub file_size: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Video {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub width: u32,
+    pub height: u32,
+    /// Seconds
+    pub duration: u32,
+    #[serde(default)]
+    pub file_name: Option<String>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub file_size: Option<i64>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct User {
     pub id: i32,
@@ -64,6 +137,54 @@ pub struct SendMessage<'a> {
     pub chat_id: String,
     pub text: String,
     pub reply_to_message_id: Option<&'a i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InlineKeyboardMarkup {
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InlineKeyboardButton {
+    pub text: String,
+    /// Up to 64 bytes, sent back in CallbackQuery::data
+    pub callback_data: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnswerCallbackQuery<'a> {
+    pub callback_query_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+/// Bot API method used to upload a file, it defines how the file
+/// is shown in the chat
+#[derive(Clone, Copy, Debug)]
+pub enum FileKind {
+    Document,
+    Audio,
+    Video,
+}
+
+impl FileKind {
+    fn method(&self) -> &'static str {
+        match self {
+            FileKind::Document => "sendDocument",
+            FileKind::Audio => "sendAudio",
+            FileKind::Video => "sendVideo",
+        }
+    }
+
+    fn field(&self) -> &'static str {
+        match self {
+            FileKind::Document => "document",
+            FileKind::Audio => "audio",
+            FileKind::Video => "video",
+        }
+    }
 }
 
 pub struct TelegramClient<'a> {
@@ -75,6 +196,8 @@ pub struct TelegramClient<'a> {
 impl<'a> TelegramClient<'a> {
     const BASE_TELEGRAM_API_URL: &'static str = "https://api.telegram.org/bot";
     const BASE_FILE_TELEGRAM_API_URL: &'static str = "https://api.telegram.org/file/bot";
+    /// Limit of Bot API for files uploaded by bots
+    pub const MAX_UPLOAD_SIZE: u64 = 50 * 1024 * 1024;
 
     fn api_url(&self, method: &str) -> String {
         format!(
@@ -140,31 +263,79 @@ impl<'a> TelegramClient<'a> {
         &self,
         chat_id: &str,
         path: PathBuf,
+        kind: FileKind,
     ) -> Result<TelegramResponse<Message>> {
-        let file = fs::read(path.clone()).await?;
+        let file = fs::read(path.clone())
+            .await
+            .with_context(|| format!("Failed to read file with path {:?}", path))?;
         let file_name = String::from(path.file_name().unwrap().to_str().unwrap());
-        let file_part = reqwest::multipart::Part::bytes(file).file_name(file_name);
-        let form = reqwest::multipart::Form::new().part("video", file_part);
+        self.async_send_bytes(chat_id, file_name, file, kind).await
+    }
+
+    pub async fn async_send_bytes(
+        &self,
+        chat_id: &str,
+        file_name: String,
+        content: Vec<u8>,
+        kind: FileKind,
+    ) -> Result<TelegramResponse<Message>> {
+        let file_part = reqwest::multipart::Part::bytes(content);
+        self.async_send_part(chat_id, file_name, file_part, kind)
+            .await
+    }
+
+    /// Streams `length` bytes of the file starting at `offset`, so big files
+    /// are uploaded without reading them into memory
+    pub async fn async_send_file_range(
+        &self,
+        chat_id: &str,
+        path: &Path,
+        file_name: String,
+        offset: u64,
+        length: u64,
+        kind: FileKind,
+    ) -> Result<TelegramResponse<Message>> {
+        let mut file = fs::File::open(path)
+            .await
+            .with_context(|| format!("Failed to open file with path {:?}", path))?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .with_context(|| format!("Failed to seek in file with path {:?}", path))?;
+        let body = reqwest::Body::wrap_stream(ReaderStream::new(file.take(length)));
+        let file_part = reqwest::multipart::Part::stream_with_length(body, length);
+        self.async_send_part(chat_id, file_name, file_part, kind)
+            .await
+    }
+
+    async fn async_send_part(
+        &self,
+        chat_id: &str,
+        file_name: String,
+        file_part: reqwest::multipart::Part,
+        kind: FileKind,
+    ) -> Result<TelegramResponse<Message>> {
+        let file_part = file_part.file_name(file_name.clone());
+        let form = reqwest::multipart::Form::new().part(kind.field(), file_part);
 
         let response = self
             .async_http_client
-            .post(&self.api_url(&format!("sendVideo?chat_id={}", chat_id)))
+            .post(self.api_url(&format!("{}?chat_id={}", kind.method(), chat_id)))
             .multipart(form)
             .send()
             .await
-            .with_context(|| format!("Failed to send file with path {:?}", path))?;
+            .with_context(|| format!("Failed to send file {}", file_name))?;
 
         let response_text = response.text().await.with_context(|| {
             format!(
-                "Failed to read response body for file upload with path {:?}",
-                path
+                "Failed to read response body for file upload of {}",
+                file_name
             )
         })?;
 
         serde_json::from_str(&response_text).with_context(|| {
             format!(
-                "Failed to parse response with file upload for file with path {:?}, response body: {}",
-                path, response_text
+                "Failed to parse response with file upload for file {}, response body: {}",
+                file_name, response_text
             )
         })
     }
@@ -187,6 +358,23 @@ impl<'a> TelegramClient<'a> {
             .map(|_| ())?)
     }
 
+    pub async fn async_answer_callback_query(&self, answer: AnswerCallbackQuery<'_>) -> Result<()> {
+        let json_body = serde_json::to_string(&answer).with_context(|| {
+            format!(
+                "Failed to serialize body to json for answering callback query {:?}",
+                answer
+            )
+        })?;
+        self.async_http_client
+            .post(self.api_url("answerCallbackQuery"))
+            .body(json_body)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .with_context(|| format!("Failed to answer the callback query {:?}", answer))
+            .map(|_| ())
+    }
+
     pub fn send_message(&self, message: SendMessage) -> Result<()> {
         let json_body = serde_json::to_string(&message).with_context(|| {
             format!(
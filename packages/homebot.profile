@@ -4,15 +4,82 @@ export BOT_STATE_PATH=
 # , delimited list of chat ids (user ids as well - for private chats)
 export USERS_WHITE_LIST=
 
-# http://host:port/transmission/rpc
+# transmission (default) or qbittorrent
+export TORRENT_BACKEND=
+
+# http(s)://host:port/transmission/rpc
 export TRANSMISSION_ADDRESS=
+# optional, rpc basic auth credentials
+export TRANSMISSION_USERNAME=
+export TRANSMISSION_PASSWORD=
+
+# http(s)://host:port of qBittorrent Web UI
+export QBITTORRENT_ADDRESS=
+# optional, can be omitted if auth is bypassed for the bot host
+export QBITTORRENT_USERNAME=
+export QBITTORRENT_PASSWORD=
+
+# optional, torznab endpoint of Jackett/Prowlarr for /search command, e.g.
+# http://host:9117/api/v2.0/indexers/all/results/torznab/api
+export TORZNAB_URL=
+export TORZNAB_API_KEY=
 
+# optional, YouTube Data API fills video info missing in yt-dlp output
 export GOOGLE_API_KEY=
 export YOUTUBE_EXTRACTOR=
 export YOUTUBE_EXTRACTOR_OPTS=
+# comma separated yt-dlp extractors allowed for podcast feeds,
+# youtube,vimeo,soundcloud,twitch,bandcamp by default
+export PODCAST_EXTRACTORS=
+# optional, enables /audio processing of podcast episodes
+export FFMPEG_PATH=
+# optional, https://sponsor.ajay.app by default
+export SPONSORBLOCK_API_URL=
+# optional, whisper.cpp cli for /transcripts of episodes without subtitles,
+# WHISPER_MODEL is required with it
+export WHISPER_PATH=
+export WHISPER_MODEL=
+export WHISPER_OPTS=
+# optional, command reading the transcript from stdin and printing
+# the summary, which is added to the description of the episode
+export PODCAST_SUMMARY_COMMAND=
+
+# s3 (default) or local
+export PODCAST_STORAGE=
+# random string, feed urls are derived from it and telegram user id,
+# changing it changes urls of all feeds
+export PODCAST_FEED_SECRET=
+# directory for subscriptions to playlists and channels, the /find index and
+# feeds with /retention days checked in the background, all are disabled if empty
+export PODCAST_STATE_DIR=
+# 60 by default
+export PODCAST_SUBSCRIPTIONS_CHECK_MINUTES=
+# optional, e.g. ru,en: subtitles of these languages are downloaded for /find and /transcripts
+export PODCAST_SUBTITLES_LANGS=
+# optional, url of the bot's http server for private feeds, the bucket
+# (or local storage) isn't public then and feed urls carry tokens, see /rotate
+export PODCAST_PRIVATE_URL=
+# e.g. 0.0.0.0:8081, required with PODCAST_PRIVATE_URL
+export PODCAST_PRIVATE_LISTEN=
+
+# s3 storage, any S3 compatible service, Yandex Object Storage by default
 export BOT_BUCKET_NAME=
 export AWS_ACCESS_KEY_ID=
 export AWS_SECRET_ACCESS_KEY=
+# optional, default https://storage.yandexcloud.net and ru-central1
+export BOT_S3_ENDPOINT=
+export BOT_S3_REGION=
+# optional, false for bucket.endpoint public urls, default true
+export BOT_S3_PATH_STYLE=
+# optional, e.g. CDN url in front of the bucket
+export BOT_S3_PUBLIC_URL=
+
+# local storage, files are served by the bot itself
+export PODCAST_LOCAL_STORAGE_PATH=
+# e.g. 0.0.0.0:8080
+export PODCAST_HTTP_LISTEN=
+# url the http server is reachable by podcast clients
+export PODCAST_PUBLIC_URL=
 
 # socks5://host:port
 export DOWNLOADER_SOCKS_PROXY=